@@ -20,10 +20,11 @@ TESTS_FAILED=0
 
 # Test database file
 DB_FILE="comprehensive_test.json"
+BACKUP_FILE="comprehensive_test_backup.json"
 
 # Clean up function
 cleanup() {
-    rm -f "$DB_FILE"
+    rm -f "$DB_FILE" "$DB_FILE.wal" "$BACKUP_FILE"
 }
 
 # Trap to ensure cleanup happens
@@ -57,6 +58,21 @@ run_test() {
     fi
 }
 
+# Recompute the integrity tag of a database file edited by hand, so tests
+# that corrupt a document reach the code meant to reject it
+resign_db() {
+    python3 - "$1" <<'PY'
+import hashlib, hmac, json, sys
+p = sys.argv[1]
+d = json.load(open(p))
+canon = lambda v: json.dumps(v, sort_keys=True, separators=(',', ':'), ensure_ascii=False).encode()
+docs = canon(d['documents']) + (canon(d['namespaces']) if d.get('namespaces') else b'')
+k = hmac.new(bytes(d['secret_key']), b'magentadb integrity key', hashlib.sha256).digest()
+d['integrity_tag'] = hmac.new(k, docs, hashlib.sha256).hexdigest()
+json.dump(d, open(p, 'w'))
+PY
+}
+
 # Build the project first
 echo "🔨 Building MagentaDB..."
 if ! cargo build --release; then
@@ -90,7 +106,7 @@ run_test "List verbose" "$CLI $DB_ARGS list -v" "user1"
 
 # Test 5: Query operations
 run_test "Query existing value" "$CLI $DB_ARGS query 'John Doe'" "Found 1 document"
-run_test "Query non-existent value" "$CLI $DB_ARGS query 'NonExistent'" "No documents found"
+run_test "Query non-existent value" "$CLI $DB_ARGS query 'NonExistent' || true" "No documents found"
 
 # Test 6: Decrypt operations
 run_test "Decrypt existing field" "$CLI $DB_ARGS decrypt user1 name" "Decrypted user1.name: John Doe"
@@ -134,7 +150,7 @@ run_test "Stats after bulk insert" "$CLI $DB_ARGS stats" "Documents: 103"
 
 # Test 14: Remove operations
 run_test "Remove existing document" "$CLI $DB_ARGS remove user1" "Removed document 'user1'"
-run_test "Remove non-existent document" "$CLI $DB_ARGS remove nonexistent" "not found"
+run_test "Remove non-existent document" "$CLI $DB_ARGS remove nonexistent 2>&1 || true" "Document 'nonexistent' not found"
 run_test "Verify removal" "$CLI $DB_ARGS show user1" "not found"
 
 # Test 15: Clear database with confirmation
@@ -165,9 +181,1411 @@ run_test "Invalid command" "$CLI invalid_command" "error\|Error\|USAGE" || true
 run_test "Missing arguments" "$CLI $DB_ARGS insert" "error\|Error\|required" || true
 
 # Test 20: Edge cases
-run_test "Empty field name" "$CLI $DB_ARGS insert test '' 'value'" "Inserted document 'test'" || true
+run_test "Empty field name is rejected" "$CLI $DB_ARGS insert test '' 'value' 2>&1 || true" "field name cannot be empty"
 run_test "Empty value" "$CLI $DB_ARGS insert test field ''" "Inserted document 'test'"
 
+# Test 21: Field-name queries
+run_test "Insert field-query doc 1" "$CLI $DB_ARGS insert fq1 email 'a@example.com'" "Inserted document 'fq1'"
+run_test "Insert field-query doc 1 phone" "$CLI $DB_ARGS insert fq1 phone '555-0100'" "Inserted document 'fq1'"
+run_test "Insert field-query doc 2" "$CLI $DB_ARGS insert fq2 email 'b@example.com'" "Inserted document 'fq2'"
+run_test "Insert field-query doc 3" "$CLI $DB_ARGS insert fq3 address '1 Main St'" "Inserted document 'fq3'"
+run_test "Query shared field" "$CLI $DB_ARGS query-field email" "Found 2 document"
+run_test "Query disjoint field" "$CLI $DB_ARGS query-field address" "Found 1 document"
+run_test "Query unknown field" "$CLI $DB_ARGS query-field nonexistent" "No documents found"
+
+# Test 22: Count-only queries
+run_test "Count by value" "$CLI $DB_ARGS count --token 'a@example.com'" "1 document(s) matching"
+run_test "Count by field" "$CLI $DB_ARGS count --field email" "2 document(s) with field"
+run_test "Count missing value" "$CLI $DB_ARGS count --token 'nobody@example.com'" "0 document(s) matching"
+
+# Test 23: Listing visits each document once
+run_test "List entry per document" "[ \$($CLI $DB_ARGS list | grep -c '📄') -eq \$($CLI $DB_ARGS stats | sed -n 's/.*Documents: //p') ] && echo 'counts match'" "counts match"
+
+# Test 24: Deterministic query ordering
+for id in order_c order_a order_b; do
+    $CLI $DB_ARGS insert "$id" group 'ordered' >/dev/null 2>&1
+done
+run_test "Query results sorted by id" "$CLI $DB_ARGS query 'ordered' | grep -o 'order_[abc]' | tr '\n' ' '" "order_a order_b order_c"
+
+# Test 25: Prefix search
+run_test "Insert prefix-indexed value" "$CLI $DB_ARGS insert pfx1 name 'alice' --prefix" "Inserted document 'pfx1'"
+run_test "Insert second prefix-indexed value" "$CLI $DB_ARGS insert pfx2 name 'bob' --prefix" "Inserted document 'pfx2'"
+run_test "Prefix query matches" "$CLI $DB_ARGS query-prefix ali" "pfx1"
+run_test "Prefix query excludes others" "! $CLI $DB_ARGS query-prefix ali | grep -q pfx2 && echo 'excluded'" "excluded"
+run_test "Prefix token is not an exact match" "$CLI $DB_ARGS query ali || true" "No documents found"
+run_test "Short prefix rejected" "! $CLI $DB_ARGS query-prefix al" "at least 3 characters"
+
+# Test 26: Updating existing fields
+run_test "Insert field to update" "$CLI $DB_ARGS insert upd1 status 'pending'" "Inserted document 'upd1'"
+run_test "Update existing field" "$CLI $DB_ARGS update upd1 status 'shipped'" "Updated field 'status'"
+run_test "Stale value no longer matches" "$CLI $DB_ARGS query 'pending' || true" "No documents found"
+run_test "New value matches" "$CLI $DB_ARGS query 'shipped'" "upd1"
+run_test "Update missing field fails" "! $CLI $DB_ARGS update upd1 statsu 'lost'" "not found"
+run_test "Update missing document fails" "! $CLI $DB_ARGS update nobody status 'lost'" "not found"
+
+# Test 27: Overwriting a field drops the old token
+run_test "Insert original value" "$CLI $DB_ARGS insert 1 a 'foo'" "Inserted document '1'"
+run_test "Overwrite with new value" "$CLI $DB_ARGS insert 1 a 'bar'" "Inserted document '1'"
+run_test "Old value no longer matches" "$CLI $DB_ARGS query 'foo' || true" "No documents found"
+run_test "New value matches" "$CLI $DB_ARGS query 'bar'" "Found 1 document"
+
+# Test 28: Decrypting whole documents
+DA_DB="decrypt_all_test.json"
+DA_ARGS="--database $DA_DB --compact-after 1"
+rm -f "$DA_DB" "$DA_DB.wal"
+$CLI $DA_ARGS insert rec name 'Ada' >/dev/null 2>&1
+$CLI $DA_ARGS insert rec city 'London' >/dev/null 2>&1
+run_test "Decrypt all fields" "$CLI $DA_ARGS decrypt-all rec | tr '\n' ' '" "city: London    name: Ada"
+# Corrupt the first ciphertext byte of one field
+python3 -c "import base64,json,sys; p=sys.argv[1]; d=json.load(open(p)); f=d['documents']['rec']['fields']['city']; c=bytearray(base64.b64decode(f['cipher'])); c[0]^=1; f['cipher']=base64.b64encode(c).decode(); json.dump(d, open(p, 'w'))" "$DA_DB"
+resign_db "$DA_DB"
+run_test "Decrypt all reports tampered field" "! $CLI $DA_ARGS decrypt-all rec" "1 field(s) in 'rec' could not be decrypted"
+run_test "Decrypt all keeps good fields" "$CLI $DA_ARGS decrypt-all rec | grep -cE ': (Ada|London)$'" "^1$"
+rm -f "$DA_DB" "$DA_DB.wal"
+
+# Test 29: Renaming documents
+run_test "Insert document to rename" "$CLI $DB_ARGS insert typo_id name 'Grace'" "Inserted document 'typo_id'"
+run_test "Rename document" "$CLI $DB_ARGS rename typo_id fixed_id" "Renamed document 'typo_id' to 'fixed_id'"
+run_test "Query finds new id" "$CLI $DB_ARGS query 'Grace'" "fixed_id"
+run_test "Old id is gone" "$CLI $DB_ARGS show typo_id" "not found"
+run_test "Rename onto existing id fails" "! $CLI $DB_ARGS rename fixed_id upd1" "Duplicate document"
+run_test "Rename missing id fails" "! $CLI $DB_ARGS rename typo_id other_id" "Document not found"
+
+# Test 30: Document versions
+run_test "Overwrite recorded as a new version" "$CLI $DB_ARGS -v show 1" "Version: 2"
+run_test "Update bumps version" "$CLI $DB_ARGS update 1 a 'baz' && $CLI $DB_ARGS -v show 1" "Version: 3"
+
+# Test 31: Write-ahead log
+run_test "Insert appends to the log" "$CLI $DB_ARGS insert wal1 note 'logged' && test -s $DB_FILE.wal && echo 'wal written'" "wal written"
+run_test "Log is replayed on load" "$CLI $DB_ARGS query 'logged'" "wal1"
+run_test "Compaction folds the log into the snapshot" "$CLI $DB_ARGS --compact-after 1 insert wal2 note 'compacted' && test ! -e $DB_FILE.wal && echo 'wal compacted'" "wal compacted"
+run_test "State survives compaction" "$CLI $DB_ARGS query 'logged'" "wal1"
+
+# Test 32: Backup and restore
+run_test "Backup database" "$CLI $DB_ARGS backup $BACKUP_FILE" "Backed up"
+DOCS_BEFORE=$($CLI $DB_ARGS stats | sed -n 's/.*Documents: //p')
+$CLI $DB_ARGS clear --force >/dev/null 2>&1
+run_test "Restore database" "$CLI $DB_ARGS restore $BACKUP_FILE" "Restored $DOCS_BEFORE document(s)"
+run_test "Restored indexes answer queries" "$CLI $DB_ARGS query 'Grace'" "fixed_id"
+run_test "Restored field index" "$CLI $DB_ARGS query-field email" "Found 2 document"
+run_test "Backup lists documents in id order" "python3 -c \"import json; ids=[d['id'] for d in json.load(open('$BACKUP_FILE'))['documents']]; print(ids == sorted(ids) and len(ids) > 0)\"" "^True$"
+
+# Test 33: Byte-size statistics
+SZ_DB="size_test.json"
+rm -f "$SZ_DB" "$SZ_DB.wal"
+# 16-byte Poly1305 tag + plaintext, 24-byte nonce per field
+$CLI --database $SZ_DB insert sized a '0123456789' >/dev/null 2>&1
+$CLI --database $SZ_DB insert sized b '01234567890123456789' >/dev/null 2>&1
+run_test "Ciphertext byte total" "$CLI --database $SZ_DB stats" "Ciphertext size: 62 B"
+run_test "Nonce byte total" "$CLI --database $SZ_DB stats" "Nonce size: 48 B"
+run_test "Field total" "$CLI --database $SZ_DB stats" "Total fields: 2"
+rm -f "$SZ_DB" "$SZ_DB.wal"
+
+# Test 34: Counter-based nonces
+NC_DB="nonce_test.json"
+rm -f "$NC_DB" "$NC_DB.wal"
+$CLI --database $NC_DB --counter-nonces insert n1 a 'one' >/dev/null 2>&1
+$CLI --database $NC_DB insert n1 b 'two' >/dev/null 2>&1
+run_test "Counter advances per encryption" "$CLI --database $NC_DB stats" "Nonce counter: 2"
+run_test "Counter survives log replay" "$CLI --database $NC_DB insert n2 a 'three' && $CLI --database $NC_DB stats" "Nonce counter: 3"
+run_test "Counter survives compaction" "$CLI --database $NC_DB --compact-after 1 insert n3 a 'four' && $CLI --database $NC_DB stats" "Nonce counter: 4"
+run_test "Counter-encrypted fields decrypt" "$CLI --database $NC_DB decrypt n1 b" "Decrypted n1.b: two"
+rm -f "$NC_DB" "$NC_DB.wal"
+
+# Test 35: Deterministic encryption
+DE_DB="deterministic_test.json"
+DE_ARGS="--database $DE_DB --compact-after 1"
+rm -f "$DE_DB" "$DE_DB.wal"
+$CLI $DE_ARGS insert d1 v 'same' --deterministic >/dev/null 2>&1
+$CLI $DE_ARGS insert d2 v 'same' --deterministic >/dev/null 2>&1
+run_test "Equal values share ciphertext" "tr -d ' \n' < $DE_DB | grep -o '\"cipher\":\"[^\"]*\"' | sort -u | wc -l" "^1$"
+$CLI $DE_ARGS insert d3 v 'different' --deterministic >/dev/null 2>&1
+run_test "Different values differ" "tr -d ' \n' < $DE_DB | grep -o '\"cipher\":\"[^\"]*\"' | sort -u | wc -l" "^2$"
+run_test "Deterministic field decrypts" "$CLI $DE_ARGS decrypt d2 v" "Decrypted d2.v: same"
+rm -f "$DE_DB" "$DE_DB.wal"
+
+# Test 36: Numeric range queries
+for entry in "age_a:5" "age_b:17" "age_c:18" "age_d:64" "age_e:-3"; do
+    $CLI $DB_ARGS insert-num "${entry%%:*}" age "${entry##*:}" >/dev/null 2>&1
+done
+run_test "Range with inclusive bounds" "$CLI $DB_ARGS query-range age --min 5 --max 18 | grep -o 'age_[a-e]' | tr '\n' ' '" "age_a age_b age_c "
+run_test "Range spanning buckets and zero" "$CLI $DB_ARGS query-range age --min -10 --max 100" "Found 5 document"
+run_test "Open-ended range" "$CLI $DB_ARGS query-range age --min 18" "Found 2 document"
+run_test "Empty range" "$CLI $DB_ARGS query-range age --min 19 --max 63" "No documents found"
+run_test "Inverted range" "$CLI $DB_ARGS query-range age --min 10 --max 1" "No documents found"
+run_test "Non-numeric value rejected" "! $CLI $DB_ARGS insert-num age_f age inf" "not a finite number"
+
+# Test 37: Field expiry
+run_test "Insert expiring field" "$CLI $DB_ARGS insert sess1 token 'tok-123' --ttl 1" "Inserted document 'sess1'"
+$CLI $DB_ARGS insert sess1 user 'u1' >/dev/null 2>&1
+$CLI $DB_ARGS insert sess2 token 'tok-456' --ttl 1 >/dev/null 2>&1
+$CLI $DB_ARGS insert sess3 token 'tok-789' --ttl 3600 >/dev/null 2>&1
+run_test "Unexpired field matches" "$CLI $DB_ARGS query 'tok-123'" "sess1"
+sleep 2
+run_test "Expired field skipped before sweep" "$CLI $DB_ARGS query 'tok-123' || true" "No documents found"
+run_test "Sweep removes expired fields" "$CLI $DB_ARGS gc" "Removed 2 expired field(s), 1 empty document(s)"
+run_test "Surviving field kept" "$CLI $DB_ARGS query 'u1'" "sess1"
+run_test "Long-lived field kept" "$CLI $DB_ARGS query 'tok-789'" "sess3"
+
+# Test 38: Interactive mode
+REPL_DB="comprehensive_test_repl.json"
+rm -f "$REPL_DB" "$REPL_DB.wal"
+run_test "REPL runs commands" "printf 'insert r1 name \"Repl User\"\\nquery \"Repl User\"\\nexit\\n' | $CLI --database $REPL_DB repl" "Found 1 document"
+run_test "REPL changes saved on exit" "$CLI --database $REPL_DB query 'Repl User'" "r1"
+run_test "REPL reports bad commands" "printf 'bogus\\nexit\\n' | $CLI --database $REPL_DB repl" "unrecognized subcommand"
+run_test "REPL saves on end of input" "printf 'remove r1\\n' | $CLI --database $REPL_DB repl && $CLI --database $REPL_DB list" "No documents in database"
+run_test "REPL help mentions history" "printf '.help\\n' | $CLI --database $REPL_DB repl" "Up and down recall earlier lines"
+# On a terminal lines can be edited and recalled: backspace, up arrow for
+# the previous line, and Ctrl-A to insert at the start
+REPL_KEYS="(sleep 0.5; printf 'insert h1 name Ann\\r'; sleep 0.3; printf 'query Annx\\177\\r'; sleep 0.3; printf '\\033[A\\r'; sleep 0.3; printf 'uery Ann\\001q\\r'; sleep 0.3; printf 'exit\\r'; sleep 0.5)"
+run_test "REPL edits and recalls lines on a terminal" "$REPL_KEYS | script -qc '$CLI --database $REPL_DB repl' /dev/null | grep -c 'Found 1 document'" "^3$"
+rm -f "$REPL_DB" "$REPL_DB.wal"
+
+# Test 39: JSON output
+JSON_ARGS="$DB_ARGS --output json"
+$CLI $DB_ARGS insert json1 name 'Json Match' >/dev/null 2>&1
+$CLI $DB_ARGS insert json2 alias 'Json Match' >/dev/null 2>&1
+for cmd in "query 'Json Match'" "show json1" "list" "stats"; do
+    run_test "JSON output is valid: $cmd" "$CLI $JSON_ARGS $cmd | python3 -m json.tool" "{"
+done
+run_test "JSON query reports matched fields" "$CLI $JSON_ARGS query 'Json Match' | python3 -c 'import json,sys; print(sorted((m[\"id\"], m[\"matched_fields\"]) for m in json.load(sys.stdin)))'" "('json1', \['name'\]), ('json2', \['alias'\])"
+run_test "JSON and human query agree" "[ \"\$($CLI $DB_ARGS query 'Json Match' | grep -o 'json[12]' | sort)\" = \"\$($CLI $JSON_ARGS query 'Json Match' | python3 -c 'import json,sys; print(chr(10).join(sorted(m[\"id\"] for m in json.load(sys.stdin))))')\" ] && echo 'same results'" "same results"
+run_test "JSON and human list agree" "[ \$($CLI $DB_ARGS list | grep -c '📄') -eq \$($CLI $JSON_ARGS list | python3 -c 'import json,sys; print(len(json.load(sys.stdin)))') ] && echo 'same documents'" "same documents"
+run_test "JSON and human stats agree" "[ \$($CLI $DB_ARGS stats | sed -n 's/.*Documents: //p') -eq \$($CLI $JSON_ARGS stats | python3 -c 'import json,sys; print(json.load(sys.stdin)[\"documents\"])') ] && echo 'same count'" "same count"
+run_test "JSON show of missing document" "$CLI $JSON_ARGS show nonexistent" "null"
+
+# Test 40: Masked values
+$CLI $DB_ARGS insert mask1 secret 'Zebra Crossing' >/dev/null 2>&1
+MASK_FIELD="$CLI $DB_ARGS --output json show mask1 | python3 -c 'import json,sys; f=json.load(sys.stdin)[\"fields\"][\"secret\"]; print(f[\"masked\"]); print(f[\"token\"])'"
+run_test "Mask is a redaction" "$MASK_FIELD | head -1" "••• (medium)"
+run_test "Mask hides first character" "$MASK_FIELD | head -1 | grep -v 'Z' && echo 'no plaintext'" "no plaintext"
+run_test "Mask hides token bytes" "MASK_OUT=\$($MASK_FIELD); TOKEN=\$(echo \"\$MASK_OUT\" | tail -1); echo \"\$MASK_OUT\" | head -1 | grep -v \"\${TOKEN:0:4}\" && echo 'no token'" "no token"
+run_test "Show prints mask" "$CLI $DB_ARGS show mask1" "secret: ••• (medium)"
+
+# Test 41: Token matching picks only the matching field
+$CLI $DB_ARGS insert tokeq1 first 'Alpha Token' >/dev/null 2>&1
+$CLI $DB_ARGS insert tokeq1 second 'Alpha Tokeo' >/dev/null 2>&1
+run_test "Only equal token matches" "$CLI $DB_ARGS --output json query 'Alpha Token' | python3 -c 'import json,sys; print([m[\"matched_fields\"] for m in json.load(sys.stdin)])'" "\[\['first'\]\]"
+run_test "Near-identical value does not match" "$CLI $DB_ARGS query 'Alpha Toke' || true" "No documents found"
+
+# Test 42: Length-hiding padding
+PAD_DB="comprehensive_test_pad.json"
+rm -f "$PAD_DB" "$PAD_DB.wal"
+CIPHER_BYTES="python3 -c 'import json,sys; print(json.load(sys.stdin)[\"fields\"][\"v\"][\"cipher_bytes\"])'"
+$CLI --database $PAD_DB insert unpadded v 'written before padding' >/dev/null 2>&1
+$CLI --database $PAD_DB --pad 16 insert pad1 v 'abc' >/dev/null 2>&1
+$CLI --database $PAD_DB insert pad2 v 'abcdefghijklmn' >/dev/null 2>&1
+$CLI --database $PAD_DB insert pad3 v 'abcdefghijklmnop' >/dev/null 2>&1
+run_test "Short value padded to one block" "$CLI --database $PAD_DB --output json show pad1 | $CIPHER_BYTES" "^32$"
+run_test "Values in the same block share a size" "$CLI --database $PAD_DB --output json show pad2 | $CIPHER_BYTES" "^32$"
+run_test "Full block gains a padding block" "$CLI --database $PAD_DB --output json show pad3 | $CIPHER_BYTES" "^48$"
+run_test "Padded value decrypts exactly" "$CLI --database $PAD_DB decrypt pad2 v | grep -c 'pad2.v: abcdefghijklmn$'" "^1$"
+run_test "Unpadded value still decrypts" "$CLI --database $PAD_DB decrypt unpadded v" "unpadded.v: written before padding"
+run_test "Padding setting persists" "$CLI --database $PAD_DB stats" "Padding block: 16 B"
+run_test "Pad block size is bounded" "$CLI --database $PAD_DB --pad 257 stats 2>&1 || true" "not in 1..=256"
+rm -f "$PAD_DB" "$PAD_DB.wal"
+
+# Test 43: Field writes merge into the stored document
+for f in f1 f2 f3; do
+    $CLI $DB_ARGS insert merge1 "$f" "value-$f" >/dev/null 2>&1
+done
+run_test "All merged fields survive" "$CLI $DB_ARGS --output json show merge1 | python3 -c 'import json,sys; d=json.load(sys.stdin); print(sorted(d[\"fields\"]), d[\"version\"])'" "\['f1', 'f2', 'f3'\] 3"
+
+# Test 44: Distinct field names
+FIELDS_DB="comprehensive_test_fields.json"
+rm -f "$FIELDS_DB" "$FIELDS_DB.wal"
+run_test "No fields in empty database" "$CLI --database $FIELDS_DB fields" "No fields in database"
+$CLI --database $FIELDS_DB insert fn1 zeta 'x' >/dev/null 2>&1
+$CLI --database $FIELDS_DB insert fn1 alpha 'x' >/dev/null 2>&1
+$CLI --database $FIELDS_DB insert fn2 alpha 'y' >/dev/null 2>&1
+$CLI --database $FIELDS_DB insert fn2 mid 'y' >/dev/null 2>&1
+run_test "Fields are distinct and sorted" "$CLI --database $FIELDS_DB fields | sed -n 's/^   \\([a-z]*\\) .*/\\1/p' | tr '\\n' ' '" "^alpha mid zeta $"
+run_test "Field count reported" "$CLI --database $FIELDS_DB fields" "3 distinct field"
+rm -f "$FIELDS_DB" "$FIELDS_DB.wal"
+
+# Test 45: Whole-database verification
+VF_DB="comprehensive_test_verify.json"
+VF_ARGS="--database $VF_DB --compact-after 1"
+rm -f "$VF_DB" "$VF_DB.wal"
+$CLI $VF_ARGS insert vdoc1 alpha 'first' >/dev/null 2>&1
+$CLI $VF_ARGS insert vdoc1 beta 'second' >/dev/null 2>&1
+$CLI $VF_ARGS insert vdoc2 gamma 'third' >/dev/null 2>&1
+run_test "Verify clean database" "$CLI $VF_ARGS verify" "Verified 3 field(s) in 2 document(s)"
+# Corrupt the first ciphertext byte of vdoc1.beta
+python3 -c "import base64,json,sys; p=sys.argv[1]; d=json.load(open(p)); f=d['documents']['vdoc1']['fields']['beta']; c=bytearray(base64.b64decode(f['cipher'])); c[0]=(c[0]+1)%256; f['cipher']=base64.b64encode(c).decode(); json.dump(d, open(p,'w'))" "$VF_DB"
+resign_db "$VF_DB"
+run_test "Verify fails on corruption" "! $CLI $VF_ARGS verify" "1 of 3 field(s) failed verification"
+run_test "Verify names the corrupt field" "$CLI $VF_ARGS verify 2>&1 | grep -c 'vdoc1.beta'" "^1$"
+run_test "Verify passes the other fields" "$CLI $VF_ARGS verify 2>&1 | grep -cE 'vdoc1.alpha|vdoc2.gamma' || true" "^0$"
+rm -f "$VF_DB" "$VF_DB.wal"
+
+# Test 46: Removing documents by value
+$CLI $DB_ARGS insert rv1 status 'stale' >/dev/null 2>&1
+$CLI $DB_ARGS insert rv2 state 'stale' >/dev/null 2>&1
+$CLI $DB_ARGS insert rv3 status 'fresh' >/dev/null 2>&1
+run_test "Remove matching documents" "$CLI $DB_ARGS remove-value 'stale'" "Removed 2 document(s) matching 'stale'"
+run_test "Matching documents gone" "$CLI $DB_ARGS query 'stale' || true" "No documents found"
+run_test "Removed document not shown" "$CLI $DB_ARGS show rv2" "not found"
+run_test "Other documents kept" "$CLI $DB_ARGS query 'fresh'" "rv3"
+run_test "Remove with no matches" "$CLI $DB_ARGS remove-value 'stale'" "Removed 0 document(s)"
+
+# Test 47: Paginated listing
+PG_DB="comprehensive_test_pages.json"
+rm -f "$PG_DB" "$PG_DB.wal"
+for id in pg_e pg_c pg_a pg_d pg_b; do
+    $CLI --database $PG_DB insert "$id" data 'page' >/dev/null 2>&1
+done
+PAGE_IDS="grep -o 'pg_[a-e]' | tr '\\n' ' '"
+run_test "First page" "$CLI --database $PG_DB list --limit 2 | $PAGE_IDS" "^pg_a pg_b $"
+run_test "Middle page" "$CLI --database $PG_DB list --limit 2 --offset 2 | $PAGE_IDS" "^pg_c pg_d $"
+run_test "Last partial page" "$CLI --database $PG_DB list --limit 2 --offset 4 | $PAGE_IDS" "^pg_e $"
+run_test "Page reports total" "$CLI --database $PG_DB list --limit 2 --offset 2" "contains 5 document(s), showing 3-4"
+run_test "Offset past the end is empty" "$CLI --database $PG_DB list --offset 10" "none at offset 10"
+run_test "Pages are stable across calls" "[ \"\$($CLI --database $PG_DB list --limit 3 | $PAGE_IDS)\" = \"\$($CLI --database $PG_DB list --limit 3 | $PAGE_IDS)\" ] && echo 'stable'" "stable"
+run_test "JSON page" "$CLI --database $PG_DB --output json list --limit 1 --offset 1 | python3 -c 'import json,sys; print([e[\"id\"] for e in json.load(sys.stdin)])'" "\['pg_b'\]"
+rm -f "$PG_DB" "$PG_DB.wal"
+
+# Test 48: Length queries
+$CLI $DB_ARGS insert len1 code 'abcd' >/dev/null 2>&1
+$CLI $DB_ARGS insert len2 code 'wxyz' >/dev/null 2>&1
+$CLI $DB_ARGS insert len3 code 'abcdefgh' >/dev/null 2>&1
+$CLI $DB_ARGS insert len4 other 'abcd' >/dev/null 2>&1
+run_test "Length query matches" "$CLI $DB_ARGS query-length code 4 | grep -o 'len[0-9]' | tr '\\n' ' '" "^len1 len2 $"
+run_test "Length query other length" "$CLI $DB_ARGS query-length code 8" "Found 1 document"
+run_test "Length counts bytes" "$CLI $DB_ARGS insert len5 code '日本' >/dev/null && $CLI $DB_ARGS query-length code 6" "len5"
+run_test "Length query no match" "$CLI $DB_ARGS query-length code 5" "No documents found"
+
+# Test 49: Decrypting binary values
+BIN_DB="comprehensive_test_binary.json"
+BIN_ARGS="--database $BIN_DB --compact-after 1"
+BIN_OUT="comprehensive_test_binary.out"
+rm -f "$BIN_DB" "$BIN_DB.wal" "$BIN_OUT"
+# A 128-byte value padded to 256 bytes gains 128 bytes of 0x80; dropping the
+# padding record makes decrypt return them, which is not valid UTF-8
+$CLI $BIN_ARGS --pad 256 insert bin blob "$(head -c 128 /dev/zero | tr '\0' 'a')" >/dev/null 2>&1
+python3 -c "import json,sys; p=sys.argv[1]; d=json.load(open(p)); del d['documents']['bin']['fields']['blob']['pad_block']; json.dump(d, open(p,'w'))" "$BIN_DB"
+resign_db "$BIN_DB"
+run_test "Text decrypt rejects binary" "! $CLI $BIN_ARGS decrypt bin blob" "not valid UTF-8; use --hex or --out"
+run_test "Hex decrypt of binary" "$CLI $BIN_ARGS decrypt bin blob --hex" "bin.blob: \(61\)\{128\}\(80\)\{128\}$"
+run_test "File decrypt of binary" "$CLI $BIN_ARGS decrypt bin blob --out $BIN_OUT && [ \"\$(od -An -tx1 -v $BIN_OUT | tr -d ' \\n')\" = \"\$($CLI $BIN_ARGS decrypt bin blob --hex | sed -n 's/.*bin.blob: //p')\" ] && echo 'bytes match'" "bytes match"
+run_test "File decrypt size" "$CLI $BIN_ARGS decrypt bin blob --out $BIN_OUT" "wrote 256 B to $BIN_OUT"
+rm -f "$BIN_DB" "$BIN_DB.wal" "$BIN_OUT"
+
+# Test 50: Transactional batches
+TX_DB="comprehensive_test_tx.json"
+TX_BATCH="comprehensive_test_tx_batch.json"
+rm -f "$TX_DB" "$TX_DB.wal" "$TX_BATCH"
+$CLI --database $TX_DB insert tx_keep name 'Kept' >/dev/null 2>&1
+cat > "$TX_BATCH" <<'BATCH'
+[
+  {"op": "insert", "id": "tx1", "field": "name", "value": "Batch One"},
+  {"op": "remove", "id": "tx_keep"},
+  {"op": "remove", "id": "tx_missing"}
+]
+BATCH
+run_test "Failing batch reports error" "! $CLI --database $TX_DB exec $TX_BATCH" "no changes were applied"
+run_test "Failing batch inserted nothing" "$CLI --database $TX_DB query 'Batch One' || true" "No documents found"
+run_test "Failing batch removed nothing" "$CLI --database $TX_DB show tx_keep" "Document: tx_keep"
+cat > "$TX_BATCH" <<'BATCH'
+[
+  {"op": "insert", "id": "tx1", "field": "name", "value": "Batch One"},
+  {"op": "insert", "id": "tx1", "field": "city", "value": "Paris"},
+  {"op": "remove", "id": "tx_keep"}
+]
+BATCH
+run_test "Batch applies" "$CLI --database $TX_DB exec $TX_BATCH" "Applied 3 operation(s)"
+run_test "Batch fields merged" "$CLI --database $TX_DB --output json show tx1 | python3 -c 'import json,sys; print(sorted(json.load(sys.stdin)[\"fields\"]))'" "\['city', 'name'\]"
+run_test "Batch remove applied" "$CLI --database $TX_DB show tx_keep" "not found"
+rm -f "$TX_DB" "$TX_DB.wal" "$TX_BATCH"
+
+# Test 51: Reporting overwritten fields
+run_test "First write is an insert" "$CLI $DB_ARGS -v insert chg1 color 'red'" "Inserted field 'color' in document 'chg1'"
+run_test "Overwrite is an update" "$CLI $DB_ARGS -v insert chg1 color 'blue'" "Updated field 'color' in document 'chg1'"
+run_test "Changed value changes token" "$CLI $DB_ARGS -v insert chg1 color 'green'" "Token changed: yes"
+run_test "Same value keeps token" "$CLI $DB_ARGS -v insert chg1 color 'green'" "Token changed: no"
+run_test "New field in existing document is an insert" "$CLI $DB_ARGS -v insert chg1 size 'L'" "Inserted field 'size'"
+
+# Test 52: Weak key rejection
+WK_DB="comprehensive_test_weak_key.json"
+WK_ARGS="--database $WK_DB --compact-after 1"
+rm -f "$WK_DB" "$WK_DB.wal"
+run_test "Generated key accepted" "$CLI $WK_ARGS insert wk1 name 'Key Test'" "Inserted document 'wk1'"
+run_test "Generated key reloads" "$CLI $WK_ARGS query 'Key Test'" "Found 1 document"
+python3 -c "import json,sys; p=sys.argv[1]; d=json.load(open(p)); d['secret_key']=[0]*32; json.dump(d, open(p,'w'))" "$WK_DB"
+run_test "All-zero key rejected" "! $CLI $WK_ARGS list" "Refusing to use the database key"
+rm -f "$WK_DB" "$WK_DB.wal"
+
+# Test 53: Merging databases
+MG_BASE="comprehensive_test_merge_base.json"
+MG_SAME="comprehensive_test_merge_same.json"
+MG_OTHER="comprehensive_test_merge_other.json"
+MG_DB="comprehensive_test_merge.json"
+rm -f "$MG_BASE" "$MG_SAME" "$MG_OTHER" "$MG_DB" "$MG_BASE.wal" "$MG_SAME.wal" "$MG_OTHER.wal" "$MG_DB.wal"
+$CLI --database $MG_BASE --compact-after 1 insert col1 name 'Base Name' >/dev/null 2>&1
+$CLI --database $MG_BASE --compact-after 1 insert col1 base_only 'b' >/dev/null 2>&1
+# Same key: a copy of the base database that then diverges
+cp "$MG_BASE" "$MG_SAME"
+$CLI --database $MG_SAME --compact-after 1 remove col1 >/dev/null 2>&1
+$CLI --database $MG_SAME --compact-after 1 insert col1 name 'Same Name' >/dev/null 2>&1
+$CLI --database $MG_SAME --compact-after 1 insert col1 same_only 's' >/dev/null 2>&1
+$CLI --database $MG_SAME --compact-after 1 insert new1 name 'New Doc' >/dev/null 2>&1
+# Different key: an unrelated database
+$CLI --database $MG_OTHER --compact-after 1 insert far1 name 'Far Away' >/dev/null 2>&1
+$CLI --database $MG_OTHER --compact-after 1 insert col1 name 'Other Name' >/dev/null 2>&1
+MG_FIELDS="python3 -c 'import json,sys; print(sorted(json.load(sys.stdin)[\"fields\"]))'"
+
+cp "$MG_BASE" "$MG_DB" && rm -f "$MG_DB.wal"
+run_test "Merge skips collisions by default" "$CLI --database $MG_DB merge $MG_SAME" "1 added, 0 overwritten, 1 skipped"
+run_test "Disjoint id merged" "$CLI --database $MG_DB query 'New Doc'" "new1"
+run_test "Skipped document kept" "$CLI --database $MG_DB decrypt col1 name" "col1.name: Base Name"
+
+cp "$MG_BASE" "$MG_DB" && rm -f "$MG_DB.wal"
+run_test "Merge with overwrite" "$CLI --database $MG_DB merge $MG_SAME --on-conflict overwrite" "1 added, 1 overwritten, 0 skipped"
+run_test "Overwrite replaces document" "$CLI --database $MG_DB --output json show col1 | $MG_FIELDS" "\['name', 'same_only'\]"
+run_test "Overwrite takes incoming value" "$CLI --database $MG_DB decrypt col1 name" "col1.name: Same Name"
+
+cp "$MG_BASE" "$MG_DB" && rm -f "$MG_DB.wal"
+run_test "Merge with merge-fields" "$CLI --database $MG_DB merge $MG_SAME --on-conflict merge-fields" "1 added, 1 merged, 0 skipped"
+run_test "Merge-fields keeps both field sets" "$CLI --database $MG_DB --output json show col1 | $MG_FIELDS" "\['base_only', 'name', 'same_only'\]"
+run_test "Merge-fields takes incoming shared value" "$CLI --database $MG_DB query 'Same Name'" "col1"
+
+cp "$MG_BASE" "$MG_DB" && rm -f "$MG_DB.wal"
+run_test "Different key refused" "! $CLI --database $MG_DB merge $MG_OTHER" "different key; pass --reencrypt"
+run_test "Refused merge changed nothing" "$CLI --database $MG_DB query 'Far Away' || true" "No documents found"
+run_test "Merge with re-encryption" "$CLI --database $MG_DB merge $MG_OTHER --reencrypt --on-conflict overwrite" "1 added, 1 overwritten"
+run_test "Re-encrypted value searchable" "$CLI --database $MG_DB query 'Far Away'" "far1"
+run_test "Re-encrypted value decrypts" "$CLI --database $MG_DB decrypt col1 name" "col1.name: Other Name"
+# A source that needs its id backfilled is still only read
+python3 -c "import json; p='$MG_SAME'; d=json.load(open(p)); d.pop('db_id'); json.dump(d, open(p, 'w'))"
+MG_SUM=$(sha256sum < "$MG_SAME")
+cp "$MG_BASE" "$MG_DB" && rm -f "$MG_DB.wal"
+run_test "Merge reads an unsaved source" "$CLI --database $MG_DB merge $MG_SAME" "1 added"
+run_test "Merge never rewrites the source" "[ \"\$(sha256sum < $MG_SAME)\" = \"$MG_SUM\" ] && echo unchanged" "^unchanged$"
+# Sources whose key only lives in the environment
+MG_ENV="comprehensive_test_merge_env.json"
+MG_KEY=$(python3 -c "import secrets; print(secrets.token_hex(32))")
+rm -f "$MG_ENV" "$MG_ENV.wal"
+MAGENTADB_KEY=$MG_KEY $CLI --database $MG_ENV insert env1 name 'Env Doc' >/dev/null 2>&1
+cp "$MG_BASE" "$MG_DB" && rm -f "$MG_DB.wal"
+run_test "Merge without the source key is refused" "$CLI --database $MG_DB merge $MG_ENV --reencrypt 2>&1 || true" "does not store its key"
+run_test "Merge with --source-key" "$CLI --database $MG_DB merge $MG_ENV --reencrypt --source-key $MG_KEY" "1 added"
+run_test "Merged value decrypts under this database's key" "$CLI --database $MG_DB decrypt env1 name" "env1.name: Env Doc"
+MG_ENV_DB="comprehensive_test_merge_env_db.json"
+rm -f "$MG_ENV_DB" "$MG_ENV_DB.wal"
+MAGENTADB_KEY=$MG_KEY $CLI --database $MG_ENV_DB insert own1 name 'Own Doc' >/dev/null 2>&1
+run_test "Merge opens the source with MAGENTADB_KEY" "MAGENTADB_KEY=$MG_KEY $CLI --database $MG_ENV_DB merge $MG_ENV" "1 added"
+rm -f "$MG_ENV" "$MG_ENV.wal" "$MG_ENV_DB" "$MG_ENV_DB.wal"
+rm -f "$MG_BASE" "$MG_SAME" "$MG_OTHER" "$MG_DB" "$MG_BASE.wal" "$MG_SAME.wal" "$MG_OTHER.wal" "$MG_DB.wal"
+
+# Test 54: Existence checks
+$CLI $DB_ARGS insert exists1 name 'here' >/dev/null 2>&1
+run_test "Present id exists" "$CLI $DB_ARGS exists exists1" "^yes$"
+run_test "Absent id does not exist" "($CLI $DB_ARGS exists missing1 2>/dev/null; echo \"exit \$?\") | tr '\\n' ' '" " no exit 1 $"
+run_test "Exists usable in conditionals" "if $CLI $DB_ARGS exists exists1 >/dev/null; then echo 'found'; fi" "found"
+
+# Test 55: Per-document keys
+DK_DB="/tmp/magentadb_dockeys.json"
+rm -f "$DK_DB" "$DK_DB.wal"
+DK_ARGS="--database $DK_DB --compact-after 1 --document-keys"
+$CLI $DK_ARGS insert dk1 name 'Wrapped Value' >/dev/null 2>&1
+$CLI $DK_ARGS insert dk1 city 'Lisbon' >/dev/null 2>&1
+$CLI $DK_ARGS insert dk2 name 'Wrapped Value' >/dev/null 2>&1
+run_test "Decrypt field under document key" "$CLI $DK_ARGS decrypt dk1 city" "Lisbon"
+run_test "Query across document keys" "$CLI $DK_ARGS query 'Wrapped Value'" "Found 2"
+run_test "Documents store wrapped keys" "python3 -c \"import json; d=json.load(open('$DK_DB'))['documents']; print(all(doc.get('wrapped_key') for doc in d.values()))\"" "True"
+run_test "Stats reports document keys" "$CLI $DK_ARGS stats" "Document keys: enabled"
+python3 -c "import base64,json; p='$DK_DB'; d=json.load(open(p)); w=d['documents']['dk1']['wrapped_key']; c=bytearray(base64.b64decode(w['cipher'])); c[0]^=1; w['cipher']=base64.b64encode(c).decode(); json.dump(d, open(p, 'w'))"
+resign_db "$DK_DB"
+run_test "Corrupt wrapped key fails decrypt" "$CLI $DK_ARGS decrypt dk1 name 2>&1 || true" "Failed to unwrap key"
+run_test "Verify flags corrupt wrapped key" "$CLI $DK_ARGS verify 2>&1 || true" "2 of 3 field(s) failed"
+rm -f "$DK_DB" "$DK_DB.wal"
+
+# Test 56: Exact queries filter token collisions
+CO_DB="/tmp/magentadb_collide.json"
+rm -f "$CO_DB" "$CO_DB.wal"
+CO_ARGS="--database $CO_DB --compact-after 1"
+$CLI $CO_ARGS insert real1 name 'Real Value' >/dev/null 2>&1
+$CLI $CO_ARGS insert fake1 name 'Impostor' >/dev/null 2>&1
+# Copy the token across, as if truncation had made the two values collide
+python3 -c "import json; p='$CO_DB'; d=json.load(open(p)); docs=d['documents']; docs['fake1']['fields']['name']['token']=docs['real1']['fields']['name']['token']; json.dump(d, open(p, 'w'))"
+resign_db "$CO_DB"
+run_test "Colliding token matches both documents" "$CLI $CO_ARGS query 'Real Value'" "Found 2"
+run_test "Exact query drops the impostor" "$CLI $CO_ARGS query --exact 'Real Value'" "Found 1"
+run_test "Exact query warns about collisions" "$CLI $CO_ARGS query --exact 'Real Value'" "Dropped 1 document"
+run_test "Exact query keeps the real match" "$CLI $CO_ARGS --output json query --exact 'Real Value'" '"id": "real1"'
+rm -f "$CO_DB" "$CO_DB.wal"
+
+# Test 57: Document timestamps
+$CLI $DB_ARGS insert stamp1 name 'first' >/dev/null 2>&1
+$CLI $DB_ARGS --output json show stamp1 > /tmp/magentadb_stamp_before.json 2>/dev/null
+sleep 0.1
+$CLI $DB_ARGS update stamp1 name 'second' >/dev/null 2>&1
+$CLI $DB_ARGS --output json show stamp1 > /tmp/magentadb_stamp_after.json 2>/dev/null
+run_test "Show displays timestamps" "$CLI $DB_ARGS show stamp1" "Updated: 20"
+run_test "Update keeps created_at" "python3 -c \"import json; a=json.load(open('/tmp/magentadb_stamp_before.json')); b=json.load(open('/tmp/magentadb_stamp_after.json')); print(a['created_at'] == b['created_at'])\"" "True"
+run_test "Update bumps updated_at" "python3 -c \"import json; a=json.load(open('/tmp/magentadb_stamp_before.json')); b=json.load(open('/tmp/magentadb_stamp_after.json')); print(b['updated_at'] > a['updated_at'])\"" "True"
+rm -f /tmp/magentadb_stamp_before.json /tmp/magentadb_stamp_after.json
+
+# Test 58: Pattern scans over decrypted values
+GR_DB="/tmp/magentadb_grep.json"
+rm -f "$GR_DB" "$GR_DB.wal"
+GR_ARGS="--database $GR_DB"
+$CLI $GR_ARGS insert gr1 email 'john@example.com' >/dev/null 2>&1
+$CLI $GR_ARGS insert gr1 name 'John Example' >/dev/null 2>&1
+$CLI $GR_ARGS insert gr2 email 'jane@test.org' >/dev/null 2>&1
+$CLI $GR_ARGS insert gr3 phone '555-0123' >/dev/null 2>&1
+run_test "Grep matches substrings across fields" "$CLI $GR_ARGS grep 'xample'" "Found 1 document"
+run_test "Grep reports each matching field" "$CLI $GR_ARGS --output json grep 'xample' | tr -d ' \\n'" '"matched_fields":\["email","name"\]'
+run_test "Grep is case-sensitive" "$CLI $GR_ARGS grep 'EXAMPLE'" "No documents found"
+run_test "Grep anchors at the start" "$CLI $GR_ARGS grep '^jane@'" "Found 1 document"
+run_test "Grep anchored mismatch finds nothing" "$CLI $GR_ARGS grep '^ohn'" "No documents found"
+run_test "Grep anchors at the end" "$CLI $GR_ARGS grep '0123\$'" "gr3"
+run_test "Grep anchored at both ends matches whole values" "$CLI $GR_ARGS grep '^555-0123\$'" "gr3"
+run_test "Grep anchored at both ends rejects substrings" "$CLI $GR_ARGS grep '^555-012\$'" "No documents found"
+run_test "Grep restricted to a field" "$CLI $GR_ARGS grep --field name 'o'" "Found 1 document"
+run_test "Grep treats regex syntax literally" "$CLI $GR_ARGS grep 'j.*@'" "No documents found"
+$CLI $GR_ARGS insert gr4 note '^price$ a.b \\' >/dev/null 2>&1
+run_test "Grep matches metacharacters literally" "$CLI $GR_ARGS grep 'a.b'" "gr4"
+run_test "Grep escapes anchors" "$CLI $GR_ARGS grep '\\^price\\$ '" "gr4"
+run_test "Grep escapes backslashes" "$CLI $GR_ARGS grep '\\\\\$'" "gr4"
+run_test "Trailing backslash is a clean error" "$CLI $GR_ARGS grep 'abc\\' 2>&1 || true" "Invalid pattern at position 3: trailing"
+run_test "Escaped letter is a clean error" "$CLI $GR_ARGS grep '^\\d' 2>&1 || true" "Invalid pattern at position 1: only punctuation can be escaped"
+rm -f "$GR_DB" "$GR_DB.wal"
+
+# Test 59: Per-database token algorithm
+TA_DB="/tmp/magentadb_token_algo.json"
+TA_OTHER="/tmp/magentadb_token_algo_other.json"
+rm -f "$TA_DB" "$TA_DB.wal" "$TA_OTHER" "$TA_OTHER.wal"
+$CLI --database "$TA_DB" --token-algo sha512 insert ta1 email 'ta@example.com' >/dev/null 2>&1
+run_test "SHA-512 tokens round-trip" "$CLI --database $TA_DB query 'ta@example.com'" "Found 1 document"
+run_test "Stored token algorithm is honored on reload" "$CLI --database $TA_DB stats" "Token algorithm: sha512"
+run_test "Token algorithm cannot change after creation" "$CLI --database $TA_DB --token-algo sha256 stats 2>&1 || true" "cannot be changed after creation"
+$CLI --database "$TA_OTHER" insert ta2 email 'ta@example.com' >/dev/null 2>&1
+run_test "SHA-256 tokens round-trip" "$CLI --database $TA_OTHER query 'ta@example.com'" "Found 1 document"
+run_test "SHA-256 and SHA-512 tokens differ" "[ \"\$($CLI --database $TA_DB -v query nope | grep 'Search token')\" != \"\$($CLI --database $TA_OTHER -v query nope | grep 'Search token')\" ] && echo distinct" "distinct"
+run_test "Merge refuses mixed token algorithms" "$CLI --database $TA_OTHER merge $TA_DB 2>&1 || true" "different key"
+$CLI --database "$TA_OTHER" merge "$TA_DB" --reencrypt >/dev/null 2>&1
+run_test "Merged fields are re-tokenized" "$CLI --database $TA_OTHER query 'ta@example.com'" "Found 2 document"
+rm -f "$TA_DB" "$TA_DB.wal" "$TA_OTHER" "$TA_OTHER.wal"
+
+# Test 60: Bloom filter fast path for token lookups
+BF_DB="/tmp/magentadb_token_filter.json"
+rm -f "$BF_DB" "$BF_DB.wal"
+BF_ARGS="--database $BF_DB --token-filter"
+for i in {1..25}; do
+    $CLI --database "$BF_DB" insert "bf$i" email "bf$i@example.com" >/dev/null 2>&1
+done
+$CLI --database "$BF_DB" insert bf1 name 'Bloom Filter' --prefix >/dev/null 2>&1
+BF_MISSES=0
+for i in {1..25}; do
+    $CLI $BF_ARGS query "bf$i@example.com" | grep -q "Found 1 document" || BF_MISSES=$((BF_MISSES + 1))
+done
+run_test "Token filter has no false negatives" "echo misses=$BF_MISSES" "misses=0"
+run_test "Token filter passes prefix lookups through" "$CLI $BF_ARGS query-prefix Blo" "bf1"
+run_test "Token filter rejects absent values" "$CLI $BF_ARGS query 'absent@example.com' || true" "No documents found"
+run_test "Token filter counts present values" "$CLI $BF_ARGS count --token 'bf7@example.com'" "1 document"
+run_test "Token filter reports false-positive rate" "$CLI $BF_ARGS stats" "Token filter false-positive rate:"
+run_test "Token filter stays in sync with removals" "printf 'remove bf2\\nquery bf2@example.com\\ninsert bf2 email bf2@example.com\\nquery bf2@example.com\\n' | $CLI $BF_ARGS repl" "Found 1 document"
+run_test "Stats omit the rate without a filter" "$CLI --database $BF_DB stats | grep -c 'Token filter' || true" "^0$"
+rm -f "$BF_DB" "$BF_DB.wal"
+
+# Test 61: Configurable mask policy
+MK_DB="/tmp/magentadb_mask.json"
+rm -f "$MK_DB" "$MK_DB.wal"
+$CLI --database "$MK_DB" insert mk1 email 'john@example.com' >/dev/null 2>&1
+$CLI --database "$MK_DB" --mask none insert mk2 email 'john@example.com' >/dev/null 2>&1
+$CLI --database "$MK_DB" --mask first:4 insert mk3 email 'john@example.com' >/dev/null 2>&1
+run_test "Length-hint policy is the default" "$CLI --database $MK_DB show mk1" "email: ••• (medium)"
+run_test "None policy reveals nothing" "$CLI --database $MK_DB show mk2" "email: •••$"
+run_test "FirstN policy reveals a preview" "$CLI --database $MK_DB show mk3" "email: john•••$"
+$CLI --database "$MK_DB" insert mk4 email 'jo' >/dev/null 2>&1
+run_test "Mask policy is stored with the database" "$CLI --database $MK_DB show mk4" "email: jo•••$"
+run_test "Stats report a non-default mask policy" "$CLI --database $MK_DB stats" "Mask policy: first:4"
+run_test "Invalid mask policy is rejected" "$CLI --database $MK_DB --mask first:x stats 2>&1 || true" "unknown mask policy"
+rm -f "$MK_DB" "$MK_DB.wal"
+
+# Test 62: Several fields in one insert
+MS_DB="/tmp/magentadb_multi_set.json"
+rm -f "$MS_DB" "$MS_DB.wal"
+MS_ARGS="--database $MS_DB --compact-after 100"
+$CLI $MS_ARGS insert ms1 name Alice >/dev/null 2>&1
+run_test "Multiple --set fields land in one document" "$CLI $MS_ARGS insert ms2 --set name=bob --set email=bob@example.com --set 'note=a=b' && $CLI $MS_ARGS show ms2" "note:"
+run_test "Multi-field insert is written once" "wc -l < $MS_DB.wal" "^1$"
+run_test "Values may contain '='" "$CLI $MS_ARGS decrypt ms2 note" "Decrypted ms2.note: a=b"
+run_test "Positional field combines with --set" "$CLI $MS_ARGS insert ms2 city Paris --set email=new@example.com && $CLI $MS_ARGS decrypt ms2 email" "new@example.com"
+run_test "Combined fields are all queryable" "$CLI $MS_ARGS query Paris" "ms2"
+run_test "Repeated field is rejected" "$CLI $MS_ARGS insert ms3 --set a=1 --set a=2 2>&1 || true" "Field 'a' is set more than once"
+run_test "Malformed --set is rejected" "$CLI $MS_ARGS insert ms3 --set novalue 2>&1 || true" "expected FIELD=VALUE"
+rm -f "$MS_DB" "$MS_DB.wal"
+
+# Test 63: Values from stdin and files
+VS_DB="/tmp/magentadb_value_source.json"
+VS_FILE="/tmp/magentadb_value_source.txt"
+rm -f "$VS_DB" "$VS_DB.wal"
+printf 'line one\nline two\n\n' > "$VS_FILE"
+run_test "Insert reads the value from stdin" "echo 'stdin secret' | $CLI --database $VS_DB insert vs1 secret --value-stdin && $CLI --database $VS_DB decrypt vs1 secret" "Decrypted vs1.secret: stdin secret$"
+run_test "Stdin value keeps inner newlines" "printf 'a\\nb' | $CLI --database $VS_DB insert vs1 multi --value-stdin && $CLI --database $VS_DB decrypt vs1 multi --hex" "610a62$"
+run_test "Insert reads the value from a file verbatim" "$CLI --database $VS_DB insert vs1 notes --value-file $VS_FILE && $CLI --database $VS_DB decrypt vs1 notes --hex" "$(xxd -p "$VS_FILE" | tr -d '\n')$"
+run_test "File values are searchable" "$CLI --database $VS_DB count --field notes" "1 document"
+run_test "Missing value without a terminal is an error" "$CLI --database $VS_DB insert vs2 secret < /dev/null 2>&1 || true" "No value given for 'secret'"
+run_test "Value sources conflict" "echo x | $CLI --database $VS_DB insert vs2 secret x --value-stdin 2>&1 || true" "cannot be used with"
+rm -f "$VS_DB" "$VS_DB.wal" "$VS_FILE"
+
+# Test 64: Plaintext document metadata
+MD_DB="/tmp/magentadb_metadata.json"
+rm -f "$MD_DB" "$MD_DB.wal"
+MD_ARGS="--database $MD_DB --compact-after 1"
+$CLI $MD_ARGS insert md1 email 'shared@example.com' >/dev/null 2>&1
+$CLI $MD_ARGS insert md2 email 'shared@example.com' >/dev/null 2>&1
+run_test "Absent metadata is not stored" "grep -c '\"metadata\"' $MD_DB || true" "^0$"
+run_test "Set metadata on a document" "$CLI $MD_ARGS set-meta md1 owner alice" "Set metadata 'owner' on document 'md1'"
+run_test "Overwrite metadata" "$CLI $MD_ARGS set-meta md1 owner bob" "Updated metadata 'owner' on document 'md1'"
+$CLI $MD_ARGS set-meta md1 type pdf >/dev/null 2>&1
+$CLI $MD_ARGS set-meta md2 owner alice >/dev/null 2>&1
+run_test "Show lists metadata" "$CLI $MD_ARGS show md1" "Metadata: owner=bob, type=pdf"
+run_test "Metadata is stored in plaintext" "grep -A3 '\"metadata\"' $MD_DB" '"owner": "bob"'
+run_test "Query by metadata" "$CLI $MD_ARGS query --meta owner=alice" "md2"
+run_test "Query by several metadata labels" "$CLI $MD_ARGS --output json query --meta owner=bob --meta type=pdf | tr -d ' \n'" '^\[{"id":"md1","matched_fields":\[\]}\]$'
+run_test "Metadata narrows a value query" "$CLI $MD_ARGS query shared@example.com --meta owner=alice" "Found 1 document"
+run_test "Overwritten metadata no longer matches" "$CLI $MD_ARGS query --meta owner=alice --meta type=pdf || true" "No documents found with metadata"
+run_test "Metadata requires an existing document" "$CLI $MD_ARGS set-meta missing owner x 2>&1 || true" "Document not found: missing"
+rm -f "$MD_DB" "$MD_DB.wal"
+
+# Test 65: Missing, unreadable and corrupt database files
+LD_DB="/tmp/magentadb_load.json"
+LD_DIR="/tmp/magentadb_load_dir"
+rm -rf "$LD_DB" "$LD_DB.wal" "$LD_DIR"
+run_test "Missing database file is created" "$CLI --database $LD_DB insert ld1 name Alice && test -f $LD_DB && echo created" "created"
+mkdir -p "$LD_DIR"
+run_test "Unreadable database path is an error" "$CLI --database $LD_DIR stats 2>&1 || true" "Failed to read database file $LD_DIR; refusing to create a new one"
+printf '\xff\xfe\x00' > "$LD_DB"
+run_test "Non-UTF-8 database file is an error" "$CLI --database $LD_DB stats 2>&1 || true" "Failed to read database file"
+echo '{"documents": {' > "$LD_DB"
+LD_SUM=$(cksum < "$LD_DB")
+run_test "Corrupt database file is an error" "$CLI --database $LD_DB insert ld2 name Bob 2>&1 || true" "Failed to parse database file $LD_DB; it was left untouched"
+run_test "Corrupt database file is not overwritten" "[ \"\$(cksum < $LD_DB)\" = \"$LD_SUM\" ] && [ ! -e $LD_DB.wal ] && echo untouched" "untouched"
+rm -rf "$LD_DB" "$LD_DB.wal" "$LD_DIR"
+
+# Test 66: Dry runs of destructive commands
+DR_DB="/tmp/magentadb_dry_run.json"
+rm -f "$DR_DB" "$DR_DB.wal"
+DR_ARGS="--database $DR_DB"
+$CLI $DR_ARGS insert dr1 email 'dup@example.com' >/dev/null 2>&1
+$CLI $DR_ARGS insert dr2 --set email=dup@example.com --set note=extra >/dev/null 2>&1
+$CLI $DR_ARGS insert dr3 email 'solo@example.com' >/dev/null 2>&1
+dr_sum() { cat "$DR_DB" "$DR_DB.wal" 2>/dev/null | cksum; }
+DR_SUM=$(dr_sum)
+run_test "Dry-run remove-value lists the matches" "$CLI $DR_ARGS --dry-run remove-value dup@example.com" "would remove 2 document"
+run_test "Dry-run predicts index shrinkage" "$CLI $DR_ARGS --dry-run remove-value dup@example.com" "Field index size: 2 → 1"
+run_test "Dry-run clear does not prompt" "$CLI $DR_ARGS --dry-run clear < /dev/null" "would remove 3 document"
+run_test "Dry-run remove reports a missing document" "$CLI $DR_ARGS --dry-run remove nope 2>&1 || true" "Document 'nope' not found"
+run_test "Dry-run leaves the files untouched" "[ \"\$(dr_sum)\" = \"$DR_SUM\" ] && echo untouched" "untouched"
+run_test "Dry-run leaves the documents in place" "$CLI $DR_ARGS stats" "Documents: 3"
+DR_PREDICTED=$($CLI $DR_ARGS --dry-run remove-value dup@example.com | sed -n 's/.*Token index size: [0-9]* → \([0-9]*\)/\1/p')
+run_test "Real run matches the prediction" "$CLI $DR_ARGS remove-value dup@example.com && $CLI $DR_ARGS stats" "Token index size: $DR_PREDICTED$"
+run_test "Dry-run rejects other commands" "$CLI $DR_ARGS --dry-run insert dr4 a b 2>&1 || true" "only applies to remove"
+rm -f "$DR_DB" "$DR_DB.wal"
+
+# Test 67: Rebuilding indexes
+RI_DB="/tmp/magentadb_reindex.json"
+rm -f "$RI_DB" "$RI_DB.wal"
+RI_ARGS="--database $RI_DB"
+$CLI $RI_ARGS insert ri1 --set email=ri@example.com --set name=Rita >/dev/null 2>&1
+$CLI $RI_ARGS insert ri2 email 'ri@example.com' --prefix >/dev/null 2>&1
+$CLI $RI_ARGS insert-num ri3 age 41 >/dev/null 2>&1
+RI_SUM=$(cat "$RI_DB" "$RI_DB.wal" 2>/dev/null | cksum)
+RI_TOKENS=$($CLI $RI_ARGS stats | sed -n 's/.*Token index size: //p')
+run_test "Reindex covers every document" "$CLI $RI_ARGS reindex" "Rebuilt indexes from 3 document"
+run_test "Reindex matches the document-derived token index" "$CLI $RI_ARGS reindex" "Token index size: $RI_TOKENS → $RI_TOKENS$"
+run_test "Reindex matches the document-derived field index" "$CLI $RI_ARGS reindex" "Field index size: 3 → 3"
+run_test "Lookups work after reindex" "printf 'reindex\\nquery ri@example.com\\nquery-prefix ri@ex\\nquery-range age --min 40 --max 42\\n' | $CLI $RI_ARGS repl" "ri3"
+run_test "Reindex writes nothing" "[ \"\$(cat $RI_DB $RI_DB.wal 2>/dev/null | cksum)\" = \"$RI_SUM\" ] && echo untouched" "untouched"
+rm -f "$RI_DB" "$RI_DB.wal"
+
+# Test 68: Queries restricted to one field
+QF_DB="/tmp/magentadb_query_field.json"
+rm -f "$QF_DB" "$QF_DB.wal"
+QF_ARGS="--database $QF_DB"
+$CLI $QF_ARGS insert qf1 email 'same@example.com' >/dev/null 2>&1
+$CLI $QF_ARGS insert qf2 backup_email 'same@example.com' >/dev/null 2>&1
+$CLI $QF_ARGS insert qf3 --set email=same@example.com --set alias=same@example.com >/dev/null 2>&1
+run_test "Unrestricted query matches every field" "$CLI $QF_ARGS query same@example.com" "Found 3 document"
+run_test "Field filter narrows the results" "$CLI $QF_ARGS --output json query same@example.com --field email | tr -d ' \n'" '^\[{"id":"qf1","matched_fields":\["email"\]},{"id":"qf3","matched_fields":\["email"\]}\]$'
+run_test "Field filter reports only that field" "$CLI $QF_ARGS query same@example.com --field alias" "alias:"
+run_test "Field filter combines with --exact" "$CLI $QF_ARGS query same@example.com --field backup_email --exact" "qf2"
+run_test "Field filter on an unused field finds nothing" "$CLI $QF_ARGS query same@example.com --field phone || true" "No documents found"
+rm -f "$QF_DB" "$QF_DB.wal"
+
+# Test 69: Field size limit
+MF_DB="/tmp/magentadb_max_field.json"
+rm -f "$MF_DB" "$MF_DB.wal"
+run_test "Value at the limit is accepted" "$CLI --database $MF_DB --max-field-bytes 16 insert mf1 note 0123456789abcdef" "Inserted document 'mf1'"
+run_test "Value over the limit is rejected" "$CLI --database $MF_DB insert mf1 note 0123456789abcdefX 2>&1 || true" "Value is 17 bytes, over the limit of 16 bytes per field"
+run_test "Limit is persisted" "$CLI --database $MF_DB stats" "Max field size: 16 B"
+run_test "Limit applies to updates after reload" "$CLI --database $MF_DB update mf1 note 0123456789abcdefX 2>&1 || true" "over the limit of 16 bytes"
+run_test "Oversized --set leaves the document unchanged" "$CLI --database $MF_DB insert mf1 --set a=1 --set b=0123456789abcdefX >/dev/null 2>&1; $CLI --database $MF_DB show mf1 | grep -c ' a:' || true" "^0$"
+run_test "Raising the limit admits larger values" "$CLI --database $MF_DB --max-field-bytes 32 insert mf1 note 0123456789abcdefX" "Inserted document 'mf1'"
+rm -f "$MF_DB" "$MF_DB.wal"
+
+# Test 70: Remove exit codes
+RM_DB="/tmp/magentadb_remove_exit.json"
+rm -f "$RM_DB" "$RM_DB.wal"
+$CLI --database "$RM_DB" insert rm1 name Alice >/dev/null 2>&1
+run_test "Removing a present id exits zero" "$CLI --database $RM_DB remove rm1 >/dev/null 2>&1; echo exit=\$?" "exit=0"
+run_test "Removing an absent id exits non-zero" "$CLI --database $RM_DB remove rm1 >/dev/null 2>&1; echo exit=\$?" "exit=1"
+run_test "Removing an absent id still reports it" "$CLI --database $RM_DB remove rm1 2>&1 || true" "Document 'rm1' not found"
+run_test "Dry-run of an absent id exits non-zero" "$CLI --database $RM_DB --dry-run remove rm1 >/dev/null 2>&1; echo exit=\$?" "exit=1"
+rm -f "$RM_DB" "$RM_DB.wal"
+
+# Test 71: Multi-value fields
+MV_DB="/tmp/magentadb_multi_value.json"
+MV_ARGS="--database $MV_DB"
+rm -f "$MV_DB" "$MV_DB.wal"
+$CLI $MV_ARGS insert post1 tag rust >/dev/null 2>&1
+$CLI $MV_ARGS insert post2 tag go >/dev/null 2>&1
+run_test "Append a second tag value" "$CLI $MV_ARGS append post1 tag crypto" "2 values"
+run_test "First tag value is still queryable" "$CLI $MV_ARGS query rust" "Found 1 document"
+run_test "Appended tag value is queryable" "$CLI $MV_ARGS query crypto" "post1"
+run_test "Appended value matches an exact query" "$CLI $MV_ARGS query crypto --exact" "Found 1 document"
+run_test "Appended value matches a field query" "$CLI $MV_ARGS query crypto --field tag" "Found 1 document"
+run_test "Other documents do not match the appended value" "$CLI $MV_ARGS query go" "Found 1 document"
+run_test "Show counts the extra values" "$CLI $MV_ARGS show post1" "+1 more values"
+run_test "Appended values verify" "$CLI $MV_ARGS verify" "2 document"
+run_test "Append to a missing field fails" "$CLI $MV_ARGS append post1 missing x 2>&1 || true" "Field 'missing' not found"
+rm -f "$MV_DB" "$MV_DB.wal"
+
+# Test 72: Indexes built once after loading match eager indexing
+DI_DB="/tmp/magentadb_deferred_index.json"
+DI_ARGS="--database $DI_DB"
+rm -f "$DI_DB" "$DI_DB.wal"
+$CLI $DI_ARGS insert di1 name Alice >/dev/null 2>&1
+$CLI $DI_ARGS insert di1 city Paris >/dev/null 2>&1
+$CLI $DI_ARGS insert di2 name Bob >/dev/null 2>&1
+$CLI $DI_ARGS insert di2 city Paris >/dev/null 2>&1
+run_test "Loaded token index matches a rebuild" "$CLI $DI_ARGS reindex" "Token index size: 3 → 3"
+run_test "Loaded field index matches a rebuild" "$CLI $DI_ARGS reindex" "Field index size: 2 → 2"
+run_test "Loaded indexes answer queries" "$CLI $DI_ARGS query Paris" "Found 2 document"
+$CLI $DI_ARGS backup /tmp/magentadb_deferred_index.bak >/dev/null 2>&1
+$CLI $DI_ARGS remove di2 >/dev/null 2>&1
+$CLI $DI_ARGS restore /tmp/magentadb_deferred_index.bak >/dev/null 2>&1
+run_test "Restored documents are indexed" "$CLI $DI_ARGS count --token Paris" "2 document(s) matching"
+rm -f "$DI_DB" "$DI_DB.wal" /tmp/magentadb_deferred_index.bak
+
+# Test 73: Wrong-length secret key on load
+SK_DB="/tmp/magentadb_short_key.json"
+rm -f "$SK_DB" "$SK_DB.wal"
+$CLI --database "$SK_DB" insert sk1 name Alice >/dev/null 2>&1
+python3 -c "import json,sys; p=sys.argv[1]; d=json.load(open(p)); d['secret_key']=d['secret_key'][:16]; json.dump(d, open(p, 'w'))" "$SK_DB"
+run_test "Short secret key is reported with its length" "$CLI --database $SK_DB list 2>&1 || true" "secret_key must be 32 bytes, found 16"
+run_test "Database with a short key is left untouched" "python3 -c \"import json; print(len(json.load(open('$SK_DB'))['secret_key']))\"" "^16$"
+rm -f "$SK_DB" "$SK_DB.wal"
+
+# Test 74: Non-searchable fields
+NS_DB="/tmp/magentadb_no_search.json"
+NS_ARGS="--database $NS_DB"
+rm -f "$NS_DB" "$NS_DB.wal"
+$CLI $NS_ARGS insert ns1 note 'call back Tuesday' --no-search >/dev/null 2>&1
+$CLI $NS_ARGS insert ns1 name Alice >/dev/null 2>&1
+run_test "Non-searchable field is decryptable" "$CLI $NS_ARGS decrypt ns1 note" "call back Tuesday"
+run_test "Non-searchable field is never queried" "$CLI $NS_ARGS query 'call back Tuesday' || true" "No documents found"
+run_test "Non-searchable field is not found by an exact query" "$CLI $NS_ARGS query 'call back Tuesday' --exact || true" "No documents found"
+run_test "Searchable fields of the same document still match" "$CLI $NS_ARGS query Alice" "Found 1 document"
+run_test "Non-searchable field takes no index space" "$CLI $NS_ARGS stats" "Token index size: 1"
+run_test "Non-searchable field shows no token" "$CLI $NS_ARGS --verbose show ns1" "Not searchable"
+run_test "No-search conflicts with prefix indexing" "$CLI $NS_ARGS insert ns2 note x --no-search --prefix 2>&1 || true" "cannot be used with"
+rm -f "$NS_DB" "$NS_DB.wal"
+
+# Test 75: Query limits
+QL_DB="/tmp/magentadb_query_limit.json"
+QL_ARGS="--database $QL_DB"
+rm -f "$QL_DB" "$QL_DB.wal"
+for i in 1 2 3 4 5; do
+    $CLI $QL_ARGS insert "ql$i" team blue >/dev/null 2>&1
+done
+run_test "Limited query returns at most the limit" "$CLI $QL_ARGS query blue --limit 3" "Found 3 document"
+run_test "Limited JSON query never exceeds the limit" "$CLI $QL_ARGS --output json query blue --limit 2 | python3 -c 'import json,sys; print(len(json.load(sys.stdin)))'" "^2$"
+run_test "Limit above the match count returns every match" "$CLI $QL_ARGS query blue --limit 50" "Found 5 document"
+run_test "Limit applies to exact queries" "$CLI $QL_ARGS query blue --exact --limit 4" "Found 4 document"
+run_test "Limit of zero returns nothing" "$CLI $QL_ARGS query blue --limit 0 || true" "No documents found"
+rm -f "$QL_DB" "$QL_DB.wal"
+
+# Test 76: Database ids
+ID_DB="/tmp/magentadb_db_id.json"
+ID_ARGS="--database $ID_DB"
+DB_ID="python3 -c 'import json,sys; print(json.load(sys.stdin)[\"db_id\"])'"
+rm -f "$ID_DB" "$ID_DB.wal"
+$CLI $ID_ARGS insert id1 name Alice >/dev/null 2>&1
+FIRST_ID=$($CLI $ID_ARGS --output json stats | python3 -c 'import json,sys; print(json.load(sys.stdin)["db_id"])')
+run_test "Database id is a version 4 UUID" "echo $FIRST_ID" "^[0-9a-f]\{8\}-[0-9a-f]\{4\}-4[0-9a-f]\{3\}-[89ab][0-9a-f]\{3\}-[0-9a-f]\{12\}$"
+run_test "Stats shows the database id" "$CLI $ID_ARGS stats" "Database ID: $FIRST_ID"
+$CLI $ID_ARGS --compact-after 1 insert id2 name Bob >/dev/null 2>&1
+$CLI $ID_ARGS insert id3 name Carol >/dev/null 2>&1
+run_test "Database id survives save and load" "$CLI $ID_ARGS --output json stats | $DB_ID" "^$FIRST_ID$"
+python3 -c "import json,sys; p=sys.argv[1]; d=json.load(open(p)); del d['db_id']; json.dump(d, open(p, 'w'))" "$ID_DB"
+LEGACY_ID=$($CLI $ID_ARGS --output json stats | python3 -c 'import json,sys; print(json.load(sys.stdin)["db_id"])')
+run_test "Database without an id is given one" "[ -n '$LEGACY_ID' ] && [ '$LEGACY_ID' != '$FIRST_ID' ] && echo new id" "new id"
+run_test "Generated id is written to the file" "python3 -c \"import json; print(json.load(open('$ID_DB'))['db_id'])\"" "^$LEGACY_ID$"
+run_test "Generated id is stable on later loads" "$CLI $ID_ARGS --output json stats | $DB_ID" "^$LEGACY_ID$"
+rm -f "$ID_DB" "$ID_DB.wal"
+
+# Test 77: Key fingerprint
+KF_DB="/tmp/magentadb_key_fingerprint.json"
+KF_OTHER="/tmp/magentadb_key_fingerprint_other.json"
+rm -f "$KF_DB" "$KF_DB.wal" "$KF_OTHER" "$KF_OTHER.wal"
+$CLI --database "$KF_DB" insert kf1 name Alice >/dev/null 2>&1
+$CLI --database "$KF_OTHER" insert kf1 name Bob >/dev/null 2>&1
+run_test "Database records a key fingerprint" "python3 -c \"import json; print(len(json.load(open('$KF_DB'))['key_fingerprint']))\"" "^64$"
+python3 -c "import json,sys; d=json.load(open(sys.argv[1])); o=json.load(open(sys.argv[2])); d['secret_key']=o['secret_key']; json.dump(d, open(sys.argv[1], 'w'))" "$KF_DB" "$KF_OTHER"
+KF_SUM=$(md5sum < "$KF_DB")
+run_test "Swapped key is rejected on load" "$CLI --database $KF_DB decrypt kf1 name 2>&1 || true" "Key mismatch"
+run_test "Swapped key is rejected before writing" "$CLI --database $KF_DB insert kf2 name Carol >/dev/null 2>&1; echo exit=\$?" "exit=1"
+run_test "Database with a swapped key is left untouched" "[ \"\$(md5sum < $KF_DB)\" = '$KF_SUM' ] && [ ! -s $KF_DB.wal ] && echo unchanged" "unchanged"
+python3 -c "import json,sys; p=sys.argv[1]; d=json.load(open(p)); del d['key_fingerprint']; json.dump(d, open(p, 'w'))" "$KF_OTHER"
+run_test "Database without a fingerprint still opens" "$CLI --database $KF_OTHER decrypt kf1 name" "Bob"
+run_test "Missing fingerprint is written on load" "python3 -c \"import json; print(len(json.load(open('$KF_OTHER'))['key_fingerprint']))\"" "^64$"
+rm -f "$KF_DB" "$KF_DB.wal" "$KF_OTHER" "$KF_OTHER.wal"
+
+# Test 78: Touch
+TC_DB="/tmp/magentadb_touch.json"
+TC_ARGS="--database $TC_DB"
+LAST_MODIFIED="python3 -c 'import json,sys; print(json.load(open(sys.argv[1]))[\"last_modified\"])' $TC_DB"
+rm -f "$TC_DB" "$TC_DB.wal"
+$CLI $TC_ARGS --compact-after 1 insert tc1 name Alice >/dev/null 2>&1
+$CLI $TC_ARGS insert tc2 name Bob >/dev/null 2>&1
+BEFORE_TOUCH=$(eval "$LAST_MODIFIED")
+sleep 1
+run_test "Touch rewrites every document" "$CLI $TC_ARGS touch" "Rewrote $TC_DB (2 document(s))"
+run_test "Touch updates last_modified" "[ \"\$($LAST_MODIFIED)\" != '$BEFORE_TOUCH' ] && echo updated" "updated"
+run_test "Touch folds the log into the file" "[ ! -s $TC_DB.wal ] && python3 -c \"import json; print(sorted(json.load(open('$TC_DB'))['documents']))\"" "\['tc1', 'tc2'\]"
+run_test "Touched documents still decrypt" "$CLI $TC_ARGS decrypt tc2 name" "Bob"
+run_test "Touched documents are still queryable" "$CLI $TC_ARGS query Alice" "Found 1 document"
+$CLI $TC_ARGS --pad 64 touch >/dev/null 2>&1
+run_test "Touch records database options" "$CLI $TC_ARGS stats" "Padding block: 64 B"
+rm -f "$TC_DB" "$TC_DB.wal"
+
+# Test 79: Key from the environment
+EK_DB="/tmp/magentadb_env_key.json"
+EK_ARGS="--database $EK_DB"
+EK_KEY=$(python3 -c 'import os; print(os.urandom(32).hex())')
+EK_OTHER_KEY=$(python3 -c 'import os; print(os.urandom(32).hex())')
+rm -f "$EK_DB" "$EK_DB.wal"
+MAGENTADB_KEY=$EK_KEY $CLI $EK_ARGS insert ek1 name Alice >/dev/null 2>&1
+run_test "Environment key is not written to the file" "python3 -c \"import json; print('secret_key' in json.load(open('$EK_DB')))\"" "^False$"
+run_test "Environment key decrypts data" "MAGENTADB_KEY=$EK_KEY $CLI $EK_ARGS decrypt ek1 name" "Alice"
+run_test "Environment key answers queries" "MAGENTADB_KEY=$EK_KEY $CLI $EK_ARGS query Alice" "Found 1 document"
+run_test "Stats reports where the key came from" "MAGENTADB_KEY=$EK_KEY $CLI $EK_ARGS stats" "Key: from MAGENTADB_KEY"
+run_test "Database without a stored key needs the environment key" "$CLI $EK_ARGS list 2>&1 || true" "does not store its key; set MAGENTADB_KEY"
+run_test "A different environment key is rejected" "MAGENTADB_KEY=$EK_OTHER_KEY $CLI $EK_ARGS list 2>&1 || true" "Key mismatch"
+run_test "Short environment key is rejected" "MAGENTADB_KEY=\${EK_KEY:0:62} $CLI $EK_ARGS list 2>&1 || true" "expected 32 bytes (64 hex digits), found 31 bytes"
+run_test "Malformed environment key is rejected" "MAGENTADB_KEY=not-hex $CLI $EK_ARGS list 2>&1 || true" "MAGENTADB_KEY is not a usable key"
+rm -f "$EK_DB" "$EK_DB.wal"
+
+# Test 80: Index compaction
+IC_DB="/tmp/magentadb_compact.json"
+IC_ARGS="--database $IC_DB"
+rm -f "$IC_DB" "$IC_DB.wal"
+$CLI $IC_ARGS insert ic1 name Alice >/dev/null 2>&1
+$CLI $IC_ARGS insert ic2 name Bob >/dev/null 2>&1
+$CLI $IC_ARGS insert-num ic2 age 42 >/dev/null 2>&1
+run_test "Compact finds nothing dangling in a loaded database" "$CLI $IC_ARGS compact" "Removed 0 dangling index entries"
+run_test "Compact reports each index" "$CLI $IC_ARGS compact" "Range index: 0"
+run_test "Compact after removals in a session leaves nothing dangling" "printf 'remove ic2\\ncompact\\nexit\\n' | $CLI $IC_ARGS repl" "Removed 0 dangling index entries"
+run_test "Valid entries survive compaction" "printf 'compact\\nquery Alice\\nexit\\n' | $CLI $IC_ARGS repl" "Found 1 document"
+run_test "Index sizes are unchanged by compaction" "printf 'compact\\nstats\\nexit\\n' | $CLI $IC_ARGS repl" "Token index size: 1"
+rm -f "$IC_DB" "$IC_DB.wal"
+
+# Test 81: Token command
+TK_DB="/tmp/magentadb_token.json"
+TK_ARGS="--database $TK_DB"
+rm -f "$TK_DB" "$TK_DB.wal"
+$CLI $TK_ARGS insert tk1 city Paris >/dev/null 2>&1
+STORED_TOKEN=$($CLI $TK_ARGS --output json show tk1 | python3 -c 'import json,sys; print(json.load(sys.stdin)["fields"]["city"]["token"])')
+run_test "Token matches the stored field token" "$CLI $TK_ARGS token Paris" "Token for 'Paris': $STORED_TOKEN$"
+run_test "JSON token matches the stored field token" "$CLI $TK_ARGS --output json token Paris | python3 -c 'import json,sys; print(json.load(sys.stdin))'" "^$STORED_TOKEN$"
+run_test "Token of another value differs" "$CLI $TK_ARGS token London | grep -v $STORED_TOKEN" "Token for 'London'"
+run_test "Token command stores nothing" "$CLI $TK_ARGS token London >/dev/null && $CLI $TK_ARGS stats" "Documents: 1"
+rm -f "$TK_DB" "$TK_DB.wal"
+
+# Test 82: Clear confirmed by typing the database name
+CN_DB="/tmp/magentadb_confirm_name.json"
+CN_ARGS="--database $CN_DB"
+rm -f "$CN_DB" "$CN_DB.wal"
+$CLI $CN_ARGS insert cn1 name Alice >/dev/null 2>&1
+$CLI $CN_ARGS insert cn2 name Bob >/dev/null 2>&1
+CN_ID=$($CLI $CN_ARGS --output json stats | python3 -c 'import json,sys; print(json.load(sys.stdin)["db_id"])')
+run_test "Wrong name aborts clear" "echo magentadb.json | $CLI $CN_ARGS clear --confirm-name 2>&1 || true" "Confirmation did not match"
+run_test "Wrong name exits non-zero" "echo y | $CLI $CN_ARGS clear --confirm-name >/dev/null 2>&1; echo exit=\$?" "exit=1"
+run_test "Aborted clear keeps every document" "$CLI $CN_ARGS list" "Database contains 2 document"
+run_test "Typing the database name clears" "echo $CN_DB | $CLI $CN_ARGS clear --confirm-name" "2 documents removed"
+$CLI $CN_ARGS insert cn3 name Carol >/dev/null 2>&1
+run_test "Typing the database id clears" "echo $CN_ID | $CLI $CN_ARGS clear --confirm-name" "1 documents removed"
+run_test "Typed confirmation cannot be combined with force" "$CLI $CN_ARGS clear --confirm-name --force 2>&1 || true" "cannot be used with"
+rm -f "$CN_DB" "$CN_DB.wal"
+
+# Test 83: Field distribution in stats
+FD_DB="/tmp/magentadb_field_distribution.json"
+FD_ARGS="--database $FD_DB"
+rm -f "$FD_DB" "$FD_DB.wal"
+$CLI $FD_ARGS insert fd1 name Alice --set email=a@example.com --set city=Paris >/dev/null 2>&1
+$CLI $FD_ARGS insert fd2 name Bob --set email=b@example.com >/dev/null 2>&1
+$CLI $FD_ARGS insert fd3 name Carol >/dev/null 2>&1
+run_test "Field distribution counts documents per field" "$CLI $FD_ARGS --output json stats | python3 -c 'import json,sys; print(json.load(sys.stdin)[\"field_distribution\"])'" "{'city': 1, 'email': 2, 'name': 3}"
+run_test "Stats lists the most common field first" "$CLI $FD_ARGS stats | grep -A1 'Documents per field'" "name: 3"
+run_test "Stats lists every field" "$CLI $FD_ARGS stats" "city: 1"
+$CLI $FD_ARGS remove fd1 >/dev/null 2>&1
+run_test "Removed documents leave the distribution" "$CLI $FD_ARGS --output json stats | python3 -c 'import json,sys; print(json.load(sys.stdin)[\"field_distribution\"])'" "{'email': 1, 'name': 2}"
+rm -f "$FD_DB" "$FD_DB.wal"
+
+# Test 84: Base64 ciphertext in the database file
+B64_DB="/tmp/magentadb_base64.json"
+B64_ARGS="--database $B64_DB"
+TO_ARRAYS="import base64,json,sys
+p=sys.argv[1]; d=json.load(open(p))
+for doc in d['documents'].values():
+    for f in doc['fields'].values():
+        for v in [f] + f.get('extra_values', []):
+            v['cipher'] = list(base64.b64decode(v['cipher'])); v['nonce'] = list(base64.b64decode(v['nonce']))
+json.dump(d, open(p, 'w'), indent=2)"
+rm -f "$B64_DB" "$B64_DB.wal"
+$CLI $B64_ARGS --compact-after 1 insert b1 name 'Base Sixty Four' >/dev/null 2>&1
+$CLI $B64_ARGS --compact-after 1 insert b2 tag red >/dev/null 2>&1
+$CLI $B64_ARGS --compact-after 1 append b2 tag blue >/dev/null 2>&1
+run_test "Ciphertext is stored as base64" "python3 -c \"import json; f=json.load(open('$B64_DB'))['documents']['b1']['fields']['name']; print(type(f['cipher']).__name__, type(f['nonce']).__name__)\"" "^str str$"
+B64_SIZE=$(wc -c < "$B64_DB")
+python3 -c "$TO_ARRAYS" "$B64_DB"
+ARRAY_SIZE=$(wc -c < "$B64_DB")
+run_test "Base64 form is smaller than the array form" "[ $B64_SIZE -lt $ARRAY_SIZE ] && echo smaller" "smaller"
+resign_db "$B64_DB"
+run_test "Array form still loads" "$CLI $B64_ARGS decrypt b1 name" "Base Sixty Four"
+run_test "Array form extra values still load" "$CLI $B64_ARGS query blue" "Found 1 document"
+$CLI $B64_ARGS touch >/dev/null 2>&1
+run_test "Array form is rewritten as base64" "python3 -c \"import json; f=json.load(open('$B64_DB'))['documents']['b2']['fields']['tag']; print(type(f['cipher']).__name__, type(f['extra_values'][0]['cipher']).__name__)\"" "^str str$"
+run_test "Round-tripped fields still decrypt" "$CLI $B64_ARGS verify" "Verified 2 field(s) in 2 document(s)"
+rm -f "$B64_DB" "$B64_DB.wal"
+
+# Test 85: Watching for changes
+WA_DB="/tmp/magentadb_watch.json"
+WA_ARGS="--database $WA_DB"
+WA_OUT="/tmp/magentadb_watch.out"
+rm -f "$WA_DB" "$WA_DB.wal" "$WA_OUT"
+$CLI $WA_ARGS insert wa1 name Alice >/dev/null 2>&1
+timeout 4 $CLI $WA_ARGS watch --interval 100 > "$WA_OUT" 2>&1 &
+WA_PID=$!
+sleep 0.5
+$CLI $WA_ARGS insert wa2 name Bob >/dev/null 2>&1
+sleep 0.5
+$CLI $WA_ARGS insert wa1 city Paris >/dev/null 2>&1
+sleep 0.5
+$CLI $WA_ARGS remove wa2 >/dev/null 2>&1
+sleep 0.5
+wait $WA_PID 2>/dev/null
+run_test "Watch reports inserts" "cat $WA_OUT" "Inserted wa2"
+run_test "Watch reports updates" "cat $WA_OUT" "Updated wa1"
+run_test "Watch reports removals" "cat $WA_OUT" "Removed wa2"
+run_test "Watch reports changes in order" "grep -oE '(Inserted|Updated|Removed) wa[12]' $WA_OUT | tr '\\n' ' '" "^Inserted wa2 Updated wa1 Removed wa2 $"
+run_test "Watch does not report documents present at start" "grep -c 'Inserted wa1' $WA_OUT || true" "^0$"
+timeout 2 $CLI $WA_ARGS watch --interval 100 >/dev/null 2>&1 &
+WA_PID=$!
+sleep 0.5
+# A file without an id is backfilled and saved by a normal load
+python3 -c "import json; p='$WA_DB'; d=json.load(open(p)); d.pop('db_id'); json.dump(d, open(p, 'w'))"
+WA_SUM=$(sha256sum < "$WA_DB")
+sleep 0.5
+wait $WA_PID 2>/dev/null
+run_test "Watch never rewrites the database file" "[ \"\$(sha256sum < $WA_DB)\" = \"$WA_SUM\" ] && echo unchanged" "^unchanged$"
+run_test "Watch opens the database without rewriting it" "timeout 1 $CLI $WA_ARGS watch --interval 100 >/dev/null 2>&1; [ \"\$(sha256sum < $WA_DB)\" = \"$WA_SUM\" ] && echo unchanged" "^unchanged$"
+rm -f "$WA_DB" "$WA_DB.wal"
+run_test "Watch needs an existing database" "$CLI $WA_ARGS watch --interval 100 2>&1 || true" "Database file $WA_DB not found"
+run_test "Watch creates no database" "ls $WA_DB 2>&1 || true" "No such file"
+rm -f "$WA_DB" "$WA_DB.wal" "$WA_OUT"
+
+# Test 86: Renaming fields
+RF_DB="/tmp/magentadb_rename_field.json"
+RF_ARGS="--database $RF_DB"
+rm -f "$RF_DB" "$RF_DB.wal"
+$CLI $RF_ARGS insert rf1 email alice@example.com >/dev/null 2>&1
+$CLI $RF_ARGS insert rf1 city Paris >/dev/null 2>&1
+$CLI $RF_ARGS insert rf2 email bob@example.com >/dev/null 2>&1
+run_test "Rename field" "$CLI $RF_ARGS rename-field rf1 email contact" "Renamed field 'email' to 'contact'"
+run_test "Renamed field found under new name" "$CLI $RF_ARGS query-field contact" "rf1"
+run_test "Renamed field gone from old name" "$CLI $RF_ARGS query-field email | grep -c rf1 || true" "^0$"
+run_test "Other documents keep old name" "$CLI $RF_ARGS query-field email" "rf2"
+run_test "Renamed field keeps its value" "$CLI $RF_ARGS decrypt rf1 contact" "alice@example.com"
+run_test "Renamed field keeps its token" "$CLI $RF_ARGS query alice@example.com" "rf1"
+run_test "Rename missing field fails" "$CLI $RF_ARGS rename-field rf1 email other 2>&1 || true" "Field not found"
+run_test "Rename onto existing field fails" "$CLI $RF_ARGS rename-field rf1 contact city 2>&1 || true" "Field already exists"
+run_test "Failed rename leaves field in place" "$CLI $RF_ARGS decrypt rf1 city" "Paris"
+rm -f "$RF_DB" "$RF_DB.wal"
+
+# Test 87: Integrity tag over the documents
+IT_DB="/tmp/magentadb_integrity.json"
+IT_ARGS="--database $IT_DB"
+rm -f "$IT_DB" "$IT_DB.wal"
+$CLI $IT_ARGS insert it1 name Alice >/dev/null 2>&1
+$CLI $IT_ARGS insert it2 name Bob >/dev/null 2>&1
+$CLI $IT_ARGS insert it3 name Carol >/dev/null 2>&1
+$CLI $IT_ARGS touch >/dev/null 2>&1
+run_test "Database records an integrity tag" "python3 -c \"import json; print(len(json.load(open('$IT_DB'))['integrity_tag']))\"" "^64$"
+run_test "Saved database passes the integrity check" "$CLI $IT_ARGS query Bob" "it2"
+run_test "Reloaded database passes the integrity check" "$CLI $IT_ARGS decrypt it1 name" "Alice"
+cp "$IT_DB" "$IT_DB.orig"
+python3 -c "import json,sys; p=sys.argv[1]; d=json.load(open(p)); del d['documents']['it2']; json.dump(d, open(p, 'w'))" "$IT_DB"
+run_test "Deleted document is detected" "$CLI $IT_ARGS list 2>&1 || true" "integrity check failed"
+run_test "Tampered database is left untouched" "python3 -c \"import json; print(sorted(json.load(open('$IT_DB'))['documents']))\"" "\['it1', 'it3'\]"
+cp "$IT_DB.orig" "$IT_DB"
+python3 -c "import json,sys; p=sys.argv[1]; d=json.load(open(p)); docs=d['documents']; docs['it1']['fields'], docs['it3']['fields'] = docs['it3']['fields'], docs['it1']['fields']; json.dump(d, open(p, 'w'))" "$IT_DB"
+run_test "Swapped fields are detected" "$CLI $IT_ARGS list 2>&1 || true" "integrity check failed"
+cp "$IT_DB.orig" "$IT_DB"
+python3 -c "import json,sys; p=sys.argv[1]; d=json.load(open(p)); del d['integrity_tag']; json.dump(d, open(p, 'w'))" "$IT_DB"
+run_test "Database without a tag still opens" "$CLI $IT_ARGS list" "it2"
+run_test "Missing tag is written on load" "python3 -c \"import json; print(len(json.load(open('$IT_DB'))['integrity_tag']))\"" "^64$"
+rm -f "$IT_DB" "$IT_DB.wal" "$IT_DB.orig"
+
+# Test 88: Namespaces
+NS_DB="/tmp/magentadb_namespaces.json"
+NS_ARGS="--database $NS_DB"
+rm -f "$NS_DB" "$NS_DB.wal"
+$CLI $NS_ARGS insert ns1 team red >/dev/null 2>&1
+$CLI $NS_ARGS --ns hr insert ns2 team red >/dev/null 2>&1
+$CLI $NS_ARGS --ns hr insert ns3 team blue >/dev/null 2>&1
+$CLI $NS_ARGS insert ns4 team blue >/dev/null 2>&1
+run_test "Default namespace only finds its documents" "$CLI $NS_ARGS query red" "ns1"
+run_test "Default namespace hides other namespaces" "$CLI $NS_ARGS query red | grep -c ns2 || true" "^0$"
+run_test "Named namespace finds its documents" "$CLI $NS_ARGS --ns hr query red" "ns2"
+run_test "Named namespace hides the default one" "$CLI $NS_ARGS --ns hr query red | grep -c ns1 || true" "^0$"
+run_test "Named namespace lists only its documents" "$CLI $NS_ARGS --ns hr --output json list | python3 -c 'import json,sys; print(sorted(d[\"id\"] for d in json.load(sys.stdin)))'" "\['ns2', 'ns3'\]"
+run_test "Same id can exist in two namespaces" "$CLI $NS_ARGS --ns hr insert ns1 team green >/dev/null && $CLI $NS_ARGS decrypt ns1 team" "red"
+run_test "Documents with a reused id stay separate" "$CLI $NS_ARGS --ns hr decrypt ns1 team" "green"
+run_test "Stats name the namespaces" "$CLI $NS_ARGS --ns hr stats" "Namespaces: default, hr"
+run_test "Unused namespace is empty" "$CLI $NS_ARGS --ns other list" "No documents"
+$CLI $NS_ARGS --ns hr clear --force >/dev/null 2>&1
+run_test "Clearing a namespace leaves the others" "$CLI $NS_ARGS query blue" "ns4"
+run_test "Cleared namespace is dropped from the file" "python3 -c \"import json; print('namespaces' in json.load(open('$NS_DB')))\"" "^False$"
+$CLI $NS_ARGS --ns hr insert ns5 team red >/dev/null 2>&1
+python3 -c "import json,sys; p=sys.argv[1]; d=json.load(open(p)); d['namespaces']['hr']['ns6'] = d['namespaces']['hr']['ns5']; json.dump(d, open(p, 'w'))" "$NS_DB"
+run_test "Tampering with a namespace is detected" "$CLI $NS_ARGS list 2>&1 || true" "integrity check failed"
+run_test "Empty namespace name is rejected" "$CLI $NS_ARGS --ns '' list 2>&1 || true" "cannot be empty"
+rm -f "$NS_DB" "$NS_DB.wal"
+
+# Test 89: Query exit status
+QX_DB="/tmp/magentadb_query_exit.json"
+QX_ARGS="--database $QX_DB"
+rm -f "$QX_DB" "$QX_DB.wal"
+$CLI $QX_ARGS insert qx1 city Paris >/dev/null 2>&1
+$CLI $QX_ARGS set-meta qx1 owner alice >/dev/null 2>&1
+run_test "Query with a match exits zero" "$CLI $QX_ARGS query Paris >/dev/null; echo \"exit=\$?\"" "^exit=0$"
+run_test "Query without a match exits one" "$CLI $QX_ARGS query Berlin >/dev/null; echo \"exit=\$?\"" "^exit=1$"
+run_test "Query miss output is unchanged" "$CLI $QX_ARGS query Berlin || true" "No documents found"
+run_test "Query miss prints no error" "$CLI $QX_ARGS query Berlin 2>&1 >/dev/null | grep -c Error || true" "^0$"
+run_test "JSON query miss exits one" "$CLI $QX_ARGS --output json query Berlin; echo \"exit=\$?\"" "exit=1"
+run_test "Metadata query miss exits one" "$CLI $QX_ARGS query --meta owner=bob >/dev/null; echo \"exit=\$?\"" "^exit=1$"
+run_test "Metadata query hit exits zero" "$CLI $QX_ARGS query --meta owner=alice >/dev/null; echo \"exit=\$?\"" "^exit=0$"
+run_test "Query works as a shell condition" "if $CLI $QX_ARGS query Paris >/dev/null; then echo found; fi; if ! $CLI $QX_ARGS query Berlin >/dev/null; then echo missing; fi" "missing"
+rm -f "$QX_DB" "$QX_DB.wal"
+
+# Test 90: In-memory databases
+MEM_DIR=$(mktemp -d)
+MEM_CLI="$PWD/$CLI"
+MEM_DB="/tmp/magentadb_memory.json"
+rm -f "$MEM_DB" "$MEM_DB.wal"
+run_test "No-persist insert succeeds" "(cd $MEM_DIR && $MEM_CLI --no-persist insert m1 name Alice)" "Inserted document 'm1'"
+run_test "No-persist creates no file" "ls -A $MEM_DIR | wc -l" "^0$"
+run_test "Memory path works within a session" "printf 'insert m1 name Alice\\nquery Alice\\ndecrypt m1 name\\nexit\\n' | (cd $MEM_DIR && $MEM_CLI --database :memory: repl)" "Decrypted m1.name: Alice"
+run_test "Memory session is never saved" "printf 'insert m1 name Alice\\nexit\\n' | (cd $MEM_DIR && $MEM_CLI --database :memory: repl) | grep -c Saved || true" "^0$"
+run_test "Memory path creates no file" "ls -A $MEM_DIR | wc -l" "^0$"
+run_test "Memory database starts empty every run" "(cd $MEM_DIR && $MEM_CLI --database :memory: list)" "No documents"
+$CLI --database $MEM_DB insert m2 name Bob >/dev/null 2>&1
+run_test "No-persist ignores an existing file" "$CLI --database $MEM_DB --no-persist list" "No documents"
+run_test "No-persist leaves an existing file alone" "$CLI --database $MEM_DB --no-persist clear --force >/dev/null && $CLI --database $MEM_DB decrypt m2 name" "Bob"
+run_test "Touch is refused in memory" "$CLI --no-persist touch 2>&1 || true" "no file to rewrite"
+rm -rf "$MEM_DIR"
+rm -f "$MEM_DB" "$MEM_DB.wal"
+
+# Test 91: Document id and field name validation
+IV_DB="/tmp/magentadb_validation.json"
+IV_ARGS="--database $IV_DB"
+rm -f "$IV_DB" "$IV_DB.wal"
+run_test "Empty id is rejected" "$CLI $IV_ARGS insert '' name Alice 2>&1 || true" "document id cannot be empty"
+run_test "Whitespace id is rejected" "$CLI $IV_ARGS insert '   ' name Alice 2>&1 || true" "document id cannot be empty"
+run_test "Empty field name is rejected" "$CLI $IV_ARGS insert iv1 '' Alice 2>&1 || true" "field name cannot be empty"
+run_test "Whitespace field name is rejected" "$CLI $IV_ARGS insert iv1 ' ' Alice 2>&1 || true" "field name cannot be empty"
+run_test "Overlong id is rejected" "$CLI $IV_ARGS insert $(printf 'x%.0s' $(seq 1025)) name Alice 2>&1 || true" "at most 1024 are allowed"
+run_test "Rejected inserts store nothing" "$CLI $IV_ARGS list" "No documents"
+run_test "Valid id and field are accepted" "$CLI $IV_ARGS insert iv1 name Alice" "Inserted document 'iv1'"
+run_test "Rename to an empty id is rejected" "$CLI $IV_ARGS rename iv1 ' ' 2>&1 || true" "document id cannot be empty"
+run_test "Rename field to an empty name is rejected" "$CLI $IV_ARGS rename-field iv1 name '' 2>&1 || true" "field name cannot be empty"
+python3 -c "import json,sys; p=sys.argv[1]; d=json.load(open(p)); doc=dict(d['documents']['iv1'], id=''); d['documents']['']=doc; json.dump(d, open(p, 'w'))" "$IV_DB"
+resign_db "$IV_DB"
+run_test "Existing documents with empty ids still load" "$CLI $IV_ARGS query Alice" "Found 2 document"
+rm -f "$IV_DB" "$IV_DB.wal"
+
+# Test 92: Copying documents
+CP_DB="/tmp/magentadb_copy.json"
+CP_ARGS="--database $CP_DB"
+rm -f "$CP_DB" "$CP_DB.wal"
+$CLI $CP_ARGS insert tpl role engineer >/dev/null 2>&1
+$CLI $CP_ARGS insert tpl team platform >/dev/null 2>&1
+run_test "Copy document" "$CLI $CP_ARGS copy tpl cp1" "Copied document 'tpl' to 'cp1'"
+run_test "Copy is found by value" "$CLI $CP_ARGS query engineer" "cp1"
+run_test "Source is still found by value" "$CLI $CP_ARGS query engineer" "tpl"
+run_test "Copy is found by field" "$CLI $CP_ARGS query-field team" "cp1"
+run_test "Copy decrypts" "$CLI $CP_ARGS decrypt cp1 team" "platform"
+$CLI $CP_ARGS update cp1 role manager >/dev/null 2>&1
+run_test "Updating the copy leaves the source" "$CLI $CP_ARGS decrypt tpl role" "engineer"
+run_test "Source no longer matches the copy's new value" "$CLI $CP_ARGS query manager | grep -c tpl || true" "^0$"
+$CLI $CP_ARGS remove tpl >/dev/null 2>&1
+run_test "Removing the source leaves the copy" "$CLI $CP_ARGS decrypt cp1 team" "platform"
+run_test "Copy of a missing document fails" "$CLI $CP_ARGS copy tpl cp2 2>&1 || true" "not found"
+run_test "Copy onto an existing document fails" "$CLI $CP_ARGS insert cp3 role x >/dev/null; $CLI $CP_ARGS copy cp1 cp3 2>&1 || true" "Duplicate document: cp3"
+run_test "Refused copy leaves the destination" "$CLI $CP_ARGS decrypt cp3 role" ": x$"
+# A destination written before documents were versioned
+$CLI $CP_ARGS touch >/dev/null 2>&1
+python3 -c "import json,sys; p=sys.argv[1]; d=json.load(open(p)); d['documents']['cp3'].pop('version', None); json.dump(d, open(p, 'w'))" "$CP_DB"
+resign_db "$CP_DB"
+run_test "Copy onto an unversioned document fails" "$CLI $CP_ARGS copy cp1 cp3 2>&1 || true" "Duplicate document: cp3"
+run_test "Refused copy leaves the unversioned destination" "$CLI $CP_ARGS decrypt cp3 role" ": x$"
+rm -f "$CP_DB" "$CP_DB.wal"
+
+# Test 93: Config file
+CF_DIR=$(mktemp -d)
+CF_CLI="$PWD/$CLI"
+CF_RUN="cd $CF_DIR/work && XDG_CONFIG_HOME=$CF_DIR/xdg $CF_CLI"
+mkdir -p "$CF_DIR/work" "$CF_DIR/xdg/magentadb"
+run_test "Without a config file the built-in defaults apply" "($CF_RUN insert c1 name Alice) && ls $CF_DIR/work" "^magentadb.json$"
+run_test "Without a config file output is human" "($CF_RUN list)" "c1"
+printf 'database = \"from_file.json\"\noutput = \"json\"\nverbose = true\n' > "$CF_DIR/work/magentadb.toml"
+run_test "Config file sets the database" "($CF_RUN insert c2 name Bob >/dev/null) && ls $CF_DIR/work" "^from_file.json$"
+run_test "Config file sets the output format" "($CF_RUN list) | python3 -c 'import json,sys; print([d[\"id\"] for d in json.load(sys.stdin)])'" "\['c2'\]"
+run_test "Flag beats the config file database" "($CF_RUN --database flag.json insert c3 name Carol >/dev/null) && ls $CF_DIR/work" "^flag.json$"
+run_test "Flag beats the config file output" "($CF_RUN --output human list)" "c2"
+run_test "Config file enables verbose output" "($CF_RUN --output human list)" "Config: magentadb.toml"
+mv "$CF_DIR/work/magentadb.toml" "$CF_DIR/xdg/magentadb/magentadb.toml"
+run_test "Config file is found in the config directory" "($CF_RUN --output human list)" "c2"
+printf 'database = \"cwd.json\"\n' > "$CF_DIR/work/magentadb.toml"
+run_test "Working directory config beats the config directory" "($CF_RUN insert c4 name Dan >/dev/null) && ls $CF_DIR/work" "^cwd.json$"
+printf 'databse = \"typo.json\"\n' > "$CF_DIR/work/magentadb.toml"
+run_test "Unknown config setting is rejected" "($CF_RUN list 2>&1) || true" "Failed to parse config file"
+printf 'output = \"xml\"\n' > "$CF_DIR/work/magentadb.toml"
+run_test "Invalid config output format is rejected" "($CF_RUN list 2>&1) || true" "Invalid output format 'xml'"
+rm -rf "$CF_DIR"
+
+# Test 94: Ids-only queries
+QI_DB="/tmp/magentadb_query_ids.json"
+QI_ARGS="--database $QI_DB"
+rm -f "$QI_DB" "$QI_DB.wal"
+for i in 3 1 2; do $CLI $QI_ARGS insert qi$i color teal >/dev/null 2>&1; done
+$CLI $QI_ARGS insert qi4 color plum >/dev/null 2>&1
+run_test "Ids-only query prints sorted ids" "$CLI $QI_ARGS query teal --ids-only | tail -n 3 | tr '\\n' ' '" "^qi1 qi2 qi3 $"
+run_test "Ids-only query matches a full query" "[ \"\$($CLI $QI_ARGS --output json query teal --ids-only)\" = \"\$($CLI $QI_ARGS --output json query teal | python3 -c 'import json,sys; print(json.dumps(sorted(d[\"id\"] for d in json.load(sys.stdin)), indent=2))')\" ] && echo same" "same"
+run_test "Ids-only query excludes other values" "$CLI $QI_ARGS query teal --ids-only | grep -c qi4 || true" "^0$"
+run_test "Ids-only query miss exits one" "$CLI $QI_ARGS query absent --ids-only >/dev/null; echo \"exit=\$?\"" "^exit=1$"
+run_test "Ids-only query rejects --exact" "$CLI $QI_ARGS query teal --ids-only --exact 2>&1 || true" "cannot be used with"
+rm -f "$QI_DB" "$QI_DB.wal"
+
+# Test 95: Typed values
+VT_DB="/tmp/magentadb_value_types.json"
+VT_ARGS="--database $VT_DB"
+rm -f "$VT_DB" "$VT_DB.wal"
+$CLI $VT_ARGS insert-num vt1 age 42 >/dev/null 2>&1
+$CLI $VT_ARGS insert-bool vt1 active true >/dev/null 2>&1
+$CLI $VT_ARGS insert vt1 name Ada >/dev/null 2>&1
+run_test "Number decrypts with its type" "$CLI $VT_ARGS decrypt vt1 age" "vt1.age: 42 (number)$"
+run_test "Bool decrypts with its type" "$CLI $VT_ARGS decrypt vt1 active" "vt1.active: true (bool)$"
+run_test "String decrypts untagged" "$CLI $VT_ARGS decrypt vt1 name" "vt1.name: Ada$"
+run_test "JSON decrypt of a number" "$CLI $VT_ARGS --output json decrypt vt1 age" "^42$"
+run_test "JSON decrypt of a bool" "$CLI $VT_ARGS --output json decrypt vt1 active" "^true$"
+run_test "JSON decrypt of a string" "$CLI $VT_ARGS --output json decrypt vt1 name" '^"Ada"$'
+run_test "Decrypt-all shows types" "$CLI $VT_ARGS decrypt-all vt1" "active: true (bool)"
+run_test "Bool update rejects other values" "$CLI $VT_ARGS update vt1 active maybe 2>&1 || true" "not a valid bool"
+run_test "Bool update accepts false" "$CLI $VT_ARGS update vt1 active false && $CLI $VT_ARGS decrypt vt1 active" "vt1.active: false (bool)$"
+run_test "Insert-bool rejects non-bools" "$CLI $VT_ARGS insert-bool vt1 flag yes 2>&1 || true" "invalid value"
+rm -f "$VT_DB" "$VT_DB.wal"
+
+# Test 96: Migrating files from older versions
+MG_DB="/tmp/magentadb_migrate.json"
+MG_ARGS="--database $MG_DB"
+rm -f "$MG_DB" "$MG_DB.wal"
+$CLI $MG_ARGS insert old1 name 'Legacy' >/dev/null 2>&1
+$CLI $MG_ARGS insert src name 'Copied' >/dev/null 2>&1
+$CLI $MG_ARGS touch >/dev/null 2>&1
+# Rewrite the file as an older release wrote it: an earlier version and
+# documents without a version count
+python3 -c "import json; p='$MG_DB'; d=json.load(open(p)); d['version']='0.0.9'; [doc.pop('version') for doc in d['documents'].values()]; json.dump(d, open(p, 'w'))"
+resign_db "$MG_DB"
+run_test "Old file is upgraded on load" "$CLI $MG_ARGS list" "upgraded to format version 0.1.0"
+run_test "Upgraded file records the new version" "python3 -c \"import json; print(json.load(open('$MG_DB'))['version'])\"" "^0.1.0$"
+run_test "Upgraded documents start at version 1" "python3 -c \"import json; print(sorted(d['version'] for d in json.load(open('$MG_DB'))['documents'].values()))\"" "^\[1, 1\]$"
+run_test "Upgraded file is not upgraded again" "$CLI $MG_ARGS list | grep -c upgraded || true" "^0$"
+run_test "Upgraded documents still decrypt" "$CLI $MG_ARGS decrypt old1 name" "Legacy"
+run_test "Copy onto an upgraded document is refused" "$CLI $MG_ARGS copy src old1 2>&1 || true" "Duplicate"
+$CLI $MG_ARGS touch >/dev/null 2>&1
+python3 -c "import json; p='$MG_DB'; d=json.load(open(p)); [doc.pop('version') for doc in d['documents'].values()]; json.dump(d, open(p, 'w'))"
+resign_db "$MG_DB"
+run_test "Current-version file with uncounted documents is upgraded" "$CLI $MG_ARGS list" "upgraded to format version 0.1.0"
+run_test "Its documents start at version 1" "python3 -c \"import json; print(sorted(d['version'] for d in json.load(open('$MG_DB'))['documents'].values()))\"" "^\[1, 1\]$"
+run_test "Unknown version is rejected" "python3 -c \"import json; p='$MG_DB'; d=json.load(open(p)); d['version']='next'; json.dump(d, open(p, 'w'))\" && $CLI $MG_ARGS list 2>&1 || true" "Unrecognized database version 'next'"
+rm -f "$MG_DB" "$MG_DB.wal"
+
+# Test 97: Raw decrypts
+RW_DB="/tmp/magentadb_raw.json"
+RW_ARGS="--database $RW_DB"
+RW_IN="/tmp/magentadb_raw.in"
+rm -f "$RW_DB" "$RW_DB.wal" "$RW_IN"
+printf 'first line\n  second line\n\n' > "$RW_IN"
+$CLI $RW_ARGS insert rw1 notes --value-file "$RW_IN" >/dev/null 2>&1
+$CLI $RW_ARGS insert rw2 name 'no newline' >/dev/null 2>&1
+run_test "Raw decrypt writes exactly the stored bytes" "$CLI $RW_ARGS decrypt rw1 notes --raw | cmp - $RW_IN && echo identical" "^identical$"
+run_test "Raw decrypt pipes into a checksum" "[ \"\$($CLI $RW_ARGS decrypt rw1 notes --raw | sha256sum)\" = \"\$(sha256sum < $RW_IN)\" ] && echo match" "^match$"
+run_test "Raw decrypt adds no newline" "$CLI $RW_ARGS decrypt rw2 name --raw | tail -c 1 | od -An -c" "^ *e$"
+run_test "Raw decrypt is quiet even when verbose" "$CLI $RW_ARGS --verbose decrypt rw2 name --raw | wc -c" "^10$"
+run_test "Raw decrypt rejects --hex" "$CLI $RW_ARGS decrypt rw2 name --raw --hex 2>&1 || true" "cannot be used with"
+rm -f "$RW_DB" "$RW_DB.wal" "$RW_IN"
+
+# Test 98: Database locking
+LK_DB="/tmp/magentadb_lock.json"
+LK_ARGS="--database $LK_DB"
+rm -f "$LK_DB" "$LK_DB.wal" "$LK_DB.lock"
+$CLI $LK_ARGS insert lk1 name Alice >/dev/null 2>&1
+run_test "Lock file is removed on exit" "[ ! -e $LK_DB.lock ] && echo gone" "^gone$"
+# An interactive session holds the lock until its input ends
+sleep 2 | $CLI $LK_ARGS repl >/dev/null 2>&1 &
+LK_PID=$!
+sleep 0.5
+run_test "Second process is refused while locked" "$CLI $LK_ARGS insert lk2 name Bob 2>&1 || true" "database is locked by another process"
+run_test "Refused write is not applied" "$CLI $LK_ARGS --no-lock query Bob || true" "No documents found"
+run_test "No-lock opens a locked database" "$CLI $LK_ARGS --no-lock show lk1" "lk1"
+wait $LK_PID 2>/dev/null
+run_test "Database opens once the lock is released" "$CLI $LK_ARGS insert lk2 name Bob" "Inserted document 'lk2'"
+run_test "Failed commands release the lock" "$CLI $LK_ARGS show missing >/dev/null 2>&1; $CLI $LK_ARGS show lk2" "lk2"
+rm -f "$LK_DB" "$LK_DB.wal" "$LK_DB.lock"
+
+# Test 99: Ranked queries
+RK_DB="/tmp/magentadb_ranked.json"
+RK_ARGS="--database $RK_DB"
+rm -f "$RK_DB" "$RK_DB.wal"
+$CLI $RK_ARGS insert rk1 topic rust >/dev/null 2>&1
+$CLI $RK_ARGS insert rk2 topic rust >/dev/null 2>&1
+$CLI $RK_ARGS insert rk2 area crypto >/dev/null 2>&1
+$CLI $RK_ARGS insert rk3 topic rust >/dev/null 2>&1
+$CLI $RK_ARGS insert rk3 area crypto >/dev/null 2>&1
+$CLI $RK_ARGS insert rk3 target wasm >/dev/null 2>&1
+$CLI $RK_ARGS insert rk4 topic go >/dev/null 2>&1
+run_test "Ranked query finds every partial match" "$CLI $RK_ARGS query --any rust crypto wasm" "Found 3 document(s) matching any of 3 value(s)"
+run_test "Ranked query orders by match count" "$CLI $RK_ARGS query --any wasm crypto rust | grep -oE 'rk[0-9] \([0-9]/3\)' | tr '\n' ' '" "^rk3 (3/3) rk2 (2/3) rk1 (1/3) $"
+run_test "Ranked query JSON scores" "$CLI $RK_ARGS --output json query --any rust crypto wasm | python3 -c 'import json,sys; print([(m[\"id\"], m[\"score\"]) for m in json.load(sys.stdin)])'" "^\[('rk3', 3), ('rk2', 2), ('rk1', 1)\]$"
+run_test "Repeated values count once" "$CLI $RK_ARGS query --any crypto crypto" "rk2 (1/1)"
+run_test "Ranked query miss exits one" "$CLI $RK_ARGS query --any absent >/dev/null; echo \"exit=\$?\"" "^exit=1$"
+run_test "Ranked query rejects a plain value" "$CLI $RK_ARGS query rust --any crypto 2>&1 || true" "cannot be used with"
+rm -f "$RK_DB" "$RK_DB.wal"
+
+# Test 100: Key export and import
+KY_DB="/tmp/magentadb_key.json"
+KY_ARGS="--database $KY_DB"
+rm -f "$KY_DB" "$KY_DB.wal"
+$CLI $KY_ARGS insert ky1 name Alice >/dev/null 2>&1
+KY_KEY=$($CLI $KY_ARGS key export --force | tail -n 1)
+run_test "Exported key is 32 bytes of base64" "printf '%s' '$KY_KEY' | base64 -d | wc -c" "^32$"
+run_test "Export matches the stored key" "python3 -c \"import base64,json; print(base64.b64encode(bytes(json.load(open('$KY_DB'))['secret_key'])).decode())\"" "^$KY_KEY$"
+run_test "Export asks for confirmation" "echo n | $CLI $KY_ARGS key export" "Operation cancelled"
+run_test "Hex export encodes the same key" "$CLI $KY_ARGS key export --force --hex | tail -n 1 | xxd -r -p | base64" "^$KY_KEY$"
+run_test "Import rejects a short key" "$CLI $KY_ARGS key import \$(head -c 16 /dev/urandom | base64) --force 2>&1 || true" "expected 32 bytes, found 16 bytes"
+run_test "Import rejects invalid base64" "$CLI $KY_ARGS key import 'not base64!' --force 2>&1 || true" "Invalid key"
+$CLI $KY_ARGS key import "$(head -c 32 /dev/urandom | base64)" --force > /tmp/magentadb_key.out 2>&1
+run_test "Import warns about existing documents" "cat /tmp/magentadb_key.out" "1 document(s) were encrypted under the previous key"
+run_test "Old documents no longer decrypt" "$CLI $KY_ARGS decrypt ky1 name 2>&1 || true" "Failed to decrypt"
+run_test "Import of the exported key round-trips" "$CLI $KY_ARGS key import '$KY_KEY' --force && $CLI $KY_ARGS decrypt ky1 name" "ky1.name: Alice"
+run_test "Import refuses a key from the environment" "MAGENTADB_KEY=\$($CLI $KY_ARGS key export --force --hex | tail -n 1) $CLI $KY_ARGS key import '$KY_KEY' --force 2>&1 || true" "change it there instead"
+rm -f "$KY_DB" "$KY_DB.wal" /tmp/magentadb_key.out
+
+# Test 101: Fixed-size nonces
+NL_DB="/tmp/magentadb_nonce_len.json"
+NL_ARGS="--database $NL_DB"
+rm -f "$NL_DB" "$NL_DB.wal"
+$CLI $NL_ARGS insert nl1 name Alice >/dev/null 2>&1
+$CLI $NL_ARGS --document-keys insert nl2 name Bob >/dev/null 2>&1
+$CLI $NL_ARGS touch >/dev/null 2>&1
+run_test "Stored nonces are 24 bytes" "python3 -c \"import base64,json; d=json.load(open('$NL_DB'))['documents']; print(sorted({len(base64.b64decode(doc['fields']['name']['nonce'])) for doc in d.values()}))\"" "^\[24\]$"
+# Older files may store nonces as arrays of numbers
+python3 -c "import base64,json; p='$NL_DB'; d=json.load(open(p)); f=d['documents']['nl1']['fields']['name']; f['nonce']=list(base64.b64decode(f['nonce'])); json.dump(d, open(p, 'w'))"
+resign_db "$NL_DB"
+run_test "Existing 24-byte nonces still load" "$CLI $NL_ARGS decrypt nl1 name" "nl1.name: Alice"
+run_test "Wrapped-key nonces still load" "$CLI $NL_ARGS decrypt nl2 name" "nl2.name: Bob"
+python3 -c "import base64,json; p='$NL_DB'; d=json.load(open(p)); f=d['documents']['nl1']['fields']['name']; f['nonce']=base64.b64encode(bytes(12)).decode(); json.dump(d, open(p, 'w'))"
+resign_db "$NL_DB"
+run_test "Short nonce is refused on load" "$CLI $NL_ARGS list 2>&1 || true" "Failed to parse database file"
+run_test "Short nonce is left untouched" "python3 -c \"import base64,json; print(len(base64.b64decode(json.load(open('$NL_DB'))['documents']['nl1']['fields']['name']['nonce'])))\"" "^12$"
+rm -f "$NL_DB" "$NL_DB.wal"
+
+# Test 102: Fuzzy queries
+FZ_DB="/tmp/magentadb_fuzzy.json"
+FZ_ARGS="--database $FZ_DB"
+rm -f "$FZ_DB" "$FZ_DB.wal"
+$CLI $FZ_ARGS insert fz1 name alice --fuzzy >/dev/null 2>&1
+$CLI $FZ_ARGS insert fz2 name Alicia --fuzzy >/dev/null 2>&1
+$CLI $FZ_ARGS insert fz3 name bob --fuzzy >/dev/null 2>&1
+$CLI $FZ_ARGS insert fz4 name alice >/dev/null 2>&1
+$CLI $FZ_ARGS insert fz5 city alice --fuzzy >/dev/null 2>&1
+run_test "Fuzzy query tolerates a typo" "$CLI $FZ_ARGS query-fuzzy name alise" "fz1 (3 shared trigrams)"
+run_test "Fuzzy query skips unrelated values" "$CLI $FZ_ARGS query-fuzzy name alise | grep -c fz3 || true" "^0$"
+run_test "Fuzzy query ranks closer values first" "$CLI $FZ_ARGS --output json query-fuzzy name alice | python3 -c 'import json,sys; print([m[\"id\"] for m in json.load(sys.stdin)])'" "^\['fz1', 'fz2'\]$"
+run_test "Fuzzy query skips fields without trigrams" "$CLI $FZ_ARGS query-fuzzy name alice | grep -c fz4 || true" "^0$"
+run_test "Fuzzy query only searches the named field" "$CLI $FZ_ARGS query-fuzzy name alice | grep -c fz5 || true" "^0$"
+run_test "Fuzzy query honors the overlap threshold" "$CLI $FZ_ARGS query-fuzzy name alise --min-overlap 4" "No documents found with name like 'alise'"
+run_test "Fuzzy tokens do not match exact queries" "$CLI $FZ_ARGS query ali || true" "No documents found"
+run_test "Update keeps fuzzy indexing" "$CLI $FZ_ARGS update fz3 name robert >/dev/null && $CLI $FZ_ARGS query-fuzzy name robbert" "fz3"
+rm -f "$FZ_DB" "$FZ_DB.wal"
+
+# Test 103: Health checks
+HC_DB="/tmp/magentadb_health.json"
+HC_ARGS="--database $HC_DB"
+rm -f "$HC_DB" "$HC_DB.wal"
+$CLI $HC_ARGS insert hc1 name Alice >/dev/null 2>&1
+$CLI $HC_ARGS insert-num hc2 age 41 >/dev/null 2>&1
+$CLI $HC_ARGS insert hc3 city Paris --fuzzy >/dev/null 2>&1
+run_test "Healthy database passes" "$CLI $HC_ARGS healthcheck" "Healthy"
+run_test "Healthy database exits zero" "$CLI $HC_ARGS healthcheck >/dev/null; echo \"exit=\$?\"" "^exit=0$"
+run_test "Health check covers the indexes" "$CLI $HC_ARGS healthcheck" "Indexes cover every document"
+$CLI $HC_ARGS touch >/dev/null 2>&1
+# File a document under another document's key
+python3 -c "import json; p='$HC_DB'; d=json.load(open(p)); docs=d['documents']; docs['hc9']=docs.pop('hc1'); json.dump(d, open(p, 'w'))"
+resign_db "$HC_DB"
+run_test "Misfiled document fails the check" "$CLI $HC_ARGS healthcheck 2>&1 || true" "stored under 'hc9' has id 'hc1'"
+run_test "Inconsistent database exits non-zero" "$CLI $HC_ARGS healthcheck >/dev/null 2>&1; echo \"exit=\$?\"" "^exit=1$"
+python3 -c "import json; p='$HC_DB'; d=json.load(open(p)); docs=d['documents']; docs['hc1']=docs.pop('hc9'); f=docs['hc2']['fields']['age']; f['cipher']=('A' if f['cipher'][0] != 'A' else 'B') + f['cipher'][1:]; json.dump(d, open(p, 'w'))"
+resign_db "$HC_DB"
+run_test "Undecryptable field fails the check" "$CLI $HC_ARGS healthcheck 2>&1 || true" "1 of 3 field(s) do not decrypt"
+run_test "Failed check names the field" "$CLI $HC_ARGS healthcheck 2>&1 || true" "hc2.age"
+run_test "Failed check counts its problems" "$CLI $HC_ARGS healthcheck 2>&1 || true" "Health check failed: 1 problem(s) found"
+rm -f "$HC_DB" "$HC_DB.wal"
+
+# Test 104: Decrypting with an explicit key
+XK_DB="/tmp/magentadb_explicit_key.json"
+XK_ARGS="--database $XK_DB"
+rm -f "$XK_DB" "$XK_DB.wal"
+$CLI $XK_ARGS insert xk1 name Alice >/dev/null 2>&1
+$CLI $XK_ARGS --document-keys insert xk2 name Bob >/dev/null 2>&1
+XK_OLD=$($CLI $XK_ARGS key export --hex --force | tail -n 1)
+$CLI $XK_ARGS key import "$(head -c 32 /dev/urandom | base64)" --force >/dev/null 2>&1
+XK_SUM=$(sha256sum < "$XK_DB")
+run_test "Field under a previous key no longer decrypts" "$CLI $XK_ARGS decrypt xk1 name 2>&1 || true" "Failed to decrypt field"
+run_test "Explicit key decrypts it" "$CLI $XK_ARGS decrypt xk1 name --key $XK_OLD" "xk1.name: Alice"
+run_test "Explicit key unwraps document keys" "$CLI $XK_ARGS decrypt xk2 name --key $XK_OLD" "xk2.name: Bob"
+run_test "Wrong explicit key fails" "$CLI $XK_ARGS decrypt xk1 name --key \$(head -c 32 /dev/urandom | od -An -tx1 -v | tr -d ' \n') 2>&1 || true" "Failed to decrypt field"
+run_test "Short explicit key is rejected" "$CLI $XK_ARGS decrypt xk1 name --key abcd 2>&1 || true" "expected 32 bytes (64 hex digits), found 2 bytes"
+run_test "Explicit key leaves the database key alone" "[ \"\$(sha256sum < $XK_DB)\" = \"$XK_SUM\" ] && echo unchanged" "^unchanged$"
+rm -f "$XK_DB" "$XK_DB.wal"
+
+# Test 105: Per-database token encoding
+TE_DB="/tmp/magentadb_token_encoding.json"
+TE_OTHER="/tmp/magentadb_token_encoding_other.json"
+rm -f "$TE_DB" "$TE_DB.wal" "$TE_OTHER" "$TE_OTHER.wal"
+$CLI --database "$TE_DB" --token-encoding base64url insert te1 email 'te@example.com' --prefix >/dev/null 2>&1
+run_test "Base64url tokens are produced" "$CLI --database $TE_DB token 'te@example.com'" "Token for 'te@example.com': [A-Za-z0-9_-]\{11\}$"
+run_test "Base64url tokens round-trip" "$CLI --database $TE_DB query 'te@example.com'" "Found 1 document"
+run_test "Base64url prefix tokens round-trip" "$CLI --database $TE_DB query-prefix 'te@ex'" "Found 1 document"
+run_test "Stored token encoding is honored on reload" "$CLI --database $TE_DB stats" "Token encoding: base64url"
+run_test "Token encoding cannot change after creation" "$CLI --database $TE_DB --token-encoding hex stats 2>&1 || true" "cannot be changed after creation"
+run_test "Unknown token encoding is rejected" "$CLI --database $TE_DB --token-encoding b64 stats 2>&1 || true" "expected hex or base64url"
+$CLI --database "$TE_OTHER" insert te2 email 'te@example.com' >/dev/null 2>&1
+run_test "Hex tokens are the default" "$CLI --database $TE_OTHER token 'te@example.com'" "Token for 'te@example.com': [0-9a-f]\{16\}$"
+# Same key, different encoding: only the encoding keeps the merge out
+cp "$TE_OTHER" "$TE_DB"
+python3 -c "import json; d = json.load(open('$TE_DB')); d['documents'] = {}; d['token_encoding'] = 'base64url'; json.dump(d, open('$TE_DB', 'w'))"
+resign_db "$TE_DB"
+$CLI --database "$TE_DB" insert te1 email 'te@example.com' >/dev/null 2>&1
+run_test "Merge refuses mixed token encodings" "$CLI --database $TE_DB merge $TE_OTHER 2>&1 || true" "uses sha256 tokens in hex but this database uses sha256 in base64url"
+$CLI --database "$TE_DB" merge "$TE_OTHER" --reencrypt >/dev/null 2>&1
+run_test "Merged fields are re-encoded" "$CLI --database $TE_DB query 'te@example.com'" "Found 2 document"
+rm -f "$TE_DB" "$TE_DB.wal" "$TE_OTHER" "$TE_OTHER.wal"
+
+# Test 106: Batch decryption matches field-by-field decryption
+BD_DB="/tmp/magentadb_batch_decrypt.json"
+BD_ARGS="--database $BD_DB --compact-after 1"
+rm -f "$BD_DB" "$BD_DB.wal"
+$CLI $BD_ARGS insert bd name 'Ada' >/dev/null 2>&1
+$CLI $BD_ARGS --pad 32 insert bd city 'London' >/dev/null 2>&1
+$CLI $BD_ARGS insert-num bd age 36 >/dev/null 2>&1
+$CLI $BD_ARGS insert bd email 'ada@example.com' --deterministic >/dev/null 2>&1
+BD_SINGLE=$(for f in age city email name; do $CLI $BD_ARGS decrypt bd $f | sed -n "s/.*Decrypted bd\.\($f: \)/\1/p"; done)
+run_test "Batch decryption matches single decrypts" "[ \"\$($CLI $BD_ARGS decrypt-all bd | grep -E '^ +[a-z]+: ' | sed 's/^ *//')\" = \"$BD_SINGLE\" ] && echo same" "^same$"
+python3 -c "import base64,json,sys; p=sys.argv[1]; d=json.load(open(p)); f=d['documents']['bd']['fields']['email']; c=bytearray(base64.b64decode(f['cipher'])); c[-1]^=1; f['cipher']=base64.b64encode(c).decode(); json.dump(d, open(p, 'w'))" "$BD_DB"
+resign_db "$BD_DB"
+run_test "Batch decryption reports the corrupt field" "$CLI $BD_ARGS decrypt-all bd 2>&1 || true" "1 field(s) in 'bd' could not be decrypted"
+run_test "Batch decryption names the corrupt field" "$CLI $BD_ARGS decrypt-all bd 2>&1 || true" "email: .*Failed to decrypt field"
+run_test "Batch decryption keeps the other fields" "$CLI $BD_ARGS decrypt-all bd | grep -cE ': (Ada|London|36 \(number\))$'" "^3$"
+rm -f "$BD_DB" "$BD_DB.wal"
+
+# Test 107: Soft delete with tombstones
+SD_DB="/tmp/magentadb_soft_delete.json"
+SD_ARGS="--database $SD_DB --compact-after 1"
+rm -f "$SD_DB" "$SD_DB.wal"
+$CLI $SD_ARGS insert sd1 name 'Ada' >/dev/null 2>&1
+$CLI $SD_ARGS insert sd2 name 'Ada' >/dev/null 2>&1
+run_test "Soft delete" "$CLI $SD_ARGS remove sd1 --soft" "Soft-deleted document 'sd1'"
+run_test "Soft-deleted document is not queried" "$CLI $SD_ARGS query Ada | grep -c '📄'" "^1$"
+run_test "Soft-deleted document is not listed" "$CLI $SD_ARGS list" "contains 1 document"
+run_test "Soft-deleted document cannot be shown" "$CLI $SD_ARGS show sd1 2>&1 || true" "not found"
+run_test "Tombstone is kept in the file" "python3 -c \"import json; print('deleted_at' in json.load(open('$SD_DB'))['documents']['sd1'])\"" "^True$"
+run_test "Tombstone survives reload" "$CLI $SD_ARGS stats" "1 soft-deleted document"
+run_test "Undelete" "$CLI $SD_ARGS undelete sd1" "Restored document 'sd1'"
+run_test "Undeleted document is queried again" "$CLI $SD_ARGS query Ada | grep -c '📄'" "^2$"
+run_test "Undeleted document still decrypts" "$CLI $SD_ARGS decrypt sd1 name" "sd1.name: Ada"
+run_test "Undelete needs a tombstone" "$CLI $SD_ARGS undelete sd1 2>&1 || true" "No soft-deleted document 'sd1'"
+$CLI $SD_ARGS remove sd1 --soft >/dev/null 2>&1
+run_test "Compact purges tombstones" "$CLI $SD_ARGS compact" "Purged 1 soft-deleted document"
+run_test "Purged document is gone for good" "$CLI $SD_ARGS undelete sd1 2>&1 || true" "No soft-deleted document 'sd1'"
+run_test "Purged document is gone from the file" "python3 -c \"import json; print('sd1' in json.load(open('$SD_DB'))['documents'])\"" "^False$"
+$CLI $SD_ARGS insert sd3 name 'Ada' >/dev/null 2>&1
+$CLI $SD_ARGS backup /tmp/magentadb_soft_delete.bak >/dev/null 2>&1
+$CLI $SD_ARGS remove sd3 --soft >/dev/null 2>&1
+run_test "Clear counts only live documents" "$CLI $SD_ARGS clear --force" "(1 documents removed)"
+$CLI $SD_ARGS insert sd4 name 'Ada' >/dev/null 2>&1
+$CLI $SD_ARGS remove sd4 --soft >/dev/null 2>&1
+run_test "Restore replaces live documents" "$CLI $SD_ARGS restore /tmp/magentadb_soft_delete.bak" "Restored 2 document(s)"
+run_test "Restore purges tombstones" "$CLI $SD_ARGS undelete sd4 2>&1 || true" "No soft-deleted document 'sd4'"
+rm -f /tmp/magentadb_soft_delete.bak
+rm -f "$SD_DB" "$SD_DB.wal"
+
+# Test 108: Querying by a precomputed token
+TR_DB="/tmp/magentadb_token_raw.json"
+TR_ARGS="--database $TR_DB"
+rm -f "$TR_DB" "$TR_DB.wal"
+$CLI $TR_ARGS insert tr1 email 'tr@example.com' >/dev/null 2>&1
+$CLI $TR_ARGS insert tr2 backup_email 'tr@example.com' >/dev/null 2>&1
+$CLI $TR_ARGS insert tr3 email 'other@example.com' >/dev/null 2>&1
+TR_TOKEN=$($CLI $TR_ARGS token 'tr@example.com' | tail -n 1 | sed 's/.*: //')
+run_test "Raw token query matches the value query" "[ \"\$($CLI $TR_ARGS query --token-raw $TR_TOKEN | grep '📄')\" = \"\$($CLI $TR_ARGS query 'tr@example.com' | grep '📄')\" ] && echo same" "^same$"
+run_test "Raw token query finds both documents" "$CLI $TR_ARGS query --token-raw $TR_TOKEN" "Found 2 document"
+run_test "Raw token query honors --field" "$CLI $TR_ARGS query --token-raw $TR_TOKEN --field email | grep '📄' | tr '\n' ' '" "^   📄 tr1 $"
+run_test "Raw token query rejects a malformed token" "$CLI $TR_ARGS query --token-raw NOTATOKEN 2>&1 || true" "is not a token: this database writes tokens in hex"
+run_test "Raw token query cannot be exact" "$CLI $TR_ARGS query --token-raw $TR_TOKEN --exact 2>&1 || true" "cannot be used with"
+rm -f "$TR_DB" "$TR_DB.wal"
+
+# Test 109: Authenticated write-ahead log
+WA_DB="/tmp/magentadb_wal_auth.json"
+WA_ARGS="--database $WA_DB"
+rm -f "$WA_DB" "$WA_DB.wal" "$WA_DB.saved"
+$CLI $WA_ARGS insert wa1 name 'Ada' >/dev/null 2>&1
+$CLI $WA_ARGS insert wa2 name 'Bob' >/dev/null 2>&1
+$CLI $WA_ARGS insert wa3 name 'Cy' >/dev/null 2>&1
+cp "$WA_DB.wal" "$WA_DB.saved"
+run_test "Log entries are tagged" "head -n 1 $WA_DB.wal" "^[0-9a-f]\{64\} {"
+run_test "Authenticated log replays" "$CLI $WA_ARGS list" "contains 3 document"
+echo '0000000000000000000000000000000000000000000000000000000000000000 {"Remove":"wa1"}' >> "$WA_DB.wal"
+echo '{"Remove":"wa2"}' >> "$WA_DB.wal"
+run_test "Forged log entry is refused" "$CLI $WA_ARGS list 2>&1 || true" "WAL entry on line 3 failed authentication"
+cp "$WA_DB.saved" "$WA_DB.wal"
+sed -i 1d "$WA_DB.wal"
+run_test "Log with an entry cut from the front is refused" "$CLI $WA_ARGS list 2>&1 || true" "does not follow the database file"
+cp "$WA_DB.saved" "$WA_DB.wal"
+$CLI $WA_ARGS touch >/dev/null 2>&1
+cp "$WA_DB.saved" "$WA_DB.wal"
+run_test "Log left by an interrupted compaction is dropped" "$CLI $WA_ARGS list && [ ! -s $WA_DB.wal ] && echo dropped" "dropped"
+$CLI $WA_ARGS --compact-after 1 insert wa2 name 'Bobby' >/dev/null 2>&1
+cp "$WA_DB.saved" "$WA_DB.wal"
+run_test "Old log replayed over newer data is refused" "$CLI $WA_ARGS list 2>&1 || true" "does not follow the database file"
+rm -f "$WA_DB" "$WA_DB.wal" "$WA_DB.saved"
+
 # Final results
 echo ""
 echo "📋 Test Summary"
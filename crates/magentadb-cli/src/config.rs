@@ -0,0 +1,61 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Database path used when neither `--database` nor the config file
+/// gives one
+pub const DEFAULT_DATABASE: &str = "magentadb.json";
+
+/// Name of the config file looked for in the working directory and the
+/// user's config directory
+pub const CONFIG_FILE_NAME: &str = "magentadb.toml";
+
+/// Defaults read from `magentadb.toml`. Every setting is optional; command
+/// line flags take precedence over anything set here.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Database file path
+    pub database: Option<String>,
+    /// Enable verbose logging
+    pub verbose: Option<bool>,
+    /// Output format, as accepted by `--output`
+    pub output: Option<String>,
+}
+
+impl Config {
+    /// Read the first config file found, in the working directory and then
+    /// in `$XDG_CONFIG_HOME/magentadb` (or `~/.config/magentadb`). Without
+    /// one every setting is left unset; a file that exists but cannot be
+    /// read or parsed is an error.
+    pub fn load() -> Result<(Self, Option<PathBuf>)> {
+        for path in config_paths() {
+            let data = match fs::read_to_string(&path) {
+                Ok(data) => data,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).context(format!("Failed to read config file {}", path.display()))
+                }
+            };
+            let config = toml::from_str(&data)
+                .context(format!("Failed to parse config file {}", path.display()))?;
+            return Ok((config, Some(path)));
+        }
+        Ok((Self::default(), None))
+    }
+}
+
+/// Where config files are looked for, in order
+fn config_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(CONFIG_FILE_NAME)];
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    if let Some(dir) = config_home {
+        paths.push(dir.join("magentadb").join(CONFIG_FILE_NAME));
+    }
+    paths
+}
@@ -0,0 +1,1648 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::sync::Arc;
+
+use magentadb_core::{
+    db::{
+        validate_field_name, validate_id, DbSnapshot, InMemoryDB, IndexCheck, IndexCompaction,
+        RemovalImpact,
+    },
+    document::{
+        mask, unix_millis, verify_documents, DocumentStored, FieldMaterialized, FieldValue,
+        MaskPolicy, ValueType, VerifyFailure, WrappedKey,
+    },
+    pattern::Pattern,
+    wal::WalOp,
+};
+use magentadb_crypto::{
+    decrypt_many, decrypt_padded, encrypt, encrypt_counter, encrypt_deterministic, generate_key,
+    key_fingerprint, pad, token, tokens_equal, unpad, verify_key_fingerprint, Nonce, TokenAlgo,
+    TokenEncoding,
+};
+
+use crate::state::{DatabaseState, KEY_ENV_VAR};
+
+/// What to do with a document id present in both databases during a merge
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep this database's document
+    Skip,
+    /// Replace this database's document with the incoming one
+    Overwrite,
+    /// Keep both documents' fields, taking incoming values for shared names
+    MergeFields,
+}
+
+/// Per-field storage choices made at insert time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FieldOptions {
+    pub prefix: bool,
+    pub fuzzy: bool,
+    pub deterministic: bool,
+    pub numeric: bool,
+    pub expires_at: Option<i64>,
+    pub no_search: bool,
+    pub value_type: ValueType,
+}
+
+impl FieldOptions {
+    /// The options an existing field was stored with
+    pub fn of(field_data: &FieldMaterialized) -> Self {
+        Self {
+            prefix: !field_data.prefix_tokens.is_empty(),
+            fuzzy: !field_data.trigram_tokens.is_empty(),
+            deterministic: field_data.deterministic,
+            numeric: field_data.range_token.is_some(),
+            expires_at: field_data.expires_at,
+            no_search: !field_data.searchable,
+            value_type: field_data.value_type,
+        }
+    }
+}
+
+/// Unix time `ttl` seconds from now
+pub fn expires_at(ttl: Option<u64>) -> Option<i64> {
+    ttl.map(|ttl| chrono::Utc::now().timestamp().saturating_add_unsigned(ttl))
+}
+
+/// One operation in an `exec` batch file
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+enum BatchOp {
+    Insert {
+        id: String,
+        field: String,
+        value: String,
+        #[serde(default)]
+        prefix: bool,
+        #[serde(default)]
+        fuzzy: bool,
+        #[serde(default)]
+        deterministic: bool,
+        #[serde(default)]
+        ttl: Option<u64>,
+        #[serde(default)]
+        no_search: bool,
+    },
+    Remove {
+        id: String,
+    },
+}
+
+/// Result of writing one field
+#[derive(Debug, Clone)]
+pub enum InsertOutcome {
+    /// The field is new to its document, which may itself be new
+    Inserted { field: FieldMaterialized },
+    /// The field replaced `previous`
+    Updated {
+        field: FieldMaterialized,
+        previous: Box<FieldMaterialized>,
+    },
+}
+
+impl InsertOutcome {
+    /// The field as stored
+    pub fn field(&self) -> &FieldMaterialized {
+        match self {
+            InsertOutcome::Inserted { field } | InsertOutcome::Updated { field, .. } => field,
+        }
+    }
+}
+
+/// A document found by a search, with the names of the fields that matched
+/// in sorted order
+#[derive(Debug, Clone)]
+pub struct QueryHit {
+    pub doc: Arc<DocumentStored>,
+    pub fields: Vec<String>,
+}
+
+/// Result of `handle_query`
+#[derive(Debug, Clone)]
+pub struct QueryResult {
+    /// Token the value was searched under
+    pub token: String,
+    pub hits: Vec<QueryHit>,
+    /// Documents whose token matched but whose value did not, when the
+    /// query was exact
+    pub dropped: usize,
+}
+
+/// One page of `handle_list`
+#[derive(Debug, Clone)]
+pub struct ListResult {
+    /// Documents in the whole database
+    pub total: usize,
+    pub docs: Vec<Arc<DocumentStored>>,
+}
+
+/// Database statistics as reported by `stats`
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsReport {
+    pub db_id: String,
+    /// Namespace the statistics are for
+    pub namespace: String,
+    /// Every namespace holding documents, in name order
+    pub namespaces: Vec<String>,
+    pub documents: usize,
+    pub token_index_size: usize,
+    pub field_index_size: usize,
+    /// Documents carrying each field name, by name
+    pub field_distribution: BTreeMap<String, usize>,
+    pub version: String,
+    pub created_at: String,
+    pub last_modified: String,
+    pub total_fields: usize,
+    pub cipher_bytes: usize,
+    pub nonce_bytes: usize,
+    pub token_algo: TokenAlgo,
+    pub token_encoding: TokenEncoding,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce_counter: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pad_block: Option<usize>,
+    pub mask_policy: MaskPolicy,
+    pub max_field_bytes: usize,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub document_keys: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub external_key: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_filter_fp_rate: Option<f64>,
+}
+
+/// Result of `handle_remove`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoveOutcome {
+    Removed,
+    NotFound,
+}
+
+/// What `handle_compact` dropped
+#[derive(Debug, Clone)]
+pub struct CompactReport {
+    pub indexes: IndexCompaction,
+    /// Ids of the soft-deleted documents purged, in order
+    pub purged: Vec<String>,
+}
+
+/// What a destructive command would do, as reported by `--dry-run`
+#[derive(Debug, Clone)]
+pub struct RemovalPlan {
+    /// Documents that would be removed, in id order
+    pub ids: Vec<String>,
+    pub impact: RemovalImpact,
+    /// Token index size before and after
+    pub token_index_size: (usize, usize),
+    /// Field index size before and after
+    pub field_index_size: (usize, usize),
+}
+
+/// Result of `handle_reindex`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReindexReport {
+    pub documents: usize,
+    /// Token index size before and after
+    pub token_index_size: (usize, usize),
+    /// Field index size before and after
+    pub field_index_size: (usize, usize),
+}
+
+/// Result of `handle_healthcheck`
+#[derive(Debug, Clone)]
+pub struct HealthReport {
+    /// Why the key does not match the fingerprint stored with the
+    /// database, if it does not
+    pub key_error: Option<String>,
+    pub verify: VerifyReport,
+    pub indexes: IndexCheck,
+    /// `(key, id)` of every document stored under a key other than its id
+    pub misplaced: Vec<(String, String)>,
+}
+
+impl HealthReport {
+    /// Number of problems found; stale index entries are not problems
+    pub fn problems(&self) -> usize {
+        usize::from(self.key_error.is_some())
+            + self.verify.failures.len()
+            + usize::from(!self.indexes.is_consistent())
+            + self.misplaced.len()
+    }
+}
+
+/// Result of `handle_merge`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergeOutcome {
+    pub added: usize,
+    /// Documents overwritten or merged, depending on the policy
+    pub replaced: usize,
+    pub skipped: usize,
+}
+
+/// Result of `handle_gc`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcOutcome {
+    pub fields_removed: usize,
+    pub documents_removed: usize,
+}
+
+/// Result of `handle_verify`
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    pub fields: usize,
+    pub documents: usize,
+    pub failures: Vec<VerifyFailure>,
+}
+
+/// Encrypt `value` into `field` of document `id`, creating the document or
+/// merging into its existing fields, and persist the result.
+pub fn handle_insert(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    id: &str,
+    field: &str,
+    value: &str,
+    options: FieldOptions,
+    db_path: &str,
+) -> Result<InsertOutcome> {
+    let fields = [(field.to_string(), value.to_string())];
+    let mut outcomes = handle_insert_fields(db, db_state, id, &fields, options, db_path)?;
+    Ok(outcomes.remove(0))
+}
+
+/// Encrypt several `(field, value)` pairs into document `id` with the same
+/// options, writing the document and persisting it once. Outcomes are
+/// returned in the order of `fields`.
+pub fn handle_insert_fields(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    id: &str,
+    fields: &[(String, String)],
+    options: FieldOptions,
+    db_path: &str,
+) -> Result<Vec<InsertOutcome>> {
+    // Checked before anything is encrypted; the database checks again on write
+    validate_id(id)?;
+    let mut names = HashSet::new();
+    for (field, _) in fields {
+        validate_field_name(field)?;
+        if !names.insert(field) {
+            anyhow::bail!("Field '{}' is set more than once", field);
+        }
+    }
+
+    let existing = db.get(id).ok();
+    let (key, wrapped_key) = match &existing {
+        Some(existing) => (existing.field_key(&db_state.secret_key)?, None),
+        None => new_document_key(db_state)?,
+    };
+    let mut materialized = Vec::with_capacity(fields.len());
+    for (field, value) in fields {
+        materialized.push((
+            field.clone(),
+            materialize_field(db_state, &key, value, options)?,
+        ));
+    }
+
+    let (doc, old_doc) = if existing.is_some() {
+        db.upsert_fields(id, materialized.iter().cloned().collect())?
+    } else {
+        let doc = DocumentStored {
+            id: id.to_string(),
+            fields: materialized.iter().cloned().collect(),
+            version: 0,
+            wrapped_key,
+            created_at: None,
+            updated_at: None,
+            metadata: HashMap::new(),
+            deleted_at: None,
+        };
+        db.compare_and_swap(id, 0, doc)?;
+        (db.get(id)?, None)
+    };
+    db_state.persist(db_path, WalOp::Upsert((*doc).clone()))?;
+
+    Ok(materialized
+        .into_iter()
+        .map(|(field, field_data)| {
+            let previous = old_doc
+                .as_ref()
+                .and_then(|old_doc| old_doc.fields.get(&field).cloned());
+            match previous {
+                Some(previous) => InsertOutcome::Updated {
+                    field: field_data,
+                    previous: Box::new(previous),
+                },
+                None => InsertOutcome::Inserted { field: field_data },
+            }
+        })
+        .collect())
+}
+
+/// Key and wrapped key for a document that does not exist yet: a fresh
+/// data key when per-document keys are enabled, otherwise the master key
+fn new_document_key(db_state: &DatabaseState) -> Result<([u8; 32], Option<WrappedKey>)> {
+    if db_state.document_keys {
+        let key = generate_key();
+        let wrapped_key = WrappedKey::new(&key, &db_state.secret_key)?;
+        Ok((key, Some(wrapped_key)))
+    } else {
+        Ok((db_state.secret_key, None))
+    }
+}
+
+/// Encrypt a plaintext value under `key` and tokenize it under the master
+/// key into its stored form
+fn materialize_field(
+    db_state: &mut DatabaseState,
+    key: &[u8; 32],
+    value: &str,
+    options: FieldOptions,
+) -> Result<FieldMaterialized> {
+    options
+        .value_type
+        .check(value)
+        .map_err(anyhow::Error::msg)?;
+    let (nonce, cipher) = seal_value(
+        db_state,
+        key,
+        value,
+        options.deterministic,
+        db_state.pad_block,
+    )?;
+    let tok = if options.no_search {
+        String::new()
+    } else {
+        token::tokenize(
+            db_state.token_algo,
+            db_state.token_encoding,
+            &db_state.secret_key,
+            value,
+        )
+    };
+    let range_token = if options.numeric {
+        let number: f64 = value
+            .parse()
+            .ok()
+            .filter(|n: &f64| n.is_finite())
+            .context(format!("Value '{}' is not a finite number", value))?;
+        token::tokenize_range(
+            db_state.token_algo,
+            db_state.token_encoding,
+            &db_state.secret_key,
+            number,
+        )
+    } else {
+        None
+    };
+    let prefix_tokens = if options.prefix {
+        token::tokenize_prefixes(
+            db_state.token_algo,
+            db_state.token_encoding,
+            &db_state.secret_key,
+            value,
+        )
+    } else {
+        Vec::new()
+    };
+    let trigram_tokens = if options.fuzzy {
+        token::tokenize_trigrams(
+            db_state.token_algo,
+            db_state.token_encoding,
+            &db_state.secret_key,
+            value,
+        )
+    } else {
+        Vec::new()
+    };
+
+    Ok(FieldMaterialized {
+        cipher,
+        nonce,
+        token: tok,
+        masked: mask(value, db_state.mask_policy),
+        prefix_tokens,
+        trigram_tokens,
+        range_token,
+        expires_at: options.expires_at,
+        deterministic: options.deterministic,
+        pad_block: db_state.pad_block,
+        value_len: Some(value.len()),
+        extra_values: Vec::new(),
+        searchable: !options.no_search,
+        value_type: options.value_type,
+    })
+}
+
+/// Check `value` against the field size limit, pad it to `pad_block` and
+/// encrypt it under `key`, returning `(nonce, cipher)`
+fn seal_value(
+    db_state: &mut DatabaseState,
+    key: &[u8; 32],
+    value: &str,
+    deterministic: bool,
+    pad_block: Option<usize>,
+) -> Result<(Nonce, Vec<u8>)> {
+    if value.len() > db_state.max_field_bytes {
+        anyhow::bail!(
+            "Value is {} bytes, over the limit of {} bytes per field",
+            value.len(),
+            db_state.max_field_bytes
+        );
+    }
+    let plaintext = match pad_block {
+        Some(block) => pad(value.as_bytes(), block),
+        None => value.as_bytes().to_vec(),
+    };
+    Ok(if deterministic {
+        encrypt_deterministic(&plaintext, key)?
+    } else if let Some(counter) = db_state.nonce_counter.as_mut() {
+        let prefix = counter.prefix;
+        encrypt_counter(&plaintext, key, &prefix, counter.take()?)?
+    } else {
+        encrypt(&plaintext, key)?
+    })
+}
+
+/// Encrypt `value` under `key` into an extra value of `field_data`, padded
+/// and encrypted the way the field's first value was
+fn materialize_extra_value(
+    db_state: &mut DatabaseState,
+    key: &[u8; 32],
+    value: &str,
+    field_data: &FieldMaterialized,
+) -> Result<FieldValue> {
+    field_data
+        .value_type
+        .check(value)
+        .map_err(anyhow::Error::msg)?;
+    let (nonce, cipher) = seal_value(
+        db_state,
+        key,
+        value,
+        field_data.deterministic,
+        field_data.pad_block,
+    )?;
+    let token = if field_data.searchable {
+        token::tokenize(
+            db_state.token_algo,
+            db_state.token_encoding,
+            &db_state.secret_key,
+            value,
+        )
+    } else {
+        String::new()
+    };
+    Ok(FieldValue {
+        cipher,
+        nonce,
+        token,
+    })
+}
+
+/// Replace the value of an existing field, keeping the options it was
+/// stored with. Returns the document as stored.
+pub fn handle_update(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    id: &str,
+    field: &str,
+    value: &str,
+    db_path: &str,
+) -> Result<Arc<DocumentStored>> {
+    let existing = db.get(id).context(format!("Document '{}' not found", id))?;
+    let old_field = existing
+        .fields
+        .get(field)
+        .context(format!("Field '{}' not found in document '{}'", field, id))?;
+
+    let key = existing.field_key(&db_state.secret_key)?;
+    let field_data = materialize_field(db_state, &key, value, FieldOptions::of(old_field))?;
+    db.update_field(id, field, field_data)?;
+
+    let doc = db.get(id)?;
+    db_state.persist(db_path, WalOp::Upsert((*doc).clone()))?;
+
+    Ok(doc)
+}
+
+/// Add `value` to an existing field alongside its current values, so the
+/// field matches queries for any of them. Returns how many values the field
+/// now holds.
+pub fn handle_append(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    id: &str,
+    field: &str,
+    value: &str,
+    db_path: &str,
+) -> Result<usize> {
+    let existing = db.get(id).context(format!("Document '{}' not found", id))?;
+    let field_data = existing
+        .fields
+        .get(field)
+        .context(format!("Field '{}' not found in document '{}'", field, id))?;
+
+    let key = existing.field_key(&db_state.secret_key)?;
+    let extra = materialize_extra_value(db_state, &key, value, field_data)?;
+    let count = db.append_field_value(id, field, extra)?;
+
+    let doc = db.get(id)?;
+    db_state.persist(db_path, WalOp::Upsert((*doc).clone()))?;
+
+    Ok(count)
+}
+
+pub fn handle_show(db: &InMemoryDB, id: &str) -> Option<Arc<DocumentStored>> {
+    db.get(id).ok()
+}
+
+pub fn handle_exists(db: &InMemoryDB, id: &str) -> bool {
+    db.contains(id)
+}
+
+/// Find documents with a field equal to `value` by its token. With
+/// `exact`, matched fields are decrypted and those that only share the
+/// token are dropped. With `field`, only that field is searched. Only
+/// documents whose metadata holds every pair of `meta` are returned. With
+/// `limit`, at most that many documents are returned; a plain token query
+/// stops collecting once it has them, so which ones is unspecified.
+pub fn handle_query(
+    db: &InMemoryDB,
+    db_state: &DatabaseState,
+    value: &str,
+    field: Option<&str>,
+    exact: bool,
+    meta: &[(String, String)],
+    limit: Option<usize>,
+) -> QueryResult {
+    let tok = token::tokenize(
+        db_state.token_algo,
+        db_state.token_encoding,
+        &db_state.secret_key,
+        value,
+    );
+    query_token(
+        db,
+        db_state,
+        tok,
+        exact.then_some(value),
+        field,
+        meta,
+        limit,
+    )
+}
+
+/// Like `handle_query`, but with a token computed elsewhere, e.g. by the
+/// `token` command on another machine holding the same key. It must be in
+/// this database's token encoding; the value is never needed, so the
+/// query cannot be exact.
+pub fn handle_query_token(
+    db: &InMemoryDB,
+    db_state: &DatabaseState,
+    token: &str,
+    field: Option<&str>,
+    meta: &[(String, String)],
+) -> Result<QueryResult> {
+    if !db_state.token_encoding.is_token(token) {
+        anyhow::bail!(
+            "'{}' is not a token: this database writes tokens in {}",
+            token,
+            db_state.token_encoding
+        );
+    }
+    Ok(query_token(
+        db,
+        db_state,
+        token.to_string(),
+        None,
+        field,
+        meta,
+        None,
+    ))
+}
+
+/// Documents holding a value with token `tok`, checked against `exact`
+/// by decrypting when it is given
+fn query_token(
+    db: &InMemoryDB,
+    db_state: &DatabaseState,
+    tok: String,
+    exact: Option<&str>,
+    field: Option<&str>,
+    meta: &[(String, String)],
+    limit: Option<usize>,
+) -> QueryResult {
+    let now = chrono::Utc::now().timestamp();
+    let is_match = |doc: &DocumentStored, field_data: &FieldMaterialized| {
+        !field_data.is_expired(now)
+            && field_data.values().any(|(value_token, cipher, nonce)| {
+                tokens_equal(value_token, &tok)
+                    && exact.is_none_or(|value| {
+                        decrypts_to(db_state, doc, field_data, cipher, nonce, value)
+                    })
+            })
+    };
+
+    let (docs, dropped) = match field {
+        Some(field) => {
+            let candidates = db.query_by_field_token(field, &tok);
+            let count = candidates.len();
+            let docs: Vec<_> = candidates
+                .into_iter()
+                .filter(|doc| {
+                    doc.fields
+                        .get(field)
+                        .is_some_and(|field_data| is_match(doc, field_data))
+                })
+                .collect();
+            let dropped = count - docs.len();
+            (docs, dropped)
+        }
+        None if exact.is_some() => {
+            let candidates = db.query_by_token(&tok).len();
+            let docs = db.query_by_token_verified(&tok, is_match);
+            let dropped = candidates.saturating_sub(docs.len());
+            (docs, dropped)
+        }
+        None => match limit.filter(|_| meta.is_empty()) {
+            Some(max) => {
+                let mut docs = db.query_by_token_limited(&tok, max);
+                docs.sort_by(|a, b| a.id.cmp(&b.id));
+                (docs, 0)
+            }
+            None => {
+                #[cfg(feature = "parallel")]
+                let docs = db.query_by_token_par(&tok);
+                #[cfg(not(feature = "parallel"))]
+                let docs = db.query_by_token(&tok);
+                (docs, 0)
+            }
+        },
+    };
+
+    let mut hits: Vec<QueryHit> = docs
+        .into_iter()
+        .filter(|doc| {
+            meta.iter()
+                .all(|(key, value)| doc.metadata.get(key) == Some(value))
+        })
+        .map(|doc| {
+            let mut fields: Vec<String> = doc
+                .fields
+                .iter()
+                .filter(|(name, field_data)| {
+                    field.is_none_or(|field| field == name.as_str()) && is_match(&doc, field_data)
+                })
+                .map(|(name, _)| name.clone())
+                .collect();
+            fields.sort();
+            QueryHit { doc, fields }
+        })
+        .collect();
+    if let Some(limit) = limit {
+        hits.truncate(limit);
+    }
+
+    QueryResult {
+        token: tok,
+        hits,
+        dropped,
+    }
+}
+
+/// Documents whose metadata holds every `(key, value)` of `filters`, as
+/// hits without matched fields
+pub fn handle_query_meta(db: &InMemoryDB, filters: &[(String, String)]) -> Vec<QueryHit> {
+    db.query_by_metadata(filters)
+        .into_iter()
+        .map(|doc| QueryHit {
+            doc,
+            fields: Vec::new(),
+        })
+        .collect()
+}
+
+/// Set plaintext metadata `key` of document `id`, returning the value it
+/// replaced
+pub fn handle_set_meta(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    id: &str,
+    key: &str,
+    value: &str,
+    db_path: &str,
+) -> Result<Option<String>> {
+    let previous = db.set_metadata(id, key, value)?;
+    let doc = db.get(id)?;
+    db_state.persist(db_path, WalOp::Upsert((*doc).clone()))?;
+
+    Ok(previous)
+}
+
+/// Whether one value of `field_data` of `doc`, given by its `cipher` and
+/// `nonce`, decrypts to exactly `value`
+fn decrypts_to(
+    db_state: &DatabaseState,
+    doc: &DocumentStored,
+    field_data: &FieldMaterialized,
+    cipher: &[u8],
+    nonce: &Nonce,
+    value: &str,
+) -> bool {
+    let Ok(key) = doc.field_key(&db_state.secret_key) else {
+        return false;
+    };
+    decrypt_padded(cipher, nonce, &key, field_data.pad_block)
+        .is_ok_and(|plaintext| plaintext == value.as_bytes())
+}
+
+pub fn handle_query_prefix(
+    db: &InMemoryDB,
+    db_state: &DatabaseState,
+    prefix: &str,
+) -> Result<Vec<Arc<DocumentStored>>> {
+    if prefix.chars().count() < token::PREFIX_MIN_LEN {
+        anyhow::bail!(
+            "Prefix must be at least {} characters",
+            token::PREFIX_MIN_LEN
+        );
+    }
+
+    Ok(db.query_by_prefix(
+        db_state.token_algo,
+        db_state.token_encoding,
+        &db_state.secret_key,
+        prefix,
+    ))
+}
+
+pub fn handle_query_range(
+    db: &InMemoryDB,
+    db_state: &DatabaseState,
+    field: &str,
+    min: f64,
+    max: f64,
+) -> Vec<Arc<DocumentStored>> {
+    db.query_range(
+        db_state.token_algo,
+        db_state.token_encoding,
+        &db_state.secret_key,
+        field,
+        min,
+        max,
+    )
+}
+
+pub fn handle_query_length(db: &InMemoryDB, field: &str, len: usize) -> Vec<Arc<DocumentStored>> {
+    db.query_by_length(field, len)
+}
+
+/// Decrypt every field, or only `field`, and return the documents with a
+/// value matching `pattern`
+pub fn handle_grep(
+    db: &InMemoryDB,
+    db_state: &DatabaseState,
+    pattern: &str,
+    field: Option<&str>,
+) -> Result<Vec<QueryHit>> {
+    let compiled = Pattern::new(pattern)?;
+    Ok(db
+        .scan_decrypted(&db_state.secret_key, field, &compiled)
+        .into_iter()
+        .map(|(doc, fields)| QueryHit { doc, fields })
+        .collect())
+}
+
+pub fn handle_query_field(db: &InMemoryDB, name: &str) -> Vec<Arc<DocumentStored>> {
+    db.query_by_field(name)
+}
+
+/// Distinct field names in sorted order, each with the number of documents
+/// that have it
+pub fn handle_fields(db: &InMemoryDB) -> Vec<(String, usize)> {
+    db.field_names()
+        .into_iter()
+        .map(|name| {
+            let count = db.count_by_field(&name);
+            (name, count)
+        })
+        .collect()
+}
+
+/// The search token of `value` under this database's key, as it would be
+/// stored for a field with that value
+pub fn handle_token(db_state: &DatabaseState, value: &str) -> String {
+    token::tokenize(
+        db_state.token_algo,
+        db_state.token_encoding,
+        &db_state.secret_key,
+        value,
+    )
+}
+
+/// Count documents with a field equal to `value`
+pub fn handle_count_value(db: &InMemoryDB, db_state: &DatabaseState, value: &str) -> usize {
+    db.count_by_token(&token::tokenize(
+        db_state.token_algo,
+        db_state.token_encoding,
+        &db_state.secret_key,
+        value,
+    ))
+}
+
+/// Ids of the documents holding `value` in any field, sorted, read
+/// straight from the token index
+pub fn handle_query_ids(db: &InMemoryDB, db_state: &DatabaseState, value: &str) -> Vec<String> {
+    db.query_ids_by_token(&token::tokenize(
+        db_state.token_algo,
+        db_state.token_encoding,
+        &db_state.secret_key,
+        value,
+    ))
+}
+
+/// Documents holding any of `values` in any field, each with how many of
+/// the distinct values it holds, best match first
+pub fn handle_query_ranked(
+    db: &InMemoryDB,
+    db_state: &DatabaseState,
+    values: &[String],
+) -> Vec<(Arc<DocumentStored>, usize)> {
+    let tokens: Vec<String> = values
+        .iter()
+        .map(|value| {
+            token::tokenize(
+                db_state.token_algo,
+                db_state.token_encoding,
+                &db_state.secret_key,
+                value,
+            )
+        })
+        .collect();
+    db.query_ranked(&tokens)
+}
+
+/// Documents whose fuzzy-indexed `field` shares at least `min_overlap`
+/// trigrams with `value`, each with how many it shares, best match first
+pub fn handle_query_fuzzy(
+    db: &InMemoryDB,
+    db_state: &DatabaseState,
+    field: &str,
+    value: &str,
+    min_overlap: usize,
+) -> Vec<(Arc<DocumentStored>, usize)> {
+    db.query_fuzzy(
+        db_state.token_algo,
+        db_state.token_encoding,
+        &db_state.secret_key,
+        field,
+        value,
+        min_overlap,
+    )
+}
+
+/// Count documents with a field called `field`
+pub fn handle_count_field(db: &InMemoryDB, field: &str) -> usize {
+    db.count_by_field(field)
+}
+
+/// Decrypt one field, returning its raw plaintext bytes and the type they
+/// encode. With `key`, the field is decrypted with that key in place of the
+/// database's, which is left as it is.
+pub fn handle_decrypt(
+    db: &InMemoryDB,
+    db_state: &DatabaseState,
+    id: &str,
+    field: &str,
+    key: Option<&[u8; 32]>,
+) -> Result<(Vec<u8>, ValueType)> {
+    let doc = db.get(id).context(format!("Document '{}' not found", id))?;
+
+    let field_data = doc
+        .fields
+        .get(field)
+        .context(format!("Field '{}' not found in document '{}'", field, id))?;
+
+    let key = doc.field_key(key.unwrap_or(&db_state.secret_key))?;
+    let plaintext = decrypt_padded(
+        &field_data.cipher,
+        &field_data.nonce,
+        &key,
+        field_data.pad_block,
+    )
+    .context("Failed to decrypt field")?;
+    Ok((plaintext, field_data.value_type))
+}
+
+/// Decrypt every field of a document as text, in field name order. A field
+/// that fails does not stop the others from being decrypted.
+pub fn handle_decrypt_all(
+    db: &InMemoryDB,
+    db_state: &DatabaseState,
+    id: &str,
+) -> Result<Vec<(String, Result<String>, ValueType)>> {
+    let doc = db.get(id).context(format!("Document '{}' not found", id))?;
+
+    let mut field_names: Vec<&String> = doc.fields.keys().collect();
+    field_names.sort();
+
+    let key = doc.field_key(&db_state.secret_key)?;
+    let items: Vec<(&[u8], &Nonce)> = field_names
+        .iter()
+        .map(|field_name| {
+            let field_data = &doc.fields[*field_name];
+            (field_data.cipher.as_slice(), &field_data.nonce)
+        })
+        .collect();
+    Ok(field_names
+        .iter()
+        .zip(decrypt_many(&items, &key))
+        .map(|(field_name, plaintext)| {
+            let field_data = &doc.fields[*field_name];
+            let text = plaintext
+                .and_then(|plaintext| match field_data.pad_block {
+                    Some(block) => unpad(&plaintext, block),
+                    None => Ok(plaintext),
+                })
+                .context("Failed to decrypt field")
+                .and_then(|plaintext| {
+                    String::from_utf8(plaintext).context("Decrypted data is not valid UTF-8")
+                });
+            (field_name.to_string(), text, field_data.value_type)
+        })
+        .collect())
+}
+
+/// Documents in id order, skipping `offset` and returning at most `limit`
+pub fn handle_list(db: &InMemoryDB, limit: Option<usize>, offset: usize) -> ListResult {
+    let docs = db
+        .ids_page(offset, limit.unwrap_or(usize::MAX))
+        .iter()
+        .filter_map(|id| db.get(id).ok())
+        .collect();
+
+    ListResult {
+        total: db.len(),
+        docs,
+    }
+}
+
+pub fn handle_stats(db: &InMemoryDB, db_state: &DatabaseState) -> StatsReport {
+    let stats = db.stats();
+
+    StatsReport {
+        db_id: db_state.db_id.clone(),
+        namespace: db_state.namespace.clone(),
+        namespaces: db_state.namespace_names(),
+        documents: stats.document_count,
+        token_index_size: stats.token_index_size,
+        field_index_size: stats.field_index_size,
+        field_distribution: stats.field_distribution.into_iter().collect(),
+        version: db_state.version.clone(),
+        created_at: db_state.created_at.clone(),
+        last_modified: db_state.last_modified.clone(),
+        total_fields: stats.total_field_count,
+        cipher_bytes: stats.total_cipher_bytes,
+        nonce_bytes: stats.total_nonce_bytes,
+        token_algo: db_state.token_algo,
+        token_encoding: db_state.token_encoding,
+        nonce_counter: db_state.nonce_counter.as_ref().map(|counter| counter.next),
+        pad_block: db_state.pad_block,
+        mask_policy: db_state.mask_policy,
+        max_field_bytes: db_state.max_field_bytes,
+        document_keys: db_state.document_keys,
+        external_key: db_state.external_key,
+        token_filter_fp_rate: stats.token_filter_fp_rate,
+    }
+}
+
+/// Remove a document. With `soft`, it is kept in the file as a tombstone
+/// instead, hidden from every query until `handle_undelete` restores it or
+/// `handle_compact` purges it.
+pub fn handle_remove(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    id: &str,
+    soft: bool,
+    db_path: &str,
+) -> Result<RemoveOutcome> {
+    let Ok(doc) = db.get(id) else {
+        return Ok(RemoveOutcome::NotFound);
+    };
+    db.remove_if_exists(id);
+    let op = if soft {
+        let mut tombstone = (*doc).clone();
+        tombstone.deleted_at = Some(unix_millis());
+        WalOp::Upsert(tombstone)
+    } else {
+        WalOp::Remove(id.to_string())
+    };
+    db_state.persist(db_path, op)?;
+    Ok(RemoveOutcome::Removed)
+}
+
+/// Restore a document removed with `remove --soft`, as it was when removed
+pub fn handle_undelete(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    id: &str,
+    db_path: &str,
+) -> Result<()> {
+    let mut doc = db_state
+        .documents
+        .get(id)
+        .filter(|doc| doc.is_deleted())
+        .cloned()
+        .context(format!("No soft-deleted document '{}'", id))?;
+    doc.deleted_at = None;
+    db.upsert_unvalidated(doc.clone())?;
+    db_state.persist(db_path, WalOp::Upsert(doc))
+}
+
+/// Rebuild every index from the documents. Only in-memory indexes change;
+/// nothing is written.
+pub fn handle_reindex(db: &InMemoryDB) -> ReindexReport {
+    let before = db.stats();
+    db.rebuild_indexes();
+    let after = db.stats();
+
+    ReindexReport {
+        documents: after.document_count,
+        token_index_size: (before.token_index_size, after.token_index_size),
+        field_index_size: (before.field_index_size, after.field_index_size),
+    }
+}
+
+/// Drop index entries of documents that no longer exist, which only
+/// changes in-memory indexes, and permanently remove soft-deleted
+/// documents
+pub fn handle_compact(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    db_path: &str,
+) -> Result<CompactReport> {
+    let mut purged: Vec<String> = db_state
+        .documents
+        .values()
+        .filter(|doc| doc.is_deleted())
+        .map(|doc| doc.id.clone())
+        .collect();
+    purged.sort();
+    for id in &purged {
+        db_state.persist(db_path, WalOp::Remove(id.clone()))?;
+    }
+
+    Ok(CompactReport {
+        indexes: db.compact_indexes(),
+        purged,
+    })
+}
+
+/// What `handle_remove` would remove, without changing anything
+pub fn plan_remove(db: &InMemoryDB, id: &str) -> RemovalPlan {
+    let ids = if db.contains(id) {
+        vec![id.to_string()]
+    } else {
+        Vec::new()
+    };
+    plan_removal(db, ids)
+}
+
+/// What `handle_remove_value` would remove, without changing anything
+pub fn plan_remove_value(db: &InMemoryDB, db_state: &DatabaseState, value: &str) -> RemovalPlan {
+    let tok = token::tokenize(
+        db_state.token_algo,
+        db_state.token_encoding,
+        &db_state.secret_key,
+        value,
+    );
+    let ids = db
+        .query_by_token(&tok)
+        .into_iter()
+        .map(|doc| doc.id.clone())
+        .collect();
+    plan_removal(db, ids)
+}
+
+/// What `handle_clear` would remove, without changing anything
+pub fn plan_clear(db: &InMemoryDB) -> RemovalPlan {
+    let mut ids = db.all_ids();
+    ids.sort();
+    plan_removal(db, ids)
+}
+
+fn plan_removal(db: &InMemoryDB, ids: Vec<String>) -> RemovalPlan {
+    let impact = db.removal_impact(&ids);
+    let stats = db.stats();
+
+    RemovalPlan {
+        ids,
+        impact,
+        token_index_size: (
+            stats.token_index_size,
+            stats.token_index_size - impact.tokens,
+        ),
+        field_index_size: (
+            stats.field_index_size,
+            stats.field_index_size - impact.field_names,
+        ),
+    }
+}
+
+/// Remove every document with a field equal to `value`, returning their ids
+pub fn handle_remove_value(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    value: &str,
+    db_path: &str,
+) -> Result<Vec<String>> {
+    let tok = token::tokenize(
+        db_state.token_algo,
+        db_state.token_encoding,
+        &db_state.secret_key,
+        value,
+    );
+    let removed = db.remove_by_token(&tok);
+
+    for id in &removed {
+        db_state.persist(db_path, WalOp::Remove(id.clone()))?;
+    }
+
+    Ok(removed)
+}
+
+pub fn handle_rename(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    old: &str,
+    new: &str,
+    db_path: &str,
+) -> Result<()> {
+    db.rename(old, new)?;
+
+    let doc = db.get(new)?;
+    // Log the new id first so a crash in between duplicates rather than loses
+    db_state.persist(db_path, WalOp::Upsert((*doc).clone()))?;
+    db_state.persist(db_path, WalOp::Remove(old.to_string()))?;
+
+    Ok(())
+}
+
+/// Copy document `src` to the new id `dst`
+pub fn handle_copy(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    src: &str,
+    dst: &str,
+    db_path: &str,
+) -> Result<()> {
+    db.copy(src, dst)?;
+
+    let doc = db.get(dst)?;
+    db_state.persist(db_path, WalOp::Upsert((*doc).clone()))?;
+
+    Ok(())
+}
+
+/// Rename field `old` of document `id` to `new`, keeping its value
+pub fn handle_rename_field(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    id: &str,
+    old: &str,
+    new: &str,
+    db_path: &str,
+) -> Result<()> {
+    if old == new {
+        anyhow::bail!("Field '{}' already has that name", old);
+    }
+    db.rename_field(id, old, new)?;
+
+    let doc = db.get(id)?;
+    db_state.persist(db_path, WalOp::Upsert((*doc).clone()))?;
+
+    Ok(())
+}
+
+/// Write every document to `file`, returning how many were written
+pub fn handle_backup(db: &InMemoryDB, file: &str) -> Result<usize> {
+    let data = serde_json::to_string_pretty(db).context("Failed to serialize backup")?;
+    fs::write(file, data).context("Failed to write backup file")?;
+
+    Ok(db.len())
+}
+
+/// Copy the documents of the database at `file` into this one as a single
+/// transaction. Fields are re-encrypted when the databases' keys differ,
+/// which requires `reencrypt`.
+///
+/// `file` is opened read-only, with `source_key` when given and otherwise
+/// with the key it stores, and is never written to.
+pub fn handle_merge(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    file: &str,
+    source_key: Option<[u8; 32]>,
+    on_conflict: ConflictPolicy,
+    reencrypt: bool,
+    db_path: &str,
+) -> Result<MergeOutcome> {
+    let (other, _) = DatabaseState::load_readonly(file, source_key)?;
+
+    let same_key = other.secret_key == db_state.secret_key;
+    if !same_key && !reencrypt {
+        anyhow::bail!(
+            "{} uses a different key; pass --reencrypt to re-encrypt its fields with this database's key",
+            file
+        );
+    }
+    // Tokens are never mixed across algorithms or encodings, so fields from
+    // a database using others are re-tokenized, which means re-encrypting them
+    let retokenize =
+        other.token_algo != db_state.token_algo || other.token_encoding != db_state.token_encoding;
+    if retokenize && !reencrypt {
+        anyhow::bail!(
+            "{} uses {} tokens in {} but this database uses {} in {}; pass --reencrypt to re-tokenize its fields",
+            file,
+            other.token_algo,
+            other.token_encoding,
+            db_state.token_algo,
+            db_state.token_encoding
+        );
+    }
+
+    // Documents soft-deleted in the other database are not merged
+    let mut ids: Vec<&String> = other
+        .documents
+        .iter()
+        .filter(|(_, doc)| !doc.is_deleted())
+        .map(|(id, _)| id)
+        .collect();
+    ids.sort();
+
+    let (mut added, mut replaced, mut skipped) = (0, 0, 0);
+    let mut tx = db.transaction();
+    for id in ids {
+        let existing = db.get(id).ok();
+        if existing.is_some() && on_conflict == ConflictPolicy::Skip {
+            skipped += 1;
+            continue;
+        }
+
+        let other_doc = &other.documents[id];
+        let incoming_key = other_doc
+            .field_key(&other.secret_key)
+            .map_err(|e| anyhow::anyhow!("{}: {}; nothing was merged", id, e))?;
+        let (key, wrapped_key) = match &existing {
+            Some(existing) if on_conflict == ConflictPolicy::MergeFields => (
+                existing.field_key(&db_state.secret_key)?,
+                existing.wrapped_key.clone(),
+            ),
+            _ if same_key => (incoming_key, other_doc.wrapped_key.clone()),
+            _ => new_document_key(db_state)?,
+        };
+
+        let mut fields = HashMap::new();
+        for (name, field_data) in &other_doc.fields {
+            let field_data = if key == incoming_key && !retokenize {
+                field_data.clone()
+            } else {
+                reencrypt_field(db_state, &incoming_key, &key, field_data).map_err(|e| {
+                    anyhow::anyhow!(
+                        "Failed to re-encrypt {}.{}: {}; nothing was merged",
+                        id,
+                        name,
+                        e
+                    )
+                })?
+            };
+            fields.insert(name.clone(), field_data);
+        }
+
+        // Documents new to this database keep the creation time they had
+        // in the merged file
+        let created_at = existing
+            .as_ref()
+            .map_or(other_doc.created_at, |existing| existing.created_at);
+        let mut metadata = other_doc.metadata.clone();
+        let version = match existing {
+            Some(existing) => {
+                replaced += 1;
+                if on_conflict == ConflictPolicy::MergeFields {
+                    let mut merged = existing.fields.clone();
+                    merged.extend(fields);
+                    fields = merged;
+                    let mut merged = existing.metadata.clone();
+                    merged.extend(metadata);
+                    metadata = merged;
+                }
+                existing.version + 1
+            }
+            None => {
+                added += 1;
+                1
+            }
+        };
+
+        tx.upsert(DocumentStored {
+            id: id.clone(),
+            fields,
+            version,
+            wrapped_key,
+            created_at,
+            updated_at: Some(unix_millis()),
+            metadata,
+            deleted_at: None,
+        });
+    }
+
+    for id in tx.commit()? {
+        let doc = db.get(&id)?;
+        db_state.persist(db_path, WalOp::Upsert((*doc).clone()))?;
+    }
+
+    Ok(MergeOutcome {
+        added,
+        replaced,
+        skipped,
+    })
+}
+
+/// Decrypt a field stored under `old_key` and encrypt it again under
+/// `new_key` with the same options, tokenized with this database's key
+fn reencrypt_field(
+    db_state: &mut DatabaseState,
+    old_key: &[u8; 32],
+    new_key: &[u8; 32],
+    field_data: &FieldMaterialized,
+) -> Result<FieldMaterialized> {
+    let plaintext = decrypt_padded(
+        &field_data.cipher,
+        &field_data.nonce,
+        old_key,
+        field_data.pad_block,
+    )?;
+    let value = String::from_utf8(plaintext).context("Decrypted data is not valid UTF-8")?;
+    let mut reencrypted =
+        materialize_field(db_state, new_key, &value, FieldOptions::of(field_data))?;
+
+    for (_, cipher, nonce) in field_data.values().skip(1) {
+        let plaintext = decrypt_padded(cipher, nonce, old_key, field_data.pad_block)?;
+        let value = String::from_utf8(plaintext).context("Decrypted data is not valid UTF-8")?;
+        let extra = materialize_extra_value(db_state, new_key, &value, &reencrypted)?;
+        reencrypted.extra_values.push(extra);
+    }
+    Ok(reencrypted)
+}
+
+/// Replace all documents with those of a backup file, returning how many
+/// were restored.
+///
+/// Backups hold ciphertext only, so they must be restored into a database
+/// that uses the same key they were taken with. They hold no tombstones
+/// either, so every soft-deleted document is purged and can no longer be
+/// undeleted.
+pub fn handle_restore(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    file: &str,
+    db_path: &str,
+) -> Result<usize> {
+    let data = fs::read_to_string(file).context("Failed to read backup file")?;
+    let snapshot: DbSnapshot =
+        serde_json::from_str(&data).context("Failed to parse backup file")?;
+
+    let doc_count = snapshot.documents.len();
+    db.clear();
+    db.defer_indexing();
+    let loaded = snapshot
+        .documents
+        .iter()
+        .try_for_each(|doc| db.upsert(doc.clone()).map(drop));
+    db.build_indexes();
+    loaded?;
+    db_state.documents = snapshot
+        .documents
+        .into_iter()
+        .map(|doc| (doc.id.clone(), doc))
+        .collect();
+    db_state.save(db_path)?;
+
+    Ok(doc_count)
+}
+
+pub fn handle_gc(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    db_path: &str,
+) -> Result<GcOutcome> {
+    let docs_before = db.len();
+    let removed = db.sweep_expired(chrono::Utc::now().timestamp());
+
+    if removed > 0 {
+        db_state.documents.retain(|_, doc| doc.is_deleted());
+        db.for_each_document(|doc| {
+            db_state.documents.insert(doc.id.clone(), doc.clone());
+        });
+        db_state.save(db_path)?;
+    }
+
+    Ok(GcOutcome {
+        fields_removed: removed,
+        documents_removed: docs_before - db.len(),
+    })
+}
+
+/// Apply the batch of inserts and removes in `file` all or nothing,
+/// returning the number of operations applied
+pub fn handle_exec(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    file: &str,
+    db_path: &str,
+) -> Result<usize> {
+    let data = fs::read_to_string(file).context(format!("Failed to read batch file {}", file))?;
+    let ops: Vec<BatchOp> = serde_json::from_str(&data).context("Failed to parse batch file")?;
+
+    // Keys of documents created earlier in this batch, and ids it removed,
+    // so later operations see the batch's own changes
+    let mut new_keys: HashMap<&str, [u8; 32]> = HashMap::new();
+    let mut removed: HashSet<&str> = HashSet::new();
+
+    let mut tx = db.transaction();
+    for op in &ops {
+        match op {
+            BatchOp::Insert {
+                id,
+                field,
+                value,
+                prefix,
+                fuzzy,
+                deterministic,
+                ttl,
+                no_search,
+            } => {
+                let options = FieldOptions {
+                    prefix: *prefix,
+                    fuzzy: *fuzzy,
+                    deterministic: *deterministic,
+                    expires_at: expires_at(*ttl),
+                    no_search: *no_search,
+                    ..FieldOptions::default()
+                };
+                let existing_key = match new_keys.get(id.as_str()) {
+                    Some(key) => Some(*key),
+                    None if removed.contains(id.as_str()) => None,
+                    None => match db.get(id) {
+                        Ok(doc) => Some(doc.field_key(&db_state.secret_key)?),
+                        Err(_) => None,
+                    },
+                };
+
+                if let Some(key) = existing_key {
+                    let field_data = materialize_field(db_state, &key, value, options)?;
+                    tx.upsert_field(id, field, field_data);
+                } else {
+                    let (key, wrapped_key) = new_document_key(db_state)?;
+                    let field_data = materialize_field(db_state, &key, value, options)?;
+                    let mut doc = DocumentStored {
+                        id: id.clone(),
+                        fields: HashMap::from([(field.clone(), field_data)]),
+                        version: 1,
+                        wrapped_key,
+                        created_at: None,
+                        updated_at: None,
+                        metadata: HashMap::new(),
+                        deleted_at: None,
+                    };
+                    doc.touch(None);
+                    tx.upsert(doc);
+                    new_keys.insert(id, key);
+                    removed.remove(id.as_str());
+                }
+            }
+            BatchOp::Remove { id } => {
+                tx.remove(id);
+                new_keys.remove(id.as_str());
+                removed.insert(id);
+            }
+        }
+    }
+
+    let touched = tx
+        .commit()
+        .map_err(|e| anyhow::anyhow!("{}; no changes were applied", e))?;
+
+    for id in touched {
+        let op = match db.get(&id) {
+            Ok(doc) => WalOp::Upsert((*doc).clone()),
+            Err(_) => WalOp::Remove(id),
+        };
+        db_state.persist(db_path, op)?;
+    }
+
+    Ok(ops.len())
+}
+
+/// Decrypt every field, reporting those that fail
+pub fn handle_verify(db_state: &DatabaseState) -> VerifyReport {
+    VerifyReport {
+        fields: db_state
+            .documents
+            .values()
+            .map(|doc| doc.fields.len())
+            .sum(),
+        documents: db_state.documents.len(),
+        failures: verify_documents(db_state.documents.values(), &db_state.secret_key),
+    }
+}
+
+/// Check the loaded database: that its key matches the stored
+/// fingerprint, every field decrypts, the indexes hold every document and
+/// every document is stored under its own id
+pub fn handle_healthcheck(db: &InMemoryDB, db_state: &DatabaseState) -> HealthReport {
+    let misplaced = db_state
+        .documents
+        .iter()
+        .filter(|(key, doc)| **key != doc.id)
+        .map(|(key, doc)| (key.clone(), doc.id.clone()))
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    HealthReport {
+        key_error: verify_key_fingerprint(&db_state.secret_key, &db_state.key_fingerprint)
+            .err()
+            .map(|e| e.to_string()),
+        verify: handle_verify(db_state),
+        indexes: db.check_indexes(),
+        misplaced,
+    }
+}
+
+/// Rewrite the database file from the loaded state without changing any
+/// document, folding in the log and any database settings given on the
+/// command line. Returns how many documents were written.
+pub fn handle_touch(db_state: &mut DatabaseState, db_path: &str) -> Result<usize> {
+    db_state.save(db_path)?;
+    Ok(db_state.documents.len())
+}
+
+/// Replace the database key with `key` and rewrite the file. Nothing is
+/// re-encrypted, so documents already stored stay under the previous key
+/// and no longer decrypt; returns how many there are, in every namespace.
+/// Importing the key already in use changes nothing.
+pub fn handle_key_import(
+    db_state: &mut DatabaseState,
+    key: [u8; 32],
+    db_path: &str,
+) -> Result<usize> {
+    if db_state.external_key {
+        anyhow::bail!(
+            "The key comes from {}; change it there instead",
+            KEY_ENV_VAR
+        );
+    }
+    if key == db_state.secret_key {
+        return Ok(0);
+    }
+    db_state.secret_key = key;
+    db_state.key_fingerprint = key_fingerprint(&key);
+    db_state.save(db_path)?;
+    Ok(db_state.all_documents_mut().count())
+}
+
+/// Make the documents of `db` match `documents`: new and changed ones are
+/// stored and those no longer present removed, so change listeners see only
+/// what differs. A document counts as changed when its version or update
+/// time does.
+pub fn sync_documents(db: &InMemoryDB, documents: &HashMap<String, DocumentStored>) -> Result<()> {
+    for id in db.all_ids() {
+        if documents.get(&id).is_none_or(DocumentStored::is_deleted) {
+            db.remove_if_exists(&id);
+        }
+    }
+    for doc in documents.values().filter(|doc| !doc.is_deleted()) {
+        let changed = db.get(&doc.id).map_or(true, |stored| {
+            stored.version != doc.version || stored.updated_at != doc.updated_at
+        });
+        if changed {
+            db.upsert_unvalidated(doc.clone())?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove every document, returning how many live ones there were.
+/// Tombstones left by soft deletes are purged too but not counted.
+pub fn handle_clear(db: &InMemoryDB, db_state: &mut DatabaseState, db_path: &str) -> Result<usize> {
+    let doc_count = db.len();
+
+    db.clear();
+    db_state.persist(db_path, WalOp::Clear)?;
+
+    Ok(doc_count)
+}
@@ -0,0 +1,11 @@
+pub mod config;
+pub mod handlers;
+pub mod lock;
+pub mod migrate;
+pub mod state;
+
+pub use config::*;
+pub use handlers::*;
+pub use lock::*;
+pub use migrate::*;
+pub use state::*;
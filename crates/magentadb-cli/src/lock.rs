@@ -0,0 +1,84 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::ErrorKind;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+/// Exclusive advisory lock on a database, held through a lock file beside
+/// it for as long as this value lives. Only other MagentaDB processes honor
+/// it; the database file itself is never locked.
+pub struct DbLock {
+    path: String,
+    _file: File,
+}
+
+impl DbLock {
+    /// Lock the database at `db_path`, failing at once rather than waiting
+    /// when another process holds the lock
+    #[cfg(unix)]
+    pub fn acquire(db_path: &str) -> Result<Self> {
+        let path = lock_path(db_path);
+        loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .context(format!("Failed to open lock file {}", path))?;
+            // SAFETY: the descriptor stays open for the duration of the call
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == ErrorKind::WouldBlock {
+                    anyhow::bail!(
+                        "database is locked by another process: {} (pass --no-lock to open it anyway)",
+                        db_path
+                    );
+                }
+                return Err(err).context(format!("Failed to lock {}", path));
+            }
+            // The previous holder removes the file when it lets go, so the
+            // lock only counts if it is on the file still at that path
+            let held = file.metadata()?;
+            match fs::metadata(&path) {
+                Ok(meta) if (meta.dev(), meta.ino()) == (held.dev(), held.ino()) => {
+                    return Ok(Self { path, _file: file })
+                }
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context(format!("Failed to read lock file {}", path)),
+            }
+        }
+    }
+
+    /// Lock the database at `db_path` by creating its lock file, failing at
+    /// once when it already exists. Without `flock` a process that dies
+    /// holding the lock leaves the file behind, and it must be removed by
+    /// hand.
+    #[cfg(not(unix))]
+    pub fn acquire(db_path: &str) -> Result<Self> {
+        let path = lock_path(db_path);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => Ok(Self { path, _file: file }),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => anyhow::bail!(
+                "database is locked by another process: {} (pass --no-lock to open it anyway, or remove {} if no process holds it)",
+                db_path,
+                path
+            ),
+            Err(e) => Err(e).context(format!("Failed to create lock file {}", path)),
+        }
+    }
+}
+
+impl Drop for DbLock {
+    /// Remove the lock file while still holding the lock, so no process can
+    /// lock it in between; the lock itself is released when the file closes
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn lock_path(db_path: &str) -> String {
+    format!("{}.lock", db_path)
+}
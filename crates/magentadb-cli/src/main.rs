@@ -1,29 +1,122 @@
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use rand::Rng;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fs;
-
+use std::io::{IsTerminal, Read, Write};
+
+use magentadb_cli::{
+    expires_at, handle_append, handle_backup, handle_clear, handle_compact, handle_copy,
+    handle_count_field, handle_count_value, handle_decrypt, handle_decrypt_all, handle_exec,
+    handle_exists, handle_fields, handle_gc, handle_grep, handle_healthcheck, handle_insert,
+    handle_insert_fields, handle_key_import, handle_list, handle_merge, handle_query,
+    handle_query_field, handle_query_fuzzy, handle_query_ids, handle_query_length,
+    handle_query_meta, handle_query_prefix, handle_query_range, handle_query_ranked,
+    handle_query_token, handle_reindex, handle_remove, handle_remove_value, handle_rename,
+    handle_rename_field, handle_restore, handle_set_meta, handle_show, handle_stats, handle_token,
+    handle_touch, handle_undelete, handle_update, handle_verify, key_from_env, plan_clear,
+    plan_remove, plan_remove_value, sync_documents, Config, ConflictPolicy, DatabaseState, DbLock,
+    FieldOptions, InsertOutcome, ListResult, LoadOutcome, NonceCounter, QueryHit, QueryResult,
+    RemoveOutcome, StatsReport, DEFAULT_DATABASE, DEFAULT_MAX_FIELD_BYTES, DEFAULT_NAMESPACE,
+    IN_MEMORY_PATH, KEY_ENV_VAR,
+};
 use magentadb_core::{
-    db::InMemoryDB,
-    document::{DocumentStored, FieldMaterialized},
+    db::{ChangeEvent, InMemoryDB},
+    document::{DocumentStored, MaskPolicy, ValueType},
+    wal::WalConfig,
+};
+use magentadb_crypto::{
+    key_to_base64, parse_key_base64, parse_key_hex, tokens_equal, TokenAlgo, TokenEncoding,
+    MAX_PAD_BLOCK,
 };
-use magentadb_crypto::{decrypt, encrypt, token};
 
 #[derive(Parser)]
 #[command(name = "magentadb")]
 #[command(about = "A searchable encrypted database")]
 #[command(version = "0.1.0")]
 struct Cli {
-    /// Database file path
-    #[arg(short, long, default_value = "magentadb.json")]
-    database: String,
+    /// Database file path, or `:memory:` for a database that is never
+    /// written to disk [default: magentadb.json]
+    #[arg(short, long)]
+    database: Option<String>,
+
+    /// Run against an empty in-memory database with a fresh key, never
+    /// reading or writing the database file
+    #[arg(long)]
+    no_persist: bool,
+
+    /// Open the database without taking its lock, even while another
+    /// process holds it
+    #[arg(long)]
+    no_lock: bool,
+
+    /// Namespace within the database file to work in. Namespaces share the
+    /// key but hold separate documents and indexes
+    #[arg(long, default_value = DEFAULT_NAMESPACE, value_parser = parse_namespace)]
+    ns: String,
 
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
 
+    /// Use counter-based nonces (random per-database prefix + counter) for new writes
+    #[arg(long)]
+    counter_nonces: bool,
+
+    /// Encrypt each new document under its own data key, wrapped by the database key
+    #[arg(long)]
+    document_keys: bool,
+
+    /// Keyed hash for tokens (sha256 or sha512); only takes effect when the
+    /// database is created
+    #[arg(long)]
+    token_algo: Option<TokenAlgo>,
+
+    /// How tokens are written out (hex or base64url); only takes effect
+    /// when the database is created
+    #[arg(long)]
+    token_encoding: Option<TokenEncoding>,
+
+    /// Pad values to a multiple of this many bytes before encrypting new writes
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=MAX_PAD_BLOCK as i64))]
+    pad: Option<u16>,
+
+    /// Check token lookups against an in-memory Bloom filter first, so
+    /// misses skip the token index
+    #[arg(long)]
+    token_filter: bool,
+
+    /// Reject values larger than this many bytes. Stored with the database
+    /// (default: 16 MiB)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_field_bytes: Option<u64>,
+
+    /// How much of new values their masked form reveals: none, length-hint
+    /// or first:N. Stored with the database; existing values keep their mask
+    #[arg(long)]
+    mask: Option<MaskPolicy>,
+
+    /// Fold the write-ahead log into the database file after this many writes
+    #[arg(long, default_value_t = WalConfig::default().compaction_threshold)]
+    compact_after: usize,
+
+    /// Report what remove, remove-value or clear would delete without
+    /// changing the database
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Output format for commands that print results [default: human]
+    #[arg(long, value_enum)]
+    output: Option<OutputFormat>,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+/// A single command entered in the REPL
+#[derive(Parser)]
+#[command(name = "magentadb")]
+struct ReplLine {
     #[command(subcommand)]
     command: Commands,
 }
@@ -31,12 +124,57 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Insert a new document or update an existing one
-    Insert {
+    Insert(InsertArgs),
+
+    /// Insert a numeric field that can be searched with `query-range`
+    InsertNum {
+        /// Document ID
+        id: String,
+        /// Field name
+        field: String,
+        /// Numeric value to encrypt
+        #[arg(allow_negative_numbers = true)]
+        number: f64,
+    },
+
+    /// Insert a boolean field, decrypted as true or false
+    InsertBool {
+        /// Document ID
+        id: String,
+        /// Field name
+        field: String,
+        /// Value to encrypt: true or false
+        #[arg(action = clap::ArgAction::Set)]
+        value: bool,
+    },
+
+    /// Update an existing field, failing if the document or field is missing
+    Update {
+        /// Document ID
+        id: String,
+        /// Field name
+        field: String,
+        /// New field value to encrypt
+        value: String,
+    },
+
+    /// Add another value to an existing field, each value searchable on its own
+    Append {
         /// Document ID
         id: String,
         /// Field name
         field: String,
-        /// Field value to encrypt
+        /// Value to encrypt and add
+        value: String,
+    },
+
+    /// Set a plaintext metadata label on an existing document (not encrypted)
+    SetMeta {
+        /// Document ID
+        id: String,
+        /// Metadata key
+        key: String,
+        /// Metadata value, stored as-is
         value: String,
     },
 
@@ -46,22 +184,151 @@ enum Commands {
         id: String,
     },
 
+    /// Check whether a document exists, exiting non-zero if it does not
+    Exists {
+        /// Document ID
+        id: String,
+    },
+
     /// Query documents by plaintext value
     Query {
         /// Value to search for
+        #[arg(required_unless_present_any = ["meta", "any", "token_raw"])]
+        value: Option<String>,
+
+        /// Only match the value in this field
+        #[arg(long)]
+        field: Option<String>,
+
+        /// Decrypt matched fields and drop those whose token merely collides
+        #[arg(long)]
+        exact: bool,
+
+        /// Only match documents whose metadata KEY equals VALUE; may be repeated
+        #[arg(long, value_name = "KEY=VALUE", value_parser = parse_meta)]
+        meta: Vec<(String, String)>,
+
+        /// Return at most this many documents; which ones is unspecified
+        /// when more match
+        #[arg(long, requires = "value")]
+        limit: Option<usize>,
+
+        /// Print only the matching IDs, one per line, read straight from the
+        /// index without loading any document
+        #[arg(long, requires = "value", conflicts_with_all = ["field", "exact", "meta", "limit"])]
+        ids_only: bool,
+
+        /// Match documents holding any of these values, ranked by how many
+        /// of them they hold
+        #[arg(
+            long,
+            num_args = 1..,
+            value_name = "VALUE",
+            conflicts_with_all = ["value", "field", "exact", "meta", "limit", "ids_only"]
+        )]
+        any: Vec<String>,
+
+        /// Search for a token printed by `token` instead of a value, e.g. one
+        /// computed on another machine with the same key
+        #[arg(
+            long,
+            value_name = "TOKEN",
+            conflicts_with_all = ["value", "exact", "limit", "ids_only", "any"]
+        )]
+        token_raw: Option<String>,
+    },
+
+    /// Query documents whose prefix-indexed values start with a prefix
+    QueryPrefix {
+        /// Prefix to search for (at least 3 characters)
+        prefix: String,
+    },
+
+    /// Query documents whose fuzzy-indexed field resembles a value, ranked
+    /// by how many trigrams they share
+    QueryFuzzy {
+        /// Field name to search
+        field: String,
+        /// Value to search for; typos are tolerated
+        value: String,
+        /// Fewest trigrams a match must share with the value
+        #[arg(long, default_value_t = 3)]
+        min_overlap: usize,
+    },
+
+    /// Query documents whose numeric field lies within an inclusive range
+    QueryRange {
+        /// Numeric field name
+        field: String,
+        /// Lower bound (inclusive)
+        #[arg(long, allow_negative_numbers = true)]
+        min: Option<f64>,
+        /// Upper bound (inclusive)
+        #[arg(long, allow_negative_numbers = true)]
+        max: Option<f64>,
+    },
+
+    /// Query documents that contain a field name
+    QueryField {
+        /// Field name to search for
+        name: String,
+    },
+
+    /// Query documents whose field value is exactly this many bytes long
+    QueryLength {
+        /// Field name
+        field: String,
+        /// Value length in bytes
+        len: usize,
+    },
+
+    /// Decrypt values in memory and list those containing a pattern
+    Grep {
+        /// Substring to search for; `^` and `$` anchor it, as in `^john@`
+        pattern: String,
+
+        /// Only scan this field
+        #[arg(long)]
+        field: Option<String>,
+    },
+
+    /// List the distinct field names used in the database
+    Fields,
+
+    /// Count matching documents without listing them
+    Count {
+        /// Plaintext value to count matches for
+        #[arg(long, conflicts_with = "field", required_unless_present = "field")]
+        token: Option<String>,
+        /// Field name to count documents for
+        #[arg(long)]
+        field: Option<String>,
+    },
+
+    /// Print the search token of a value without storing anything
+    Token {
+        /// Plaintext value to tokenize
         value: String,
     },
 
     /// Decrypt a specific field in a document
-    Decrypt {
+    Decrypt(DecryptArgs),
+
+    /// Decrypt every field in a document
+    DecryptAll {
         /// Document ID
         id: String,
-        /// Field name to decrypt
-        field: String,
     },
 
     /// List all documents in the database
-    List,
+    List {
+        /// Show at most this many documents
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Skip this many documents (in id order) before listing
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+    },
 
     /// Show database statistics
     Stats,
@@ -70,356 +337,2094 @@ enum Commands {
     Remove {
         /// Document ID
         id: String,
-    },
-
-    /// Clear the entire database
-    Clear {
-        /// Skip confirmation prompt
+        /// Keep the document as a tombstone, hidden from queries, that
+        /// `undelete` restores and `compact` purges
         #[arg(long)]
-        force: bool,
+        soft: bool,
     },
-}
-
-/// Database state for persistence
-#[derive(Serialize, Deserialize)]
-struct DatabaseState {
-    documents: HashMap<String, DocumentStored>,
-    secret_key: [u8; 32],
-    version: String,
-    created_at: String,
-    last_modified: String,
-}
 
-impl DatabaseState {
-    fn load_or_create(path: &str) -> Result<Self> {
-        if let Ok(data) = fs::read_to_string(path) {
-            let mut state: DatabaseState =
-                serde_json::from_str(&data).context("Failed to parse database file")?;
+    /// Restore a document removed with `remove --soft`
+    Undelete {
+        /// Document ID
+        id: String,
+    },
 
-            // Update last accessed time
-            state.last_modified = chrono::Utc::now().to_rfc3339();
+    /// Remove every document with a field equal to a plaintext value
+    RemoveValue {
+        /// Value to match
+        value: String,
+    },
 
-            println!("✓ Loaded existing database from {}", path);
-            println!(
-                "  └─ {} documents, created {}",
-                state.documents.len(),
-                state.created_at
-            );
+    /// Copy a document to a new ID, e.g. to use it as a template
+    Copy {
+        /// ID of the document to copy
+        src: String,
+        /// ID of the new copy
+        dst: String,
+    },
 
-            Ok(state)
-        } else {
-            println!("📄 Creating new database at {}", path);
-            let now = chrono::Utc::now().to_rfc3339();
+    /// Rename a document ID, keeping its fields and indexes
+    Rename {
+        /// Current document ID
+        old: String,
+        /// New document ID
+        new: String,
+    },
 
-            Ok(Self {
-                documents: HashMap::new(),
-                secret_key: rand::thread_rng().gen(),
-                version: env!("CARGO_PKG_VERSION").to_string(),
-                created_at: now.clone(),
-                last_modified: now,
-            })
-        }
-    }
+    /// Rename a field of a document, keeping its value and tokens
+    RenameField {
+        /// Document ID
+        id: String,
+        /// Current field name
+        old: String,
+        /// New field name
+        new: String,
+    },
 
-    fn save(&mut self, path: &str) -> Result<()> {
-        self.last_modified = chrono::Utc::now().to_rfc3339();
+    /// Write a point-in-time copy of all documents to a file
+    Backup {
+        /// Backup file path
+        file: String,
+    },
 
-        let data = serde_json::to_string_pretty(self).context("Failed to serialize database")?;
+    /// Copy the documents of another database file into this one
+    Merge {
+        /// Database file to merge from
+        file: String,
+        /// What to do with a document id present in both databases
+        #[arg(long, value_enum, default_value_t = ConflictPolicy::Skip)]
+        on_conflict: ConflictPolicy,
+        /// Re-encrypt fields with this database's key when the keys or token algorithms differ
+        #[arg(long)]
+        reencrypt: bool,
+        /// Key of the database merged from, as 64 hex digits; defaults to
+        /// MAGENTADB_KEY when set, else the key that database stores
+        #[arg(long, value_parser = parse_key_arg)]
+        source_key: Option<[u8; 32]>,
+    },
 
-        fs::write(path, data).context("Failed to write database file")?;
+    /// Replace all documents with those from a backup file, purging
+    /// soft-deleted ones
+    Restore {
+        /// Backup file path
+        file: String,
+    },
 
-        Ok(())
-    }
-}
+    /// Apply a JSON batch of inserts and removes, all or nothing
+    Exec {
+        /// Batch file: a JSON array of {"op": "insert" | "remove", ...}
+        file: String,
+    },
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+    /// Rebuild the token and field indexes from the documents
+    Reindex,
 
-    if cli.verbose {
-        println!("🔧 MagentaDB v{}", env!("CARGO_PKG_VERSION"));
-        println!("📂 Database: {}", cli.database);
-    }
+    /// Drop index entries that point at documents which no longer exist
+    /// and purge soft-deleted documents
+    Compact,
 
-    let mut db_state = DatabaseState::load_or_create(&cli.database)?;
-    let db = InMemoryDB::new();
+    /// Remove expired fields and documents left empty by them
+    Gc,
 
-    // Load existing documents into the in-memory DB
-    for doc in db_state.documents.values() {
-        db.upsert(doc.clone())
-            .context(format!("Failed to load document {}", doc.id))?;
-    }
+    /// Check the key, every field and the indexes, exiting non-zero if
+    /// anything is inconsistent
+    Healthcheck,
 
-    let result = match &cli.command {
-        Commands::Insert { id, field, value } => handle_insert(
-            &db,
-            &mut db_state,
-            id,
-            field,
-            value,
-            &cli.database,
-            cli.verbose,
-        ),
+    /// Check that every field decrypts, reporting any that fail
+    Verify,
 
-        Commands::Show { id } => handle_show(&db, id, cli.verbose),
+    /// Rewrite the database file in the current format without changing
+    /// any document, applying database options such as --pad or --mask
+    Touch,
 
-        Commands::Query { value } => handle_query(&db, &db_state, value, cli.verbose),
+    /// Print a line for every document inserted, updated or removed by
+    /// other processes until interrupted
+    Watch {
+        /// Milliseconds between checks of the database file
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+    },
 
-        Commands::Decrypt { id, field } => handle_decrypt(&db, &db_state, id, field),
+    /// Start an interactive session that keeps the database loaded
+    Repl,
 
-        Commands::List => handle_list(&db, cli.verbose),
+    /// Export or replace the database key
+    Key {
+        #[command(subcommand)]
+        action: KeyAction,
+    },
 
-        Commands::Stats => handle_stats(&db, &db_state),
+    /// Clear the entire database
+    Clear {
+        /// Skip confirmation prompt
+        #[arg(long)]
+        force: bool,
+        /// Confirm by typing the database file name or ID instead of y/N
+        #[arg(long, conflicts_with = "force")]
+        confirm_name: bool,
+    },
+}
 
-        Commands::Remove { id } => handle_remove(&db, &mut db_state, id, &cli.database),
+#[derive(Subcommand)]
+enum KeyAction {
+    /// Print the database key, as base64 unless --hex is given
+    Export {
+        /// Print the key as 64 hex digits
+        #[arg(long)]
+        hex: bool,
+        /// Skip confirmation prompt
+        #[arg(long)]
+        force: bool,
+    },
 
-        Commands::Clear { force } => handle_clear(&db, &mut db_state, &cli.database, *force),
-    };
+    /// Replace the database key. Nothing is re-encrypted: documents already
+    /// stored no longer decrypt
+    Import {
+        /// New key, as base64 unless --hex is given; must decode to 32 bytes
+        key: String,
+        /// Read the key as 64 hex digits
+        #[arg(long)]
+        hex: bool,
+        /// Skip confirmation prompt
+        #[arg(long)]
+        force: bool,
+    },
+}
 
-    if let Err(e) = result {
-        eprintln!(" Error: {}", e);
-        std::process::exit(1);
-    }
+/// Smallest number of tokens the token filter is sized for, leaving room
+/// for the writes of a session on a small database
+const MIN_FILTER_TOKENS: usize = 1024;
 
-    Ok(())
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Human,
+    Json,
 }
 
-fn handle_insert(
-    db: &InMemoryDB,
-    db_state: &mut DatabaseState,
-    id: &str,
-    field: &str,
-    value: &str,
-    db_path: &str,
-    verbose: bool,
-) -> Result<()> {
-    let (nonce, cipher) = encrypt(value.as_bytes(), &db_state.secret_key);
-    let tok = token::tokenize(&db_state.secret_key, value);
+/// One document matched by `query`
+#[derive(Serialize)]
+struct QueryMatch {
+    id: String,
+    matched_fields: Vec<String>,
+}
 
-    let masked = if value.len() >= 2 && tok.len() >= 6 {
-        format!("{}…{}", &value.chars().next().unwrap(), &tok[0..6])
-    } else if !value.is_empty() {
-        format!("{}…", &value.chars().next().unwrap())
-    } else {
-        "…".to_string()
-    };
+impl From<&QueryHit> for QueryMatch {
+    fn from(hit: &QueryHit) -> Self {
+        Self {
+            id: hit.doc.id.clone(),
+            matched_fields: hit.fields.clone(),
+        }
+    }
+}
 
-    // Check if document exists and merge fields
-    let mut fields = if let Ok(existing_doc) = db.get(id) {
-        existing_doc
-            .fields
-            .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect()
-    } else {
-        HashMap::new()
-    };
+/// One document matched by `query --any` or `query-fuzzy`, with its score
+#[derive(Serialize)]
+struct RankedMatch {
+    id: String,
+    score: usize,
+}
 
-    fields.insert(
-        field.to_string(),
-        FieldMaterialized {
-            cipher,
-            nonce,
-            token: tok.clone(),
-            masked: masked.clone(),
-        },
-    );
+impl From<&(std::sync::Arc<DocumentStored>, usize)> for RankedMatch {
+    fn from((doc, score): &(std::sync::Arc<DocumentStored>, usize)) -> Self {
+        Self {
+            id: doc.id.clone(),
+            score: *score,
+        }
+    }
+}
 
-    let doc = DocumentStored {
-        id: id.to_string(),
-        fields,
-    };
+/// A document as reported by `show`
+#[derive(Serialize)]
+struct DocumentOutput {
+    id: String,
+    version: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<i64>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
+    fields: BTreeMap<String, FieldOutput>,
+}
 
-    db.upsert(doc.clone())?;
-    db_state.documents.insert(id.to_string(), doc);
-    db_state.save(db_path)?;
+#[derive(Serialize)]
+struct FieldOutput {
+    masked: String,
+    token: String,
+    cipher_bytes: usize,
+    nonce_bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+}
 
-    if verbose {
-        println!("📝 Inserted field '{}' in document '{}'", field, id);
-        println!("   └─ Token: {}, Masked: {}", tok, masked);
-    } else {
-        println!("✓ Inserted document '{}'", id);
+impl From<&DocumentStored> for DocumentOutput {
+    fn from(doc: &DocumentStored) -> Self {
+        Self {
+            id: doc.id.clone(),
+            version: doc.version,
+            created_at: doc.created_at,
+            updated_at: doc.updated_at,
+            metadata: doc
+                .metadata
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            fields: doc
+                .fields
+                .iter()
+                .map(|(name, field_data)| {
+                    let field = FieldOutput {
+                        masked: field_data.masked.clone(),
+                        token: field_data.token.clone(),
+                        cipher_bytes: field_data.cipher.len(),
+                        nonce_bytes: field_data.nonce.len(),
+                        expires_at: field_data.expires_at,
+                    };
+                    (name.clone(), field)
+                })
+                .collect(),
+        }
     }
+}
+
+/// One document as reported by `list`
+#[derive(Serialize)]
+struct ListEntry {
+    id: String,
+    fields: Vec<String>,
+}
 
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).context("Failed to serialize output")?
+    );
     Ok(())
 }
 
-fn handle_show(db: &InMemoryDB, id: &str, verbose: bool) -> Result<()> {
-    match db.get(id) {
-        Ok(doc) => {
-            println!("📄 Document: {}", id);
-            for (field_name, field_data) in &doc.fields {
-                println!("   {}: {}", field_name, field_data.masked);
-                if verbose {
-                    println!("     └─ Token: {}", field_data.token);
-                    println!("     └─ Cipher size: {} bytes", field_data.cipher.len());
-                    println!("     └─ Nonce size: {} bytes", field_data.nonce.len());
-                }
-            }
-            Ok(())
-        }
-        Err(_) => {
-            println!(" Document '{}' not found", id);
-            Ok(())
-        }
-    }
+#[derive(Args)]
+struct InsertArgs {
+    /// Document ID
+    id: String,
+    /// Field name
+    #[arg(required_unless_present = "set")]
+    field: Option<String>,
+    /// Field value to encrypt. Without it (or --value-stdin/--value-file),
+    /// the value is prompted for when stdin is a terminal
+    value: Option<String>,
+    /// Read the value from stdin, dropping one trailing newline
+    #[arg(long, requires = "field", conflicts_with_all = ["value", "value_file"])]
+    value_stdin: bool,
+    /// Read the value verbatim from this file
+    #[arg(long, requires = "field", conflicts_with = "value")]
+    value_file: Option<String>,
+    /// Also set FIELD to VALUE; may be repeated to write several fields at once
+    #[arg(long, value_name = "FIELD=VALUE", value_parser = parse_assignment)]
+    set: Vec<(String, String)>,
+    /// Also index prefixes of the value for `query-prefix`
+    #[arg(long)]
+    prefix: bool,
+    /// Also index trigrams of the value for `query-fuzzy`
+    #[arg(long)]
+    fuzzy: bool,
+    /// Encrypt so identical values produce identical ciphertext (leaks equality)
+    #[arg(long)]
+    deterministic: bool,
+    /// Expire the field after this many seconds
+    #[arg(long)]
+    ttl: Option<u64>,
+    /// Store the value without any token, so value queries never match it
+    #[arg(long, conflicts_with_all = ["prefix", "fuzzy"])]
+    no_search: bool,
 }
 
-fn handle_query(
-    db: &InMemoryDB,
-    db_state: &DatabaseState,
-    value: &str,
-    verbose: bool,
-) -> Result<()> {
-    let tok = token::tokenize(&db_state.secret_key, value);
-    let results = db.query_by_token(&tok);
+impl InsertArgs {
+    /// The positional field followed by every `--set` pair, in order
+    fn assignments(&self) -> Result<Vec<(String, String)>> {
+        let mut assignments = Vec::with_capacity(self.set.len() + 1);
+        if let Some(field) = &self.field {
+            assignments.push((field.clone(), self.value_of(field)?));
+        }
+        assignments.extend(self.set.iter().cloned());
+        Ok(assignments)
+    }
 
-    if results.is_empty() {
-        println!("🔍 No documents found matching '{}'", value);
-        if verbose {
-            println!("   └─ Search token: {}", tok);
+    /// Value of the positional field, from the argument itself, stdin, a
+    /// file, or a prompt that does not echo what is typed
+    fn value_of(&self, field: &str) -> Result<String> {
+        if let Some(value) = &self.value {
+            return Ok(value.clone());
         }
-    } else {
-        println!(
-            "🔍 Found {} document(s) matching '{}':",
-            results.len(),
-            value
-        );
-        for doc in results {
-            println!("   📄 {}", doc.id);
-            for (field_name, field_data) in &doc.fields {
-                if field_data.token == tok {
-                    println!("      └─ {}: {}", field_name, field_data.masked);
+        if let Some(path) = &self.value_file {
+            let data = fs::read(path).context(format!("Failed to read {}", path))?;
+            return String::from_utf8(data)
+                .context(format!("Value file {} is not valid UTF-8", path));
+        }
+
+        let stdin = std::io::stdin();
+        if self.value_stdin {
+            let mut value = String::new();
+            stdin
+                .lock()
+                .read_to_string(&mut value)
+                .context("Failed to read the value from stdin")?;
+            if value.ends_with('\n') {
+                value.pop();
+                if value.ends_with('\r') {
+                    value.pop();
                 }
             }
+            return Ok(value);
+        }
+        if !stdin.is_terminal() {
+            anyhow::bail!(
+                "No value given for '{}'; pass it as an argument, --value-stdin or --value-file",
+                field
+            );
         }
+        read_hidden(&format!("🔑 Value for '{}': ", field))
     }
 
-    Ok(())
+    fn field_options(&self) -> FieldOptions {
+        FieldOptions {
+            prefix: self.prefix,
+            fuzzy: self.fuzzy,
+            deterministic: self.deterministic,
+            expires_at: expires_at(self.ttl),
+            no_search: self.no_search,
+            ..FieldOptions::default()
+        }
+    }
 }
 
-fn handle_decrypt(db: &InMemoryDB, db_state: &DatabaseState, id: &str, field: &str) -> Result<()> {
-    let doc = db.get(id).context(format!("Document '{}' not found", id))?;
-
-    let field_data = doc
-        .fields
-        .get(field)
-        .context(format!("Field '{}' not found in document '{}'", field, id))?;
-
-    let plaintext = decrypt(&field_data.cipher, &field_data.nonce, &db_state.secret_key)
-        .context("Failed to decrypt field")?;
+/// Returned by a query that matched nothing, so the process exits non-zero
+/// like `grep` does without reporting an error
+#[derive(Debug)]
+struct NoMatches;
 
-    let text = String::from_utf8(plaintext).context("Decrypted data is not valid UTF-8")?;
+impl std::fmt::Display for NoMatches {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No documents matched")
+    }
+}
 
-    println!("🔓 Decrypted {}.{}: {}", id, field, text);
+impl std::error::Error for NoMatches {}
 
+fn any_matches<T>(hits: &[T]) -> Result<()> {
+    if hits.is_empty() {
+        return Err(NoMatches.into());
+    }
     Ok(())
 }
 
-fn handle_list(db: &InMemoryDB, verbose: bool) -> Result<()> {
-    let all_docs = db.all_ids();
-
-    if all_docs.is_empty() {
-        println!("📭 No documents in database");
-        return Ok(());
+fn parse_namespace(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Err("namespace name cannot be empty".to_string());
     }
+    Ok(s.to_string())
+}
 
-    println!("📋 Database contains {} document(s):", all_docs.len());
+fn parse_key_arg(s: &str) -> Result<[u8; 32], String> {
+    parse_key_hex(s).map_err(|e| e.to_string())
+}
 
-    for doc_id in all_docs {
-        let doc = db.get(&doc_id)?;
-        let field_count = doc.fields.len();
-        let field_names: Vec<String> = doc.fields.keys().cloned().collect();
+fn parse_meta(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", s)),
+    }
+}
 
-        println!(
-            "   📄 {} ({} field{})",
-            doc_id,
-            field_count,
-            if field_count == 1 { "" } else { "s" }
-        );
+/// Metadata filters as `key=value` pairs joined by commas
+fn format_meta(filters: &[(String, String)]) -> String {
+    filters
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
-        if verbose {
-            for (field_name, field_data) in &doc.fields {
-                println!(
-                    "      └─ {}: {} [{}]",
-                    field_name, field_data.masked, field_data.token
-                );
-            }
-        } else {
-            println!("      └─ Fields: [{}]", field_names.join(", "));
-        }
+fn parse_assignment(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((field, value)) if !field.is_empty() => Ok((field.to_string(), value.to_string())),
+        _ => Err(format!("expected FIELD=VALUE, got '{}'", s)),
     }
-
-    Ok(())
 }
 
-fn handle_stats(db: &InMemoryDB, db_state: &DatabaseState) -> Result<()> {
-    let stats = db.stats();
+#[derive(Args)]
+struct DecryptArgs {
+    /// Document ID
+    id: String,
+    /// Field name to decrypt
+    field: String,
+    /// Print the decrypted bytes as hex instead of text
+    #[arg(long, conflicts_with = "out")]
+    hex: bool,
+    /// Write the raw decrypted bytes to this file instead of printing them
+    #[arg(long)]
+    out: Option<String>,
+    /// Write only the decrypted bytes to stdout, with no message or newline,
+    /// for piping into another program
+    #[arg(long, conflicts_with_all = ["hex", "out"])]
+    raw: bool,
+    /// Decrypt with this key, as 64 hex digits, instead of the database's,
+    /// e.g. to recover data encrypted under a previous key
+    #[arg(long, value_name = "HEX", value_parser = parse_key_arg)]
+    key: Option<[u8; 32]>,
+}
 
-    println!(" Database Statistics:");
-    println!("   Documents: {}", stats.document_count);
-    println!("   Token index size: {}", stats.token_index_size);
-    println!("   Field index size: {}", stats.field_index_size);
-    println!("   Version: {}", db_state.version);
-    println!("   Created: {}", db_state.created_at);
-    println!("   Last modified: {}", db_state.last_modified);
-
-    // Calculate total fields
-    let total_fields: usize = db_state
-        .documents
-        .values()
-        .map(|doc| doc.fields.len())
-        .sum();
-    println!("   Total fields: {}", total_fields);
+fn main() -> Result<()> {
+    let cli = Cli::parse();
 
-    Ok(())
-}
+    // Flags take precedence over the config file, which takes precedence
+    // over the built-in defaults
+    let (config, config_path) = Config::load()?;
+    let database = cli
+        .database
+        .clone()
+        .or(config.database)
+        .unwrap_or_else(|| DEFAULT_DATABASE.to_string());
+    let verbose = cli.verbose || config.verbose.unwrap_or(false);
+    let output = match (cli.output, config.output) {
+        (Some(output), _) => output,
+        (None, Some(name)) => OutputFormat::from_str(&name, true).map_err(|_| {
+            anyhow::anyhow!(
+                "Invalid output format '{}' in config file; expected human or json",
+                name
+            )
+        })?,
+        (None, None) => OutputFormat::Human,
+    };
 
-fn handle_remove(
-    db: &InMemoryDB,
-    db_state: &mut DatabaseState,
-    id: &str,
-    db_path: &str,
-) -> Result<()> {
-    match db.remove(id) {
-        Ok(_) => {
-            db_state.documents.remove(id);
-            db_state.save(db_path)?;
-            println!("  Removed document '{}'", id);
-            Ok(())
-        }
-        Err(_) => {
-            println!(" Document '{}' not found", id);
-            Ok(())
+    let json = output == OutputFormat::Json;
+    // Status lines would corrupt JSON output or raw decrypted bytes
+    let quiet = json || matches!(&cli.command, Commands::Decrypt(args) if args.raw);
+    if verbose && !quiet {
+        println!("🔧 MagentaDB v{}", env!("CARGO_PKG_VERSION"));
+        if let Some(path) = &config_path {
+            println!("⚙️  Config: {}", path.display());
+        }
+        println!("📂 Database: {}", database);
+    }
+
+    let wal_config = WalConfig {
+        compaction_threshold: cli.compact_after,
+    };
+    let in_memory = cli.no_persist || database == IN_MEMORY_PATH;
+    // Holding the lock for as long as `watch` runs would shut out every
+    // writer it is meant to observe, so it goes without and only ever
+    // loads the database read-only
+    let read_only = matches!(cli.command, Commands::Watch { .. });
+    let lock = if cli.no_lock || in_memory || read_only {
+        None
+    } else {
+        Some(DbLock::acquire(&database)?)
+    };
+    let (mut db_state, loaded) = if in_memory {
+        (
+            DatabaseState::in_memory(key_from_env()?),
+            LoadOutcome::Created,
+        )
+    } else if read_only {
+        DatabaseState::load_readonly(&database, key_from_env()?)?
+    } else {
+        DatabaseState::load_or_create(&database, wal_config, key_from_env()?)?
+    };
+    db_state.use_namespace(&cli.ns);
+    if !quiet {
+        print_load(&database, &db_state, loaded);
+    }
+    if let Some(algo) = cli.token_algo {
+        match loaded {
+            LoadOutcome::Created => db_state.token_algo = algo,
+            LoadOutcome::Loaded { .. } if db_state.token_algo != algo => {
+                anyhow::bail!(
+                    "{} uses {} tokens; the token algorithm cannot be changed after creation",
+                    database,
+                    db_state.token_algo
+                );
+            }
+            LoadOutcome::Loaded { .. } => {}
+        }
+    }
+    if let Some(encoding) = cli.token_encoding {
+        match loaded {
+            LoadOutcome::Created => db_state.token_encoding = encoding,
+            LoadOutcome::Loaded { .. } if db_state.token_encoding != encoding => {
+                anyhow::bail!(
+                    "{} writes tokens in {}; the token encoding cannot be changed after creation",
+                    database,
+                    db_state.token_encoding
+                );
+            }
+            LoadOutcome::Loaded { .. } => {}
+        }
+    }
+    if cli.counter_nonces && db_state.nonce_counter.is_none() {
+        db_state.nonce_counter = Some(NonceCounter::new());
+        db_state.snapshot_pending = true;
+    }
+    if cli.document_keys && !db_state.document_keys {
+        db_state.document_keys = true;
+        db_state.snapshot_pending = true;
+    }
+    if let Some(limit) = cli.max_field_bytes {
+        let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+        if db_state.max_field_bytes != limit {
+            db_state.max_field_bytes = limit;
+            db_state.snapshot_pending = true;
+        }
+    }
+    if let Some(policy) = cli.mask {
+        if db_state.mask_policy != policy {
+            db_state.mask_policy = policy;
+            db_state.snapshot_pending = true;
+        }
+    }
+    if let Some(block) = cli.pad.map(usize::from) {
+        if db_state.pad_block != Some(block) {
+            db_state.pad_block = Some(block);
+            db_state.snapshot_pending = true;
+        }
+    }
+    let mut builder = InMemoryDB::builder().with_capacity(db_state.documents.len());
+    if cli.token_filter {
+        let tokens: usize = db_state
+            .documents
+            .values()
+            .filter(|doc| !doc.is_deleted())
+            .flat_map(|doc| doc.fields.values())
+            .map(|field_data| field_data.tokens().count())
+            .sum();
+        builder = builder.with_token_filter(tokens.max(MIN_FILTER_TOKENS));
+    }
+    let db = builder.build();
+
+    // Load existing documents into the in-memory DB, indexing them once
+    // at the end rather than on every upsert. Soft-deleted ones stay out.
+    db.defer_indexing();
+    for doc in db_state.documents.values().filter(|doc| !doc.is_deleted()) {
+        db.upsert_unvalidated(doc.clone())
+            .context(format!("Failed to load document {}", doc.id))?;
+    }
+    db.build_indexes();
+
+    let result = if cli.dry_run {
+        dry_run(&cli.command, &db, &db_state)
+    } else {
+        run_command(&cli.command, &db, &mut db_state, &database, verbose, output)
+    };
+
+    if let Err(e) = result {
+        if !e.is::<NoMatches>() {
+            eprintln!(" Error: {}", e);
+        }
+        // Exiting skips destructors, so the lock file is removed here
+        drop(lock);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn print_load(path: &str, db_state: &DatabaseState, loaded: LoadOutcome) {
+    match loaded {
+        LoadOutcome::Created if db_state.in_memory => {
+            println!("🧪 Using an in-memory database; nothing will be written to disk")
+        }
+        LoadOutcome::Created => println!("📄 Creating new database at {}", path),
+        LoadOutcome::Loaded { replayed, migrated } => {
+            let deleted = db_state
+                .documents
+                .values()
+                .filter(|doc| doc.is_deleted())
+                .count();
+            println!("✓ Loaded existing database from {}", path);
+            println!(
+                "  └─ {} documents, created {}",
+                db_state.documents.len() - deleted,
+                db_state.created_at
+            );
+            if deleted > 0 {
+                println!("  └─ {} soft-deleted document(s)", deleted);
+            }
+            if replayed > 0 {
+                println!("  └─ replayed {} logged operation(s)", replayed);
+            }
+            if migrated {
+                println!("  └─ upgraded to format version {}", db_state.version);
+            }
+            if db_state.namespace != DEFAULT_NAMESPACE {
+                println!("  └─ namespace '{}'", db_state.namespace);
+            }
         }
     }
 }
 
-fn handle_clear(
+/// Run one command and print its result
+fn run_command(
+    command: &Commands,
     db: &InMemoryDB,
     db_state: &mut DatabaseState,
     db_path: &str,
-    force: bool,
+    verbose: bool,
+    output: OutputFormat,
 ) -> Result<()> {
-    if !force {
-        print!("⚠️  This will delete all documents. Are you sure? (y/N): ");
-        std::io::Write::flush(&mut std::io::stdout())?;
+    let json = output == OutputFormat::Json;
+    match command {
+        Commands::Insert(args) => {
+            let fields = args.assignments()?;
+            let outcomes = handle_insert_fields(
+                db,
+                db_state,
+                &args.id,
+                &fields,
+                args.field_options(),
+                db_path,
+            )?;
+            if verbose {
+                for ((field, _), outcome) in fields.iter().zip(&outcomes) {
+                    print_insert(&args.id, field, outcome);
+                }
+            } else {
+                println!("✓ Inserted document '{}'", args.id);
+            }
+        }
+
+        Commands::InsertNum { id, field, number } => {
+            let options = FieldOptions {
+                numeric: true,
+                value_type: ValueType::Number,
+                ..FieldOptions::default()
+            };
+            handle_insert(
+                db,
+                db_state,
+                id,
+                field,
+                &number.to_string(),
+                options,
+                db_path,
+            )?;
+            println!("✓ Inserted document '{}'", id);
+        }
+
+        Commands::InsertBool { id, field, value } => {
+            let options = FieldOptions {
+                value_type: ValueType::Bool,
+                ..FieldOptions::default()
+            };
+            handle_insert(
+                db,
+                db_state,
+                id,
+                field,
+                &value.to_string(),
+                options,
+                db_path,
+            )?;
+            println!("✓ Inserted document '{}'", id);
+        }
+
+        Commands::Update { id, field, value } => {
+            handle_update(db, db_state, id, field, value, db_path)?;
+            println!("✓ Updated field '{}' in document '{}'", field, id);
+        }
+
+        Commands::Append { id, field, value } => {
+            let count = handle_append(db, db_state, id, field, value, db_path)?;
+            println!(
+                "✓ Appended to field '{}' in document '{}' ({} values)",
+                field, id, count
+            );
+        }
+
+        Commands::SetMeta { id, key, value } => {
+            match handle_set_meta(db, db_state, id, key, value, db_path)? {
+                Some(_) => println!("✓ Updated metadata '{}' on document '{}'", key, id),
+                None => println!("✓ Set metadata '{}' on document '{}'", key, id),
+            }
+        }
+
+        Commands::Show { id } => {
+            let doc = handle_show(db, id);
+            if json {
+                return print_json(&doc.as_deref().map(DocumentOutput::from));
+            }
+            match doc {
+                Some(doc) => print_document(&doc, verbose),
+                None => println!(" Document '{}' not found", id),
+            }
+        }
+
+        Commands::Exists { id } => {
+            if handle_exists(db, id) {
+                println!("yes");
+            } else {
+                println!("no");
+                anyhow::bail!("Document '{}' not found", id);
+            }
+        }
+
+        Commands::Query {
+            value,
+            field,
+            exact,
+            meta,
+            limit,
+            ids_only,
+            any,
+            token_raw,
+        } => {
+            if !any.is_empty() {
+                let ranked = handle_query_ranked(db, db_state, any);
+                // Repeating a value does not raise any score
+                let values = any.iter().collect::<std::collections::BTreeSet<_>>().len();
+                if json {
+                    let matches: Vec<RankedMatch> = ranked.iter().map(RankedMatch::from).collect();
+                    print_json(&matches)?;
+                } else if ranked.is_empty() {
+                    println!("🔍 No documents found matching any of {} value(s)", values);
+                } else {
+                    println!(
+                        "🔍 Found {} document(s) matching any of {} value(s):",
+                        ranked.len(),
+                        values
+                    );
+                    for (doc, score) in &ranked {
+                        println!("   📄 {} ({}/{})", doc.id, score, values);
+                    }
+                }
+                return any_matches(&ranked);
+            }
+            if let (true, Some(value)) = (*ids_only, value) {
+                let ids = handle_query_ids(db, db_state, value);
+                if json {
+                    print_json(&ids)?;
+                } else {
+                    ids.iter().for_each(|id| println!("{}", id));
+                }
+                return any_matches(&ids);
+            }
+            if let Some(token) = token_raw {
+                let result = handle_query_token(db, db_state, token, field.as_deref(), meta)?;
+                if json {
+                    let matches: Vec<QueryMatch> =
+                        result.hits.iter().map(QueryMatch::from).collect();
+                    print_json(&matches)?;
+                } else {
+                    print_query(token, &result, verbose);
+                }
+                return any_matches(&result.hits);
+            }
+            let Some(value) = value else {
+                let hits = handle_query_meta(db, meta);
+                if json {
+                    let matches: Vec<QueryMatch> = hits.iter().map(QueryMatch::from).collect();
+                    print_json(&matches)?;
+                } else if hits.is_empty() {
+                    println!("🔍 No documents found with metadata {}", format_meta(meta));
+                } else {
+                    println!(
+                        "🔍 Found {} document(s) with metadata {}:",
+                        hits.len(),
+                        format_meta(meta)
+                    );
+                    print_hits(&hits);
+                }
+                return any_matches(&hits);
+            };
+
+            let result = handle_query(db, db_state, value, field.as_deref(), *exact, meta, *limit);
+            if json {
+                let matches: Vec<QueryMatch> = result.hits.iter().map(QueryMatch::from).collect();
+                print_json(&matches)?;
+            } else {
+                print_query(value, &result, verbose);
+            }
+            return any_matches(&result.hits);
+        }
+
+        Commands::QueryPrefix { prefix } => {
+            let docs = handle_query_prefix(db, db_state, prefix)?;
+            if docs.is_empty() {
+                println!("🔍 No documents found with prefix '{}'", prefix);
+            } else {
+                println!(
+                    "🔍 Found {} document(s) with prefix '{}':",
+                    docs.len(),
+                    prefix
+                );
+                print_ids(&docs);
+            }
+        }
+
+        Commands::QueryFuzzy {
+            field,
+            value,
+            min_overlap,
+        } => {
+            let ranked = handle_query_fuzzy(db, db_state, field, value, *min_overlap);
+            if json {
+                let matches: Vec<RankedMatch> = ranked.iter().map(RankedMatch::from).collect();
+                print_json(&matches)?;
+            } else if ranked.is_empty() {
+                println!("🔍 No documents found with {} like '{}'", field, value);
+            } else {
+                println!(
+                    "🔍 Found {} document(s) with {} like '{}':",
+                    ranked.len(),
+                    field,
+                    value
+                );
+                for (doc, score) in &ranked {
+                    println!("   📄 {} ({} shared trigrams)", doc.id, score);
+                }
+            }
+        }
+
+        Commands::QueryRange { field, min, max } => {
+            let (min, max) = (min.unwrap_or(f64::MIN), max.unwrap_or(f64::MAX));
+            let docs = handle_query_range(db, db_state, field, min, max);
+            if docs.is_empty() {
+                println!("🔍 No documents found with {} in [{}, {}]", field, min, max);
+            } else {
+                println!(
+                    "🔍 Found {} document(s) with {} in [{}, {}]:",
+                    docs.len(),
+                    field,
+                    min,
+                    max
+                );
+                print_ids(&docs);
+            }
+        }
+
+        Commands::QueryField { name } => {
+            let docs = handle_query_field(db, name);
+            if docs.is_empty() {
+                println!("🔍 No documents found with field '{}'", name);
+            } else {
+                println!("🔍 Found {} document(s) with field '{}':", docs.len(), name);
+                for doc in docs {
+                    println!("   📄 {}", doc.id);
+                    if verbose {
+                        if let Some(field_data) = doc.fields.get(name) {
+                            println!("      └─ {}: {}", name, field_data.masked);
+                        }
+                    }
+                }
+            }
+        }
+
+        Commands::QueryLength { field, len } => {
+            let docs = handle_query_length(db, field, *len);
+            if docs.is_empty() {
+                println!("🔍 No documents found with {} of {} byte(s)", field, len);
+            } else {
+                println!(
+                    "🔍 Found {} document(s) with {} of {} byte(s):",
+                    docs.len(),
+                    field,
+                    len
+                );
+                print_ids(&docs);
+            }
+        }
+
+        Commands::Grep { pattern, field } => {
+            let hits = handle_grep(db, db_state, pattern, field.as_deref())?;
+            if json {
+                let matches: Vec<QueryMatch> = hits.iter().map(QueryMatch::from).collect();
+                return print_json(&matches);
+            }
+            if hits.is_empty() {
+                println!("🔍 No documents found matching /{}/", pattern);
+            } else {
+                println!(
+                    "🔍 Found {} document(s) matching /{}/:",
+                    hits.len(),
+                    pattern
+                );
+                print_hits(&hits);
+            }
+        }
+
+        Commands::Fields => {
+            let fields = handle_fields(db);
+            if fields.is_empty() {
+                println!("📭 No fields in database");
+            } else {
+                println!("🏷️  {} distinct field(s):", fields.len());
+                for (name, count) in fields {
+                    println!("   {} ({} document(s))", name, count);
+                }
+            }
+        }
+
+        Commands::Count { token, field } => {
+            if let Some(value) = token {
+                println!(
+                    "🔢 {} document(s) matching '{}'",
+                    handle_count_value(db, db_state, value),
+                    value
+                );
+            } else if let Some(field) = field {
+                println!(
+                    "🔢 {} document(s) with field '{}'",
+                    handle_count_field(db, field),
+                    field
+                );
+            }
+        }
+
+        Commands::Token { value } => {
+            let token = handle_token(db_state, value);
+            if json {
+                return print_json(&token);
+            }
+            println!("🔑 Token for '{}': {}", value, token);
+        }
+
+        Commands::Decrypt(args) => {
+            let (plaintext, value_type) =
+                handle_decrypt(db, db_state, &args.id, &args.field, args.key.as_ref())?;
+            print_decrypted(args, plaintext, value_type, json)?
+        }
+
+        Commands::DecryptAll { id } => {
+            let fields = handle_decrypt_all(db, db_state, id)?;
+            println!("🔓 Decrypted {}:", id);
+
+            let mut failures = 0;
+            for (field_name, text, value_type) in fields {
+                match text {
+                    Ok(text) => println!("   {}: {}{}", field_name, text, type_suffix(value_type)),
+                    Err(e) => {
+                        failures += 1;
+                        println!("   {}: ⚠️  {:#}", field_name, e);
+                    }
+                }
+            }
+
+            if failures > 0 {
+                anyhow::bail!("{} field(s) in '{}' could not be decrypted", failures, id);
+            }
+        }
+
+        Commands::List { limit, offset } => {
+            let page = handle_list(db, *limit, *offset);
+            if json {
+                let entries: Vec<ListEntry> = page
+                    .docs
+                    .iter()
+                    .map(|doc| {
+                        let mut fields: Vec<String> = doc.fields.keys().cloned().collect();
+                        fields.sort();
+                        ListEntry {
+                            id: doc.id.clone(),
+                            fields,
+                        }
+                    })
+                    .collect();
+                return print_json(&entries);
+            }
+            print_list(&page, limit.is_none() && *offset == 0, *offset, verbose);
+        }
+
+        Commands::Stats => {
+            let stats = handle_stats(db, db_state);
+            if json {
+                return print_json(&stats);
+            }
+            print_stats(&stats);
+        }
+
+        Commands::Remove { id, soft } => match handle_remove(db, db_state, id, *soft, db_path)? {
+            RemoveOutcome::Removed if *soft => println!(
+                "  Soft-deleted document '{}' (undelete restores it, compact purges it)",
+                id
+            ),
+            RemoveOutcome::Removed => println!("  Removed document '{}'", id),
+            RemoveOutcome::NotFound => anyhow::bail!("Document '{}' not found", id),
+        },
+
+        Commands::Undelete { id } => {
+            handle_undelete(db, db_state, id, db_path)?;
+            println!("✓ Restored document '{}'", id);
+        }
+
+        Commands::RemoveValue { value } => {
+            let removed = handle_remove_value(db, db_state, value, db_path)?;
+            println!(
+                "  Removed {} document(s) matching '{}'",
+                removed.len(),
+                value
+            );
+        }
+
+        Commands::Copy { src, dst } => {
+            handle_copy(db, db_state, src, dst, db_path)?;
+            println!("✓ Copied document '{}' to '{}'", src, dst);
+        }
+
+        Commands::Rename { old, new } => {
+            handle_rename(db, db_state, old, new, db_path)?;
+            println!("✓ Renamed document '{}' to '{}'", old, new);
+        }
+
+        Commands::RenameField { id, old, new } => {
+            handle_rename_field(db, db_state, id, old, new, db_path)?;
+            println!(
+                "✓ Renamed field '{}' to '{}' in document '{}'",
+                old, new, id
+            );
+        }
+
+        Commands::Backup { file } => {
+            let count = handle_backup(db, file)?;
+            println!("💾 Backed up {} document(s) to {}", count, file);
+        }
+
+        Commands::Merge {
+            file,
+            on_conflict,
+            reencrypt,
+            source_key,
+        } => {
+            let source_key = match source_key {
+                Some(key) => Some(*key),
+                None => key_from_env()?,
+            };
+            let outcome = handle_merge(
+                db,
+                db_state,
+                file,
+                source_key,
+                *on_conflict,
+                *reencrypt,
+                db_path,
+            )?;
+            println!(
+                "🔀 Merged {}: {} added, {} {}, {} skipped",
+                file,
+                outcome.added,
+                outcome.replaced,
+                if *on_conflict == ConflictPolicy::MergeFields {
+                    "merged"
+                } else {
+                    "overwritten"
+                },
+                outcome.skipped
+            );
+        }
+
+        Commands::Restore { file } => {
+            let count = handle_restore(db, db_state, file, db_path)?;
+            println!("♻️  Restored {} document(s) from {}", count, file);
+        }
+
+        Commands::Exec { file } => {
+            let count = handle_exec(db, db_state, file, db_path)?;
+            println!("✓ Applied {} operation(s) from {}", count, file);
+        }
+
+        Commands::Gc => {
+            let outcome = handle_gc(db, db_state, db_path)?;
+            println!(
+                "🧹 Removed {} expired field(s), {} empty document(s)",
+                outcome.fields_removed, outcome.documents_removed
+            );
+        }
+
+        Commands::Reindex => {
+            let report = handle_reindex(db);
+            println!("🔧 Rebuilt indexes from {} document(s)", report.documents);
+            println!(
+                "   Token index size: {} → {}",
+                report.token_index_size.0, report.token_index_size.1
+            );
+            println!(
+                "   Field index size: {} → {}",
+                report.field_index_size.0, report.field_index_size.1
+            );
+        }
+
+        Commands::Compact => {
+            let report = handle_compact(db, db_state, db_path)?;
+            let compaction = &report.indexes;
+            println!(
+                "🧹 Removed {} dangling index entr{}",
+                compaction.total(),
+                if compaction.total() == 1 { "y" } else { "ies" }
+            );
+            println!("   Token index: {}", compaction.token_ids);
+            println!("   Field index: {}", compaction.field_ids);
+            println!("   Range index: {}", compaction.range_ids);
+            println!(
+                "🗑️  Purged {} soft-deleted document(s)",
+                report.purged.len()
+            );
+            for id in &report.purged {
+                println!("   📄 {}", id);
+            }
+        }
+
+        Commands::Verify => {
+            let report = handle_verify(db_state);
+            if report.failures.is_empty() {
+                println!(
+                    "✓ Verified {} field(s) in {} document(s)",
+                    report.fields, report.documents
+                );
+                return Ok(());
+            }
+
+            for failure in &report.failures {
+                println!("   ⚠️  {}.{}: {}", failure.id, failure.field, failure.error);
+            }
+            anyhow::bail!(
+                "{} of {} field(s) failed verification",
+                report.failures.len(),
+                report.fields
+            );
+        }
+
+        Commands::Healthcheck => {
+            let report = handle_healthcheck(db, db_state);
+            println!("🩺 Health check of {}", db_path);
+            println!(
+                "   ✓ Opened {} document(s); integrity tag verified",
+                db_state.documents.len()
+            );
+            match &report.key_error {
+                None => println!("   ✓ Key matches its fingerprint"),
+                Some(error) => println!("   ✗ Key: {}", error),
+            }
+            if report.verify.failures.is_empty() {
+                println!("   ✓ All {} field(s) decrypt", report.verify.fields);
+            } else {
+                println!(
+                    "   ✗ {} of {} field(s) do not decrypt",
+                    report.verify.failures.len(),
+                    report.verify.fields
+                );
+                for failure in &report.verify.failures {
+                    println!(
+                        "      └─ {}.{}: {}",
+                        failure.id, failure.field, failure.error
+                    );
+                }
+            }
+            let indexes = report.indexes;
+            if indexes.is_consistent() {
+                println!("   ✓ Indexes cover every document");
+            } else {
+                println!(
+                    "   ✗ Indexes are missing {} token, {} field and {} range entr{}",
+                    indexes.missing_tokens,
+                    indexes.missing_fields,
+                    indexes.missing_ranges,
+                    if indexes.missing() == 1 { "y" } else { "ies" }
+                );
+            }
+            if indexes.stale > 0 {
+                println!(
+                    "   ⚠️  {} stale index entr{} (reindex drops them)",
+                    indexes.stale,
+                    if indexes.stale == 1 { "y" } else { "ies" }
+                );
+            }
+            if report.misplaced.is_empty() {
+                println!("   ✓ Every document is stored under its id");
+            } else {
+                for (key, id) in &report.misplaced {
+                    println!("   ✗ Document stored under '{}' has id '{}'", key, id);
+                }
+            }
+
+            if report.problems() > 0 {
+                anyhow::bail!(
+                    "Health check failed: {} problem(s) found",
+                    report.problems()
+                );
+            }
+            println!("✓ Healthy");
+        }
+
+        Commands::Touch => {
+            if db_state.in_memory {
+                anyhow::bail!("An in-memory database has no file to rewrite");
+            }
+            let count = handle_touch(db_state, db_path)?;
+            println!("💾 Rewrote {} ({} document(s))", db_path, count);
+        }
+
+        Commands::Clear {
+            force,
+            confirm_name,
+        } => {
+            if *confirm_name {
+                let prompt = format!(
+                    "⚠️  This will delete all documents. Type the database name ({}) or ID to confirm: ",
+                    db_path
+                );
+                if !confirm_typed(&prompt, &[db_path, &db_state.db_id])? {
+                    anyhow::bail!("Confirmation did not match; nothing was cleared");
+                }
+            } else if !*force
+                && !confirm("⚠️  This will delete all documents. Are you sure? (y/N): ")?
+            {
+                println!("Operation cancelled");
+                return Ok(());
+            }
+            let count = handle_clear(db, db_state, db_path)?;
+            println!("🧹 Cleared database ({} documents removed)", count);
+        }
 
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input)?;
+        Commands::Key {
+            action: KeyAction::Export { hex, force },
+        } => {
+            if !*force
+                && !confirm(
+                    "⚠️  This prints the key, which decrypts every document. Continue? (y/N): ",
+                )?
+            {
+                println!("Operation cancelled");
+                return Ok(());
+            }
+            let key = if *hex {
+                hex::encode(db_state.secret_key)
+            } else {
+                key_to_base64(&db_state.secret_key)
+            };
+            if json {
+                print_json(&key)?;
+            } else {
+                println!("{}", key);
+            }
+        }
 
-        if !input.trim().to_lowercase().starts_with('y') {
-            println!("Operation cancelled");
-            return Ok(());
+        Commands::Key {
+            action: KeyAction::Import { key, hex, force },
+        } => {
+            let key = if *hex {
+                parse_key_hex(key)
+            } else {
+                parse_key_base64(key)
+            }?;
+            if !*force
+                && !confirm("⚠️  Documents encrypted under the current key will no longer decrypt until they are re-encrypted. Replace the key? (y/N): ")?
+            {
+                println!("Operation cancelled");
+                return Ok(());
+            }
+            let stranded = handle_key_import(db_state, key, db_path)?;
+            println!("🔑 Imported key ({})", &db_state.key_fingerprint[..16]);
+            if stranded > 0 {
+                println!(
+                    "⚠️  {} document(s) were encrypted under the previous key and must be re-encrypted",
+                    stranded
+                );
+            }
         }
+
+        Commands::Watch { interval } => {
+            if db_state.in_memory {
+                anyhow::bail!("An in-memory database has no file to watch");
+            }
+            handle_watch(db, db_path, &db_state.namespace, *interval)?
+        }
+
+        Commands::Repl => handle_repl(db, db_state, db_path, verbose, output)?,
     }
 
-    let doc_count = db_state.documents.len();
+    Ok(())
+}
 
-    db.clear();
-    db_state.documents.clear();
-    db_state.save(db_path)?;
+/// Print what a destructive command would remove, leaving both the
+/// database and its file untouched
+fn dry_run(command: &Commands, db: &InMemoryDB, db_state: &DatabaseState) -> Result<()> {
+    let plan = match command {
+        Commands::Remove { id, .. } => {
+            let plan = plan_remove(db, id);
+            if plan.ids.is_empty() {
+                anyhow::bail!("Document '{}' not found", id);
+            }
+            plan
+        }
+        Commands::RemoveValue { value } => plan_remove_value(db, db_state, value),
+        Commands::Clear { .. } => plan_clear(db),
+        _ => anyhow::bail!("--dry-run only applies to remove, remove-value and clear"),
+    };
+
+    println!(
+        "🔎 Dry run: would remove {} document(s)",
+        plan.impact.documents
+    );
+    for id in &plan.ids {
+        println!("   📄 {}", id);
+    }
+    println!("   Fields removed: {}", plan.impact.fields);
+    println!(
+        "   Token index size: {} → {}",
+        plan.token_index_size.0, plan.token_index_size.1
+    );
+    println!(
+        "   Field index size: {} → {}",
+        plan.field_index_size.0, plan.field_index_size.1
+    );
+    println!("   Nothing was changed");
+
+    Ok(())
+}
+
+/// Verbose report of one written field
+fn print_insert(id: &str, field: &str, outcome: &InsertOutcome) {
+    match outcome {
+        InsertOutcome::Updated {
+            field: stored,
+            previous,
+        } => {
+            println!("📝 Updated field '{}' in document '{}'", field, id);
+            if previous.searchable && stored.searchable {
+                println!(
+                    "   └─ Token changed: {}",
+                    if tokens_equal(&previous.token, &stored.token) {
+                        "no"
+                    } else {
+                        "yes"
+                    }
+                );
+            }
+        }
+        InsertOutcome::Inserted { .. } => {
+            println!("📝 Inserted field '{}' in document '{}'", field, id)
+        }
+    }
+    let stored = outcome.field();
+    if stored.searchable {
+        println!("   └─ Token: {}, Masked: {}", stored.token, stored.masked);
+    } else {
+        println!("   └─ Not searchable, Masked: {}", stored.masked);
+    }
+}
+
+fn print_document(doc: &DocumentStored, verbose: bool) {
+    println!("📄 Document: {}", doc.id);
+    println!("   Created: {}", format_millis(doc.created_at));
+    println!("   Updated: {}", format_millis(doc.updated_at));
+    if !doc.metadata.is_empty() {
+        let metadata: BTreeMap<&String, &String> = doc.metadata.iter().collect();
+        let labels: Vec<String> = metadata
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        println!("   Metadata: {}", labels.join(", "));
+    }
+    if verbose {
+        println!("   Version: {}", doc.version);
+    }
+    for (field_name, field_data) in &doc.fields {
+        match field_data.value_count() {
+            1 => println!("   {}: {}", field_name, field_data.masked),
+            count => println!(
+                "   {}: {} (+{} more values)",
+                field_name,
+                field_data.masked,
+                count - 1
+            ),
+        }
+        if verbose {
+            if field_data.searchable {
+                println!("     └─ Token: {}", field_data.token);
+            } else {
+                println!("     └─ Not searchable");
+            }
+            println!("     └─ Cipher size: {} bytes", field_data.cipher.len());
+            println!("     └─ Nonce size: {} bytes", field_data.nonce.len());
+            if let Some(expires_at) = field_data.expires_at {
+                println!("     └─ Expires at: {}", expires_at);
+            }
+        }
+    }
+}
+
+/// RFC 3339 form of a document timestamp in unix milliseconds
+fn format_millis(millis: Option<i64>) -> String {
+    millis
+        .and_then(chrono::DateTime::from_timestamp_millis)
+        .map_or_else(|| "unknown".to_string(), |time| time.to_rfc3339())
+}
+
+fn print_query(value: &str, result: &QueryResult, verbose: bool) {
+    if result.dropped > 0 {
+        println!(
+            "⚠️  Dropped {} document(s) whose token collided with '{}'",
+            result.dropped, value
+        );
+    }
+
+    if result.hits.is_empty() {
+        println!("🔍 No documents found matching '{}'", value);
+        if verbose {
+            println!("   └─ Search token: {}", result.token);
+        }
+    } else {
+        println!(
+            "🔍 Found {} document(s) matching '{}':",
+            result.hits.len(),
+            value
+        );
+        print_hits(&result.hits);
+    }
+}
 
-    println!("🧹 Cleared database ({} documents removed)", doc_count);
+fn print_ids(docs: &[std::sync::Arc<DocumentStored>]) {
+    for doc in docs {
+        println!("   📄 {}", doc.id);
+    }
+}
+
+fn print_hits(hits: &[QueryHit]) {
+    for hit in hits {
+        println!("   📄 {}", hit.doc.id);
+        for field_name in &hit.fields {
+            println!(
+                "      └─ {}: {}",
+                field_name, hit.doc.fields[field_name].masked
+            );
+        }
+    }
+}
+
+fn print_decrypted(
+    args: &DecryptArgs,
+    plaintext: Vec<u8>,
+    value_type: ValueType,
+    json: bool,
+) -> Result<()> {
+    let (id, field) = (&args.id, &args.field);
+    if args.raw {
+        let mut stdout = std::io::stdout().lock();
+        stdout
+            .write_all(&plaintext)
+            .and_then(|()| stdout.flush())
+            .context("Failed to write to stdout")?;
+    } else if let Some(out) = &args.out {
+        fs::write(out, &plaintext).context(format!("Failed to write {}", out))?;
+        println!(
+            "🔓 Decrypted {}.{}: wrote {} to {}",
+            id,
+            field,
+            format_bytes(plaintext.len()),
+            out
+        );
+    } else if args.hex {
+        println!("🔓 Decrypted {}.{}: {}", id, field, hex::encode(&plaintext));
+    } else {
+        let text = String::from_utf8(plaintext)
+            .context("Decrypted data is not valid UTF-8; use --hex or --out")?;
+        if json {
+            return print_json(&typed_value(&text, value_type));
+        }
+        println!(
+            "🔓 Decrypted {}.{}: {}{}",
+            id,
+            field,
+            text,
+            type_suffix(value_type)
+        );
+    }
 
     Ok(())
 }
+
+/// A decrypted value as JSON of its type: numbers and booleans as JSON
+/// numbers and booleans, anything else as a string
+fn typed_value(text: &str, value_type: ValueType) -> serde_json::Value {
+    let typed = match value_type {
+        ValueType::Number => text
+            .parse::<i64>()
+            .map(serde_json::Number::from)
+            .ok()
+            .or_else(|| text.parse().ok().and_then(serde_json::Number::from_f64))
+            .map(serde_json::Value::Number),
+        ValueType::Bool => text.parse().ok().map(serde_json::Value::Bool),
+        ValueType::String => None,
+    };
+    typed.unwrap_or_else(|| serde_json::Value::String(text.to_string()))
+}
+
+/// Marks values that are not plain strings with their type
+fn type_suffix(value_type: ValueType) -> String {
+    if value_type.is_string() {
+        String::new()
+    } else {
+        format!(" ({})", value_type)
+    }
+}
+
+fn print_list(page: &ListResult, whole: bool, offset: usize, verbose: bool) {
+    if page.total == 0 {
+        println!("📭 No documents in database");
+        return;
+    }
+
+    if whole {
+        println!("📋 Database contains {} document(s):", page.total);
+    } else if page.docs.is_empty() {
+        println!(
+            "📋 Database contains {} document(s), none at offset {}",
+            page.total, offset
+        );
+    } else {
+        println!(
+            "📋 Database contains {} document(s), showing {}-{}:",
+            page.total,
+            offset + 1,
+            offset + page.docs.len()
+        );
+    }
+
+    for doc in &page.docs {
+        let field_count = doc.fields.len();
+        let field_names: Vec<String> = doc.fields.keys().cloned().collect();
+
+        println!(
+            "   📄 {} ({} field{})",
+            doc.id,
+            field_count,
+            if field_count == 1 { "" } else { "s" }
+        );
+
+        if verbose {
+            for (field_name, field_data) in &doc.fields {
+                println!(
+                    "      └─ {}: {} [{}]",
+                    field_name, field_data.masked, field_data.token
+                );
+            }
+        } else {
+            println!("      └─ Fields: [{}]", field_names.join(", "));
+        }
+    }
+}
+
+fn print_stats(stats: &StatsReport) {
+    println!(" Database Statistics:");
+    println!("   Database ID: {}", stats.db_id);
+    println!("   Namespace: {}", stats.namespace);
+    if stats.namespaces.len() > 1 {
+        println!("   Namespaces: {}", stats.namespaces.join(", "));
+    }
+    println!("   Documents: {}", stats.documents);
+    println!("   Token index size: {}", stats.token_index_size);
+    println!("   Field index size: {}", stats.field_index_size);
+    println!("   Version: {}", stats.version);
+    println!("   Created: {}", stats.created_at);
+    println!("   Last modified: {}", stats.last_modified);
+    println!("   Total fields: {}", stats.total_fields);
+    println!("   Ciphertext size: {}", format_bytes(stats.cipher_bytes));
+    println!("   Nonce size: {}", format_bytes(stats.nonce_bytes));
+    println!("   Token algorithm: {}", stats.token_algo);
+    println!("   Token encoding: {}", stats.token_encoding);
+    if let Some(counter) = stats.nonce_counter {
+        println!("   Nonce counter: {}", counter);
+    }
+    if let Some(block) = stats.pad_block {
+        println!("   Padding block: {} B", block);
+    }
+    if stats.max_field_bytes != DEFAULT_MAX_FIELD_BYTES {
+        println!("   Max field size: {}", format_bytes(stats.max_field_bytes));
+    }
+    if stats.mask_policy != MaskPolicy::LengthHint {
+        println!("   Mask policy: {}", stats.mask_policy);
+    }
+    if stats.document_keys {
+        println!("   Document keys: enabled");
+    }
+    if stats.external_key {
+        println!("   Key: from {}", KEY_ENV_VAR);
+    }
+    if let Some(rate) = stats.token_filter_fp_rate {
+        println!("   Token filter false-positive rate: {:.4}%", rate * 100.0);
+    }
+    if !stats.field_distribution.is_empty() {
+        let mut fields: Vec<(&String, &usize)> = stats.field_distribution.iter().collect();
+        fields.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        println!("   Documents per field:");
+        for (name, count) in fields {
+            println!("      {}: {}", name, count);
+        }
+    }
+}
+
+fn format_bytes(bytes: usize) -> String {
+    const KIB: f64 = 1024.0;
+    const MIB: f64 = KIB * 1024.0;
+
+    let b = bytes as f64;
+    if b >= MIB {
+        format!("{:.2} MiB", b / MIB)
+    } else if b >= KIB {
+        format!("{:.2} KiB", b / KIB)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+/// Ask a yes/no question on stdin, defaulting to no
+fn confirm(prompt: &str) -> Result<bool> {
+    print!("{}", prompt);
+    std::io::Write::flush(&mut std::io::stdout())?;
+
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+
+    Ok(input.trim().to_lowercase().starts_with('y'))
+}
+
+/// Ask for one of `accepted` to be typed on stdin, exactly
+fn confirm_typed(prompt: &str, accepted: &[&str]) -> Result<bool> {
+    print!("{}", prompt);
+    std::io::Write::flush(&mut std::io::stdout())?;
+
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    let input = input.trim();
+
+    Ok(!input.is_empty() && accepted.contains(&input))
+}
+
+/// Read one line from the terminal on stdin without echoing it
+fn read_hidden(prompt: &str) -> Result<String> {
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+
+    let _echo = EchoOff::new();
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    eprintln!();
+
+    Ok(input.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Turns terminal echo off on stdin until dropped
+struct EchoOff {
+    #[cfg(unix)]
+    original: Option<libc::termios>,
+}
+
+impl EchoOff {
+    #[cfg(unix)]
+    fn new() -> Self {
+        Self::clearing(libc::ECHO)
+    }
+
+    /// Echo off and every key passed on as typed, a byte at a time and
+    /// without line editing or signals, for `LineEditor`
+    #[cfg(unix)]
+    fn raw() -> Self {
+        Self::clearing(libc::ECHO | libc::ICANON | libc::ISIG | libc::IEXTEN)
+    }
+
+    #[cfg(unix)]
+    fn clearing(modes: libc::tcflag_t) -> Self {
+        let mut term = std::mem::MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: tcgetattr only writes to `term`, which is read only once
+        // it reports success
+        let original = unsafe {
+            (libc::tcgetattr(libc::STDIN_FILENO, term.as_mut_ptr()) == 0)
+                .then(|| term.assume_init())
+        };
+        if let Some(original) = original {
+            let mut hidden = original;
+            hidden.c_lflag &= !modes;
+            hidden.c_cc[libc::VMIN] = 1;
+            hidden.c_cc[libc::VTIME] = 0;
+            // SAFETY: `hidden` is a valid termios obtained from tcgetattr
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &hidden) };
+        }
+        Self { original }
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(original) = &self.original {
+            // SAFETY: restores the settings tcgetattr returned
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original) };
+        }
+    }
+}
+
+/// Reads REPL lines, with editing and a history of the session's earlier
+/// lines when stdin is a terminal: left/right, Home/End, Backspace/Delete,
+/// Ctrl-A/E/K/U and up/down to recall. Input that is not a terminal is read
+/// a line at a time as is.
+struct LineEditor {
+    history: Vec<String>,
+}
+
+/// What a key does to the line being edited
+#[cfg(unix)]
+enum EditKey {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Delete,
+    Other,
+}
+
+impl LineEditor {
+    fn new() -> Self {
+        Self {
+            history: Vec::new(),
+        }
+    }
+
+    /// The next line without its newline, or `None` at the end of input
+    fn read_line(&mut self, prompt: &str) -> Result<Option<String>> {
+        let line = self.next_line(prompt)?;
+        if let Some(line) = &line {
+            if !line.trim().is_empty() && self.history.last() != Some(line) {
+                self.history.push(line.clone());
+            }
+        }
+        Ok(line)
+    }
+
+    fn next_line(&self, prompt: &str) -> Result<Option<String>> {
+        #[cfg(unix)]
+        if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+            return self.edit_line(prompt);
+        }
+
+        print!("{}", prompt);
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    #[cfg(unix)]
+    fn edit_line(&self, prompt: &str) -> Result<Option<String>> {
+        let _raw = EchoOff::raw();
+        let mut stdin = std::io::stdin().lock();
+        let mut next_byte = || -> Result<Option<u8>> {
+            let mut byte = [0u8];
+            Ok((stdin.read(&mut byte)? == 1).then_some(byte[0]))
+        };
+
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // Index into `history` of the recalled line; the line being typed
+        // is kept aside while older ones are shown
+        let mut recalled = self.history.len();
+        let mut draft: Vec<char> = Vec::new();
+
+        loop {
+            redraw(prompt, &line, cursor)?;
+            let Some(byte) = next_byte()? else {
+                if line.is_empty() {
+                    return Ok(None);
+                }
+                println!();
+                return Ok(Some(line.iter().collect()));
+            };
+            let key = match byte {
+                b'\r' | b'\n' => {
+                    println!();
+                    return Ok(Some(line.iter().collect()));
+                }
+                // Ctrl-C abandons the line, Ctrl-D on an empty one ends input
+                0x03 => {
+                    println!("^C");
+                    return Ok(Some(String::new()));
+                }
+                0x04 if line.is_empty() => return Ok(None),
+                0x04 => EditKey::Delete,
+                0x01 => EditKey::Home,
+                0x05 => EditKey::End,
+                0x02 => EditKey::Left,
+                0x06 => EditKey::Right,
+                0x10 => EditKey::Up,
+                0x0e => EditKey::Down,
+                0x0b => {
+                    line.truncate(cursor);
+                    EditKey::Other
+                }
+                0x15 => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                    EditKey::Other
+                }
+                0x7f | 0x08 => {
+                    if cursor > 0 {
+                        cursor -= 1;
+                        line.remove(cursor);
+                    }
+                    EditKey::Other
+                }
+                0x1b => escape_key(&mut next_byte)?,
+                byte if byte < 0x20 => EditKey::Other,
+                lead => {
+                    let len = match lead {
+                        0xf0.. => 4,
+                        0xe0.. => 3,
+                        0xc0.. => 2,
+                        _ => 1,
+                    };
+                    let mut bytes = vec![lead];
+                    for _ in 1..len {
+                        bytes.extend(next_byte()?);
+                    }
+                    if let Ok(text) = std::str::from_utf8(&bytes) {
+                        for c in text.chars() {
+                            line.insert(cursor, c);
+                            cursor += 1;
+                        }
+                    }
+                    EditKey::Other
+                }
+            };
+
+            match key {
+                EditKey::Left => cursor = cursor.saturating_sub(1),
+                EditKey::Right => cursor = (cursor + 1).min(line.len()),
+                EditKey::Home => cursor = 0,
+                EditKey::End => cursor = line.len(),
+                EditKey::Delete => {
+                    if cursor < line.len() {
+                        line.remove(cursor);
+                    }
+                }
+                EditKey::Up if recalled > 0 => {
+                    if recalled == self.history.len() {
+                        draft = std::mem::take(&mut line);
+                    }
+                    recalled -= 1;
+                    line = self.history[recalled].chars().collect();
+                    cursor = line.len();
+                }
+                EditKey::Down if recalled < self.history.len() => {
+                    recalled += 1;
+                    line = match self.history.get(recalled) {
+                        Some(entry) => entry.chars().collect(),
+                        None => std::mem::take(&mut draft),
+                    };
+                    cursor = line.len();
+                }
+                EditKey::Up | EditKey::Down | EditKey::Other => {}
+            }
+        }
+    }
+}
+
+/// The key an escape sequence such as `ESC [ A` or `ESC [ 3 ~` stands for,
+/// reading the rest of it after the `ESC`
+#[cfg(unix)]
+fn escape_key(next_byte: &mut impl FnMut() -> Result<Option<u8>>) -> Result<EditKey> {
+    if !matches!(next_byte()?, Some(b'[' | b'O')) {
+        return Ok(EditKey::Other);
+    }
+    let mut param = Vec::new();
+    loop {
+        let Some(byte) = next_byte()? else {
+            return Ok(EditKey::Other);
+        };
+        if byte.is_ascii_digit() || byte == b';' {
+            param.push(byte);
+            continue;
+        }
+        return Ok(match (byte, param.as_slice()) {
+            (b'A', _) => EditKey::Up,
+            (b'B', _) => EditKey::Down,
+            (b'C', _) => EditKey::Right,
+            (b'D', _) => EditKey::Left,
+            (b'H', _) | (b'~', b"1" | b"7") => EditKey::Home,
+            (b'F', _) | (b'~', b"4" | b"8") => EditKey::End,
+            (b'~', b"3") => EditKey::Delete,
+            _ => EditKey::Other,
+        });
+    }
+}
+
+/// Rewrite the prompt and `line` in place and put the cursor back on
+/// `cursor`
+#[cfg(unix)]
+fn redraw(prompt: &str, line: &[char], cursor: usize) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+    let text: String = line.iter().collect();
+    write!(out, "\r{}{}\x1b[K", prompt, text)?;
+    if cursor < line.len() {
+        write!(out, "\x1b[{}D", line.len() - cursor)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Reload the database file every `interval` milliseconds and report how
+/// its documents changed since the previous load. A file caught halfway
+/// through being rewritten is skipped until the next check. The file and
+/// its log are only ever read, never rewritten.
+fn handle_watch(db: &InMemoryDB, db_path: &str, namespace: &str, interval: u64) -> Result<()> {
+    let key = key_from_env()?;
+    println!("👀 Watching {} for changes (Ctrl-C to stop)", db_path);
+    db.on_change(|event| match event {
+        ChangeEvent::Inserted(id) => println!("➕ Inserted {}", id),
+        ChangeEvent::Updated(id) => println!("✏️  Updated {}", id),
+        ChangeEvent::Removed(id) => println!("➖ Removed {}", id),
+        ChangeEvent::Cleared => println!("🧹 Cleared"),
+    });
+
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(interval));
+        if let Ok((mut state, _)) = DatabaseState::load_readonly(db_path, key) {
+            state.use_namespace(namespace);
+            sync_documents(db, &state.documents)?;
+        }
+    }
+}
+
+fn handle_repl(
+    db: &InMemoryDB,
+    db_state: &mut DatabaseState,
+    db_path: &str,
+    verbose: bool,
+    output: OutputFormat,
+) -> Result<()> {
+    println!("🔮 MagentaDB interactive mode. Type .help for help, exit to quit.");
+    db_state.deferred = true;
+    let mut editor = LineEditor::new();
+
+    loop {
+        let Some(line) = editor.read_line("magentadb> ")? else {
+            println!();
+            break;
+        };
+
+        let line = line.trim();
+        match line {
+            "" => continue,
+            "exit" | "quit" => break,
+            ".help" => {
+                println!("Any command can be entered without the `magentadb` prefix, e.g.");
+                println!("  insert user1 name \"Alice\"");
+                println!("  query \"Alice\"");
+                println!("Changes are written when the session ends or on .save");
+                println!("  .save    Write pending changes now");
+                println!("  exit     Save and leave the session");
+                println!("Up and down recall earlier lines of the session");
+                continue;
+            }
+            ".save" if db_state.in_memory => {
+                println!("🧪 In-memory database; nothing to save");
+                continue;
+            }
+            ".save" => {
+                match db_state.save(db_path) {
+                    Ok(()) => println!("💾 Saved to {}", db_path),
+                    Err(e) => eprintln!("❌ Error: {}", e),
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let words = match split_line(line) {
+            Ok(words) => words,
+            Err(e) => {
+                eprintln!("❌ Error: {}", e);
+                continue;
+            }
+        };
+
+        let command =
+            match ReplLine::try_parse_from(std::iter::once("magentadb".to_string()).chain(words)) {
+                Ok(parsed) => parsed.command,
+                Err(e) => {
+                    let _ = e.print();
+                    continue;
+                }
+            };
+
+        if matches!(command, Commands::Repl) {
+            eprintln!("❌ Error: Already in interactive mode");
+            continue;
+        }
+
+        if let Err(e) = run_command(&command, db, db_state, db_path, verbose, output) {
+            if !e.is::<NoMatches>() {
+                eprintln!("❌ Error: {}", e);
+            }
+        }
+    }
+
+    db_state.deferred = false;
+    if db_state.snapshot_pending && !db_state.in_memory {
+        db_state.save(db_path)?;
+        println!("💾 Saved to {}", db_path);
+    }
+
+    Ok(())
+}
+
+/// Split a REPL line into words, honouring single and double quotes and
+/// backslash escapes
+fn split_line(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (Some('\''), c) => word.push(c),
+            (_, '\\') => match chars.next() {
+                Some(escaped) => {
+                    word.push(escaped);
+                    in_word = true;
+                }
+                None => anyhow::bail!("Trailing backslash"),
+            },
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (_, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        anyhow::bail!("Unterminated quote");
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+
+use crate::state::DatabaseState;
+
+/// Version of the file format this build writes
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// One upgrade of the file format: `apply` brings a database written by
+/// any version older than `version` up to it. Steps must be idempotent, as
+/// files from before versions were compared may already be partly upgraded.
+///
+/// `pending` recognizes a file that still needs the step from its content
+/// alone, for changes that shipped without a version bump: such files
+/// already claim `version` and would otherwise never be upgraded.
+struct Migration {
+    version: &'static str,
+    pending: fn(&DatabaseState) -> bool,
+    apply: fn(&mut DatabaseState),
+}
+
+/// Every migration, oldest first. A schema change that older files need
+/// filled in adds a step here under the version that introduces it.
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: "0.1.0",
+    pending: has_uncounted_documents,
+    apply: count_document_versions,
+}];
+
+/// Upgrade `state` by applying in order every migration newer than the
+/// version that wrote it, or whose content shows it is still pending, and
+/// stamp it with the current version. Files from a newer version are
+/// returned unchanged.
+pub fn migrate(mut state: DatabaseState) -> Result<DatabaseState> {
+    let from = parse_version(&state.version)?;
+    if from > parse_version(CURRENT_VERSION)? {
+        return Ok(state);
+    }
+    for migration in MIGRATIONS {
+        if from < parse_version(migration.version)? || (migration.pending)(&state) {
+            (migration.apply)(&mut state);
+        }
+    }
+    state.version = CURRENT_VERSION.to_string();
+    Ok(state)
+}
+
+/// Whether `state` was written by an older version or still needs a
+/// migration its version claims to have
+pub fn needs_migration(state: &DatabaseState) -> Result<bool> {
+    let from = parse_version(&state.version)?;
+    let current = parse_version(CURRENT_VERSION)?;
+    if from != current {
+        return Ok(from < current);
+    }
+    Ok(MIGRATIONS.iter().any(|step| (step.pending)(state)))
+}
+
+/// `major.minor.patch` as numbers, ignoring any pre-release or build suffix
+fn parse_version(version: &str) -> Result<(u64, u64, u64)> {
+    let core = version.split(['-', '+']).next().unwrap_or_default();
+    let parts = core
+        .split('.')
+        .map(str::parse)
+        .collect::<Result<Vec<u64>, _>>()
+        .ok()
+        .filter(|parts| parts.len() == 3)
+        .context(format!("Unrecognized database version '{}'", version))?;
+    Ok((parts[0], parts[1], parts[2]))
+}
+
+/// Whether any document was written before versions were counted
+fn has_uncounted_documents(state: &DatabaseState) -> bool {
+    state.all_documents().any(|doc| doc.version == 0)
+}
+
+/// Documents written before versions were counted are stored as version 0;
+/// they start at version 1, like any newly inserted document
+fn count_document_versions(state: &mut DatabaseState) {
+    for doc in state.all_documents_mut() {
+        doc.version = doc.version.max(1);
+    }
+}
@@ -0,0 +1,582 @@
+use anyhow::{Context, Result};
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::migrate::{migrate, needs_migration, CURRENT_VERSION};
+use magentadb_core::{
+    db::DBError,
+    document::{DocumentStored, MaskPolicy},
+    wal::{Wal, WalConfig, WalOp, WalReplay},
+};
+use magentadb_crypto::{
+    integrity_tag, key_fingerprint, nonce_counter, parse_key_hex, validate_key,
+    verify_integrity_tag, verify_key_fingerprint, Nonce, TokenAlgo, TokenEncoding,
+    NONCE_PREFIX_LEN,
+};
+
+/// Database state for persistence
+///
+/// The file at the database path is a snapshot; writes since that snapshot
+/// are appended to a write-ahead log beside it and replayed on load.
+#[derive(Serialize, Deserialize)]
+pub struct DatabaseState {
+    /// Documents of the selected namespace. In the file, those of the
+    /// default namespace
+    pub documents: HashMap<String, DocumentStored>,
+    /// Documents of every other namespace that holds any, by namespace
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    namespaces: HashMap<String, HashMap<String, DocumentStored>>,
+    /// Namespace `documents` belongs to
+    #[serde(skip, default = "default_namespace")]
+    pub namespace: String,
+    /// Random UUID naming this database in logs and backups; fixed when the
+    /// database is created
+    #[serde(default)]
+    pub db_id: String,
+    /// The key as written in the file; absent when it is supplied through
+    /// `MAGENTADB_KEY`
+    #[serde(
+        rename = "secret_key",
+        default,
+        deserialize_with = "deserialize_secret_key",
+        skip_serializing_if = "Option::is_none"
+    )]
+    stored_key: Option<[u8; 32]>,
+    /// Key every field and token is derived from
+    #[serde(skip)]
+    pub secret_key: [u8; 32],
+    /// Fingerprint of the key the documents were encrypted with, checked on
+    /// every load so a different key is never used on them
+    #[serde(default)]
+    pub key_fingerprint: String,
+    /// Keyed hash of `documents` as last saved, checked on every load so
+    /// documents dropped, reordered or edited in the file are noticed
+    #[serde(default)]
+    pub integrity_tag: String,
+    /// Keyed hash every token in this database is derived with; fixed when
+    /// the database is created
+    #[serde(default)]
+    pub token_algo: TokenAlgo,
+    /// How every token in this database is written out; fixed when the
+    /// database is created, so tokens are never compared across encodings
+    #[serde(default)]
+    pub token_encoding: TokenEncoding,
+    pub version: String,
+    pub created_at: String,
+    pub last_modified: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce_counter: Option<NonceCounter>,
+    /// Block size new values are padded to before encryption
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pad_block: Option<usize>,
+    /// Largest plaintext value, in bytes, a field may be written with
+    #[serde(
+        default = "default_max_field_bytes",
+        skip_serializing_if = "is_default_max_field_bytes"
+    )]
+    pub max_field_bytes: usize,
+    /// How much of each new value its masked form reveals
+    #[serde(default, skip_serializing_if = "is_length_hint")]
+    pub mask_policy: MaskPolicy,
+    /// Whether new documents get their own wrapped data key
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub document_keys: bool,
+    /// Set when the key came from `MAGENTADB_KEY`, so it is never written
+    /// to the file
+    #[serde(skip)]
+    pub external_key: bool,
+    #[serde(skip)]
+    wal: Option<Wal>,
+    /// Set when database-level settings change, which only a snapshot records
+    #[serde(skip)]
+    pub snapshot_pending: bool,
+    /// Set while a REPL session is running: mutations are only applied in
+    /// memory and written out as one snapshot when the session ends
+    #[serde(skip)]
+    pub deferred: bool,
+    /// Set for databases that only live for this process: writes are only
+    /// applied in memory and nothing is ever written to disk
+    #[serde(skip)]
+    pub in_memory: bool,
+    /// Set for databases opened with `load_readonly`: like `in_memory`,
+    /// writes never reach the file, which another process may be writing
+    #[serde(skip)]
+    pub read_only: bool,
+}
+
+/// How `DatabaseState::load_or_create` found the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadOutcome {
+    Created,
+    /// `migrated` is set when the file was upgraded from an older version
+    Loaded {
+        replayed: usize,
+        migrated: bool,
+    },
+}
+
+/// Counter-based nonce state; once enabled, every new field is encrypted
+/// under `prefix || next` and `next` is advanced
+#[derive(Serialize, Deserialize)]
+pub struct NonceCounter {
+    pub prefix: [u8; NONCE_PREFIX_LEN],
+    pub next: u64,
+}
+
+impl NonceCounter {
+    pub fn new() -> Self {
+        Self {
+            prefix: rand::thread_rng().gen(),
+            next: 0,
+        }
+    }
+
+    pub fn take(&mut self) -> Result<u64> {
+        let counter = self.next;
+        self.next = counter.checked_add(1).context("Nonce counter exhausted")?;
+        Ok(counter)
+    }
+
+    /// Advance past a nonce already in use. The snapshot only records `next`
+    /// at compaction, so counters handed out since then are recovered from
+    /// replayed log entries.
+    fn observe(&mut self, nonce: &Nonce) {
+        if let Some(counter) = nonce_counter(&self.prefix, nonce) {
+            self.next = self.next.max(counter.saturating_add(1));
+        }
+    }
+}
+
+impl Default for NonceCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DatabaseState {
+    /// Open the database at `path`, creating a new one only if no file
+    /// exists there. A file that cannot be read or parsed is an error rather
+    /// than a reason to start over with a new key.
+    ///
+    /// With `key`, the database is opened with that key instead of the one
+    /// in the file, and the file is written without any key.
+    pub fn load_or_create(
+        path: &str,
+        wal_config: WalConfig,
+        key: Option<[u8; 32]>,
+    ) -> Result<(Self, LoadOutcome)> {
+        Self::load(path, wal_config, key, true)
+    }
+
+    /// Open the existing database at `path` without ever writing to it or
+    /// its log, for readers that do not hold its lock. What a normal load
+    /// would save (a migration, a stale log, a backfilled id) is only
+    /// applied in memory, and so is any later write to the returned state,
+    /// which is marked `read_only`.
+    pub fn load_readonly(path: &str, key: Option<[u8; 32]>) -> Result<(Self, LoadOutcome)> {
+        let (state, loaded) = Self::load(path, WalConfig::default(), key, false)?;
+        if loaded == LoadOutcome::Created {
+            anyhow::bail!("Database file {} not found", path);
+        }
+        Ok((state, loaded))
+    }
+
+    fn load(
+        path: &str,
+        wal_config: WalConfig,
+        key: Option<[u8; 32]>,
+        writable: bool,
+    ) -> Result<(Self, LoadOutcome)> {
+        let wal_path = wal_path(path);
+
+        let existing = match fs::read_to_string(path) {
+            Ok(data) => Some(data),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(e).context(format!(
+                    "Failed to read database file {}; refusing to create a new one in its place",
+                    path
+                ))
+            }
+        };
+
+        let (mut state, loaded) = if let Some(data) = existing {
+            let parse_error = || {
+                format!(
+                    "Failed to parse database file {}; it was left untouched",
+                    path
+                )
+            };
+            let raw: serde_json::Value = serde_json::from_str(&data).with_context(parse_error)?;
+            // The tag covers the documents exactly as they appear in the file
+            let documents = canonical_documents(&raw["documents"], &raw["namespaces"])?;
+            let mut state: DatabaseState = serde_json::from_value(raw).with_context(parse_error)?;
+            match (key, state.stored_key) {
+                (Some(key), _) => {
+                    state.secret_key = key;
+                    state.external_key = true;
+                }
+                (None, Some(stored)) => state.secret_key = stored,
+                (None, None) => anyhow::bail!(
+                    "{} does not store its key; set {} to open it",
+                    path,
+                    KEY_ENV_VAR
+                ),
+            }
+            validate_key(&state.secret_key).context("Refusing to use the database key")?;
+            // Files written before databases had ids, fingerprints or integrity
+            // tags get them now, saved below so they stay the same on every
+            // later load
+            if state.key_fingerprint.is_empty() {
+                state.key_fingerprint = key_fingerprint(&state.secret_key);
+                state.snapshot_pending = true;
+            } else {
+                verify_key_fingerprint(&state.secret_key, &state.key_fingerprint)
+                    .context(format!("Refusing to open {}", path))?;
+            }
+            if state.integrity_tag.is_empty() {
+                state.snapshot_pending = true;
+            } else {
+                verify_integrity_tag(&state.secret_key, &documents, &state.integrity_tag)
+                    .context(format!("Refusing to open {}", path))?;
+            }
+            if state.db_id.is_empty() {
+                state.db_id = new_db_id();
+                state.snapshot_pending = true;
+            }
+
+            // Update last accessed time
+            state.last_modified = chrono::Utc::now().to_rfc3339();
+            (state, true)
+        } else {
+            if Path::new(&wal_path).exists() {
+                anyhow::bail!(
+                    "Found write-ahead log {} without its database file; refusing to create a new key",
+                    wal_path
+                );
+            }
+
+            (Self::new(key), false)
+        };
+
+        let (wal, replay) = Wal::open(
+            &wal_path,
+            wal_config,
+            &state.secret_key,
+            &state.integrity_tag,
+        )
+        .context(format!("Refusing to open {}", path))?;
+        let replayed = match replay {
+            WalReplay::Follows(ops) => {
+                let replayed = ops.len();
+                for op in ops {
+                    state.apply_op(op);
+                }
+                replayed
+            }
+            // Left behind by an interrupted compaction, the log is already
+            // part of the snapshot and changes nothing; dropped on save
+            WalReplay::Stale(ops) => {
+                let mut documents = state.documents.clone();
+                for op in &ops {
+                    apply_to_documents(&mut documents, op.clone());
+                }
+                if serde_json::to_value(&documents)? != serde_json::to_value(&state.documents)? {
+                    anyhow::bail!(
+                        "Refusing to open {}: write-ahead log {} does not follow the database file",
+                        path,
+                        wal_path
+                    );
+                }
+                state.snapshot_pending = true;
+                0
+            }
+        };
+        if writable {
+            state.wal = Some(wal);
+        } else {
+            state.read_only = true;
+        }
+        // Logged writes are replayed first so they are upgraded too
+        let migrated = loaded && needs_migration(&state)?;
+        if migrated {
+            state = migrate(state)?;
+            state.snapshot_pending = true;
+        }
+        if loaded && writable && state.snapshot_pending {
+            state.save(path)?;
+        }
+
+        let outcome = if loaded {
+            LoadOutcome::Loaded { replayed, migrated }
+        } else {
+            LoadOutcome::Created
+        };
+        Ok((state, outcome))
+    }
+
+    /// Make `name` the namespace `documents` holds, keeping the documents
+    /// of the previously selected one aside
+    pub fn use_namespace(&mut self, name: &str) {
+        if name == self.namespace {
+            return;
+        }
+        let documents = std::mem::take(&mut self.documents);
+        let previous = std::mem::replace(&mut self.namespace, name.to_string());
+        if !documents.is_empty() {
+            self.namespaces.insert(previous, documents);
+        }
+        self.documents = self.namespaces.remove(name).unwrap_or_default();
+    }
+
+    /// Every namespace holding documents, plus the selected one, in name
+    /// order
+    pub fn namespace_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.namespaces.keys().cloned().collect();
+        names.push(self.namespace.clone());
+        names.sort();
+        names
+    }
+
+    /// Documents of every namespace
+    pub(crate) fn all_documents(&self) -> impl Iterator<Item = &DocumentStored> {
+        self.documents
+            .values()
+            .chain(self.namespaces.values().flat_map(HashMap::values))
+    }
+
+    /// Documents of every namespace
+    pub(crate) fn all_documents_mut(&mut self) -> impl Iterator<Item = &mut DocumentStored> {
+        self.documents
+            .values_mut()
+            .chain(self.namespaces.values_mut().flat_map(HashMap::values_mut))
+    }
+
+    /// A new, empty database that is never read from or written to disk,
+    /// under `key` or a fresh random one
+    pub fn in_memory(key: Option<[u8; 32]>) -> Self {
+        Self {
+            in_memory: true,
+            ..Self::new(key)
+        }
+    }
+
+    fn new(key: Option<[u8; 32]>) -> Self {
+        let now = chrono::Utc::now().to_rfc3339();
+        let secret_key = key.unwrap_or_else(|| rand::thread_rng().gen());
+        Self {
+            documents: HashMap::new(),
+            namespaces: HashMap::new(),
+            namespace: default_namespace(),
+            db_id: new_db_id(),
+            stored_key: None,
+            secret_key,
+            key_fingerprint: key_fingerprint(&secret_key),
+            integrity_tag: String::new(),
+            token_algo: TokenAlgo::default(),
+            token_encoding: TokenEncoding::default(),
+            version: CURRENT_VERSION.to_string(),
+            created_at: now.clone(),
+            last_modified: now,
+            nonce_counter: None,
+            pad_block: None,
+            max_field_bytes: DEFAULT_MAX_FIELD_BYTES,
+            mask_policy: MaskPolicy::default(),
+            document_keys: false,
+            external_key: key.is_some(),
+            wal: None,
+            snapshot_pending: false,
+            deferred: false,
+            in_memory: false,
+            read_only: false,
+        }
+    }
+
+    fn apply_op(&mut self, op: WalOp) {
+        if let (WalOp::Upsert(doc), Some(counter)) = (&op, self.nonce_counter.as_mut()) {
+            for field_data in doc.fields.values() {
+                for (_, _, nonce) in field_data.values() {
+                    counter.observe(nonce);
+                }
+            }
+        }
+        apply_to_documents(&mut self.documents, op);
+    }
+
+    /// Record a single write, appending it to the log and only rewriting the
+    /// snapshot when the log is due for compaction (or no snapshot exists yet)
+    pub fn persist(&mut self, path: &str, op: WalOp) -> Result<()> {
+        if self.deferred || self.in_memory || self.read_only {
+            self.apply_op(op);
+            self.snapshot_pending = true;
+            return Ok(());
+        }
+
+        // Log entries carry no namespace, so they are only used for the
+        // default one; writes to any other rewrite the snapshot
+        let logged = match self.wal.as_mut() {
+            Some(wal)
+                if !self.snapshot_pending
+                    && self.namespace == DEFAULT_NAMESPACE
+                    && Path::new(path).exists() =>
+            {
+                wal.append(&op, &self.secret_key)?;
+                true
+            }
+            _ => false,
+        };
+        self.apply_op(op);
+
+        if !logged || self.wal.as_ref().is_some_and(Wal::needs_compaction) {
+            self.save(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Tag the documents and serialize the whole state as the file holds it
+    fn serialize_snapshot(&mut self) -> Result<String> {
+        let documents = canonical_documents(
+            &serde_json::to_value(&self.documents).context("Failed to serialize database")?,
+            &serde_json::to_value(&self.namespaces).context("Failed to serialize database")?,
+        )?;
+        self.integrity_tag = integrity_tag(&self.secret_key, &documents);
+
+        serde_json::to_string_pretty(self).context("Failed to serialize database")
+    }
+
+    /// Write a full snapshot and empty the log
+    pub fn save(&mut self, path: &str) -> Result<()> {
+        self.last_modified = chrono::Utc::now().to_rfc3339();
+        if self.in_memory || self.read_only {
+            self.snapshot_pending = false;
+            return Ok(());
+        }
+
+        self.stored_key = (!self.external_key).then_some(self.secret_key);
+
+        // The file keeps the default namespace in `documents`
+        let selected = self.namespace.clone();
+        self.use_namespace(DEFAULT_NAMESPACE);
+        let data = self.serialize_snapshot();
+        self.use_namespace(&selected);
+        let data = data?;
+
+        match self.wal.as_mut() {
+            Some(wal) => wal.compact(&self.integrity_tag, || {
+                fs::write(path, &data).map_err(|e| {
+                    DBError::StorageError(format!("Failed to write database file: {}", e))
+                })
+            })?,
+            None => fs::write(path, data).context("Failed to write database file")?,
+        }
+        self.snapshot_pending = false;
+
+        Ok(())
+    }
+}
+
+/// Environment variable holding the key, as 64 hex digits, of databases
+/// that do not store it
+pub const KEY_ENV_VAR: &str = "MAGENTADB_KEY";
+
+/// The key set in `MAGENTADB_KEY`, if any
+pub fn key_from_env() -> Result<Option<[u8; 32]>> {
+    match std::env::var(KEY_ENV_VAR) {
+        Ok(hex) => parse_key_hex(&hex)
+            .map(Some)
+            .context(format!("{} is not a usable key", KEY_ENV_VAR)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e).context(format!("Failed to read {}", KEY_ENV_VAR)),
+    }
+}
+
+/// Database path that opens an in-memory database instead of a file
+pub const IN_MEMORY_PATH: &str = ":memory:";
+
+/// Namespace commands use unless given `--ns`
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+/// Field size limit of databases that do not set one
+pub const DEFAULT_MAX_FIELD_BYTES: usize = 16 * 1024 * 1024;
+
+fn default_max_field_bytes() -> usize {
+    DEFAULT_MAX_FIELD_BYTES
+}
+
+fn is_default_max_field_bytes(limit: &usize) -> bool {
+    *limit == DEFAULT_MAX_FIELD_BYTES
+}
+
+fn is_length_hint(policy: &MaskPolicy) -> bool {
+    *policy == MaskPolicy::LengthHint
+}
+
+/// Read `secret_key` as any number of bytes so a wrong length is reported
+/// as such rather than as a generic array length mismatch
+fn deserialize_secret_key<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let bytes = Vec::<u8>::deserialize(deserializer)?;
+    let len = bytes.len();
+    bytes.try_into().map(Some).map_err(|_| {
+        serde::de::Error::custom(format!("secret_key must be 32 bytes, found {}", len))
+    })
+}
+
+/// Apply the effect a logged operation has on the documents of a namespace
+fn apply_to_documents(documents: &mut HashMap<String, DocumentStored>, op: WalOp) {
+    match op {
+        WalOp::Upsert(doc) => {
+            documents.insert(doc.id.clone(), doc);
+        }
+        WalOp::Remove(id) => {
+            documents.remove(&id);
+        }
+        WalOp::Clear => documents.clear(),
+    }
+}
+
+/// The bytes the integrity tag is computed over: `documents`, followed by
+/// `namespaces` when there are any, as compact JSON with every object's
+/// keys in sorted order, so the same documents always give the same bytes
+/// however their maps were ordered in memory
+fn canonical_documents(
+    documents: &serde_json::Value,
+    namespaces: &serde_json::Value,
+) -> Result<Vec<u8>> {
+    let mut bytes = serde_json::to_vec(documents).context("Failed to serialize database")?;
+    if namespaces.as_object().is_some_and(|ns| !ns.is_empty()) {
+        bytes.extend(serde_json::to_vec(namespaces).context("Failed to serialize database")?);
+    }
+    Ok(bytes)
+}
+
+/// A random (version 4) UUID in its hyphenated form
+fn new_db_id() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn wal_path(db_path: &str) -> String {
+    format!("{}.wal", db_path)
+}
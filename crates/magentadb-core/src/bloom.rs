@@ -0,0 +1,103 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// False-positive rate a filter is sized for at its expected item count
+pub const TOKEN_FILTER_FP_RATE: f64 = 0.01;
+
+/// Counting Bloom filter over tokens.
+///
+/// Each slot is a counter rather than a bit, so tokens can be removed as
+/// well as added. A miss is definite; a hit only means the token may be
+/// present. Only tokens that were added may be removed: removing any other
+/// token decrements slots it shares with added ones and can turn their
+/// hits into false negatives.
+#[derive(Debug)]
+pub struct TokenFilter {
+    counters: Box<[AtomicU32]>,
+    hashes: u32,
+    items: AtomicUsize,
+}
+
+impl TokenFilter {
+    /// Size a filter for `expected_items` tokens at `TOKEN_FILTER_FP_RATE`
+    pub fn new(expected_items: usize) -> Self {
+        let n = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let slots = (-n * TOKEN_FILTER_FP_RATE.ln() / (ln2 * ln2)).ceil() as usize;
+        let hashes = ((slots as f64 / n) * ln2).round().max(1.0) as u32;
+
+        Self {
+            counters: (0..slots.max(64)).map(|_| AtomicU32::new(0)).collect(),
+            hashes,
+            items: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn insert(&self, token: &str) {
+        for slot in self.slots(token) {
+            self.counters[slot].fetch_add(1, Ordering::Relaxed);
+        }
+        self.items.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Undo one `insert` of `token`, which must have been added and not
+    /// removed since. Tokens the filter already rejects are skipped, but one
+    /// that only collides with added tokens passes that check and is removed
+    /// all the same.
+    pub fn remove(&self, token: &str) {
+        if !self.may_contain(token) {
+            return;
+        }
+        for slot in self.slots(token) {
+            let _ = self.counters[slot]
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| c.checked_sub(1));
+        }
+        let _ = self
+            .items
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    /// `false` if `token` is definitely absent
+    pub fn may_contain(&self, token: &str) -> bool {
+        self.slots(token)
+            .all(|slot| self.counters[slot].load(Ordering::Relaxed) > 0)
+    }
+
+    pub fn clear(&self) {
+        for counter in self.counters.iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.items.store(0, Ordering::Relaxed);
+    }
+
+    /// Number of tokens currently added
+    pub fn len(&self) -> usize {
+        self.items.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimated chance that an absent token passes the filter, given the
+    /// tokens currently added: `(1 - e^(-k n / m))^k`
+    pub fn false_positive_rate(&self) -> f64 {
+        let k = f64::from(self.hashes);
+        let n = self.len() as f64;
+        let m = self.counters.len() as f64;
+        (1.0 - (-k * n / m).exp()).powf(k)
+    }
+
+    /// Slot indexes for `token`, derived from one 64-bit hash by double
+    /// hashing
+    fn slots(&self, token: &str) -> impl Iterator<Item = usize> + '_ {
+        let mut hasher = DefaultHasher::new();
+        token.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash, hash.rotate_left(32) | 1);
+        let m = self.counters.len() as u64;
+
+        (0..u64::from(self.hashes)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+    }
+}
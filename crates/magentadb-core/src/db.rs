@@ -1,22 +1,48 @@
-use crate::document::DocumentStored;
+use crate::bloom::TokenFilter;
+use crate::document::{DocumentStored, FieldMaterialized, FieldValue};
+use crate::pattern::Pattern;
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
-use std::collections::HashSet;
+use magentadb_crypto::{
+    decrypt_padded, tokenize_prefix, tokenize_range_query, tokenize_trigrams, tokens_equal,
+    TokenAlgo, TokenEncoding, PREFIX_MIN_LEN,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug)]
 pub enum DBError {
     NotFound(String),
+    FieldNotFound(String, String),
+    FieldExists(String, String),
     StorageError(String),
     Duplicate(String),
+    Conflict(String, u64, u64),
+    InvalidInput(String),
 }
 
 impl fmt::Display for DBError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             DBError::NotFound(id) => write!(f, "Document not found: {}", id),
+            DBError::FieldNotFound(id, field) => {
+                write!(f, "Field not found: {} in document {}", field, id)
+            }
+            DBError::FieldExists(id, field) => {
+                write!(f, "Field already exists: {} in document {}", field, id)
+            }
             DBError::StorageError(msg) => write!(f, "Storage error: {}", msg),
             DBError::Duplicate(id) => write!(f, "Duplicate document: {}", id),
+            DBError::Conflict(id, expected, found) => write!(
+                f,
+                "Version conflict on {}: expected {}, found {}",
+                id, expected, found
+            ),
+            DBError::InvalidInput(msg) => write!(f, "Invalid input: {}", msg),
         }
     }
 }
@@ -28,39 +54,406 @@ pub struct InMemoryDB {
     documents: Arc<DashMap<String, Arc<DocumentStored>>>,
     token_index: Arc<DashMap<String, HashSet<String>>>,
     field_index: Arc<DashMap<String, HashSet<String>>>,
+    /// (field name, range token) -> ids of numeric fields in that bucket
+    range_index: Arc<DashMap<(String, String), HashSet<String>>>,
+    /// Every indexed token, checked before `token_index` so definite misses
+    /// never touch it
+    token_filter: Option<Arc<TokenFilter>>,
+    /// Set by `defer_indexing`: writes leave the indexes alone until
+    /// `build_indexes` populates them
+    indexing_deferred: Arc<AtomicBool>,
+    /// Callbacks registered with `on_change`, run after every write
+    listeners: Arc<RwLock<Vec<ChangeListener>>>,
 }
 
-impl InMemoryDB {
+/// A callback registered with `InMemoryDB::on_change`
+type ChangeListener = Arc<dyn Fn(&ChangeEvent) + Send + Sync>;
+
+/// A write reported to `InMemoryDB::on_change` callbacks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// A document was stored under an id that had none
+    Inserted(String),
+    /// A stored document was replaced
+    Updated(String),
+    Removed(String),
+    /// Every document was removed at once
+    Cleared,
+}
+
+impl Default for InMemoryDB {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serialized as its `snapshot`: the documents, ordered by id, without
+/// indexes, listeners or builder options
+impl Serialize for InMemoryDB {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.snapshot().serialize(serializer)
+    }
+}
+
+/// Deserialized through `restore`, so every index is rebuilt from the
+/// documents and queries work as soon as it is loaded
+impl<'de> Deserialize<'de> for InMemoryDB {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        DbSnapshot::deserialize(deserializer).map(Self::restore)
+    }
+}
+
+/// Sizing options for an `InMemoryDB`; `InMemoryDB::new()` is the same as
+/// building with the defaults
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryDBBuilder {
+    capacity: usize,
+    shard_amount: Option<usize>,
+    token_filter: Option<usize>,
+}
+
+impl InMemoryDBBuilder {
     pub fn new() -> Self {
-        Self {
-            documents: Arc::new(DashMap::new()),
-            token_index: Arc::new(DashMap::new()),
-            field_index: Arc::new(DashMap::new()),
+        Self::default()
+    }
+
+    /// Preallocate room for `docs` documents (and as many value tokens)
+    pub fn with_capacity(mut self, docs: usize) -> Self {
+        self.capacity = docs;
+        self
+    }
+
+    /// Number of lock shards in each map; must be a power of two greater
+    /// than 1. Defaults to DashMap's choice based on available parallelism.
+    pub fn with_shard_amount(mut self, shard_amount: usize) -> Self {
+        self.shard_amount = Some(shard_amount);
+        self
+    }
+
+    /// Check token lookups against a Bloom filter sized for
+    /// `expected_tokens` tokens, so lookups of absent tokens return without
+    /// reading the token index
+    pub fn with_token_filter(mut self, expected_tokens: usize) -> Self {
+        self.token_filter = Some(expected_tokens);
+        self
+    }
+
+    /// # Panics
+    ///
+    /// If a shard amount was set that is not a power of two greater than 1.
+    pub fn build(self) -> InMemoryDB {
+        InMemoryDB {
+            documents: Arc::new(self.map(self.capacity)),
+            token_index: Arc::new(self.map(self.capacity)),
+            field_index: Arc::new(self.map(0)),
+            range_index: Arc::new(self.map(0)),
+            token_filter: self
+                .token_filter
+                .map(|expected| Arc::new(TokenFilter::new(expected))),
+            indexing_deferred: Arc::new(AtomicBool::new(false)),
+            listeners: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    fn map<K: Eq + std::hash::Hash, V>(&self, capacity: usize) -> DashMap<K, V> {
+        match self.shard_amount {
+            Some(shard_amount) => DashMap::with_capacity_and_shard_amount(capacity, shard_amount),
+            None => DashMap::with_capacity(capacity),
         }
     }
+}
+
+impl InMemoryDB {
+    pub fn new() -> Self {
+        InMemoryDBBuilder::default().build()
+    }
+
+    pub fn builder() -> InMemoryDBBuilder {
+        InMemoryDBBuilder::new()
+    }
+
+    /// Number of documents that fit before the document map reallocates
+    pub fn capacity(&self) -> usize {
+        self.documents.capacity()
+    }
 
+    /// Insert or replace a document, returning the previous version.
+    ///
+    /// The document's map entry stays locked while its indexes are
+    /// rewritten, so concurrent upserts of the same id cannot interleave
+    /// and leave tokens of a replaced version behind. The document is stored
+    /// before any index entry points at it, so an id found in an index
+    /// always resolves to a document.
+    ///
+    /// The id and field names are checked with `validate_id` and
+    /// `validate_field_name` first.
     pub fn upsert(&self, doc: DocumentStored) -> Result<Option<Arc<DocumentStored>>, DBError> {
+        validate_id(&doc.id)?;
+        doc.fields
+            .keys()
+            .try_for_each(|name| validate_field_name(name))?;
+        self.upsert_unvalidated(doc)
+    }
+
+    /// Like `upsert`, but without checking the id and field names, for
+    /// loading documents that were already stored, possibly before those
+    /// checks existed
+    pub fn upsert_unvalidated(
+        &self,
+        doc: DocumentStored,
+    ) -> Result<Option<Arc<DocumentStored>>, DBError> {
         let doc_id = doc.id.clone();
         let doc_arc = Arc::new(doc);
 
-        if let Some(old_doc) = self.documents.get(&doc_id) {
-            self.cleanup_indexes(&doc_id, &old_doc);
+        let old = match self.documents.entry(doc_id.clone()) {
+            Entry::Occupied(mut entry) => {
+                let old = entry.insert(Arc::clone(&doc_arc));
+                self.cleanup_indexes(&doc_id, &old);
+                self.index_document(&doc_id, &doc_arc);
+                Some(old)
+            }
+            Entry::Vacant(entry) => {
+                let stored = entry.insert(doc_arc);
+                self.index_document(&doc_id, &stored);
+                None
+            }
+        };
+        self.notify_write(doc_id, old.is_some());
+        Ok(old)
+    }
+
+    /// Replace `id` with `doc` only if the stored version equals
+    /// `expected_version`, storing `doc` as version `expected_version + 1`.
+    ///
+    /// A missing document counts as version 0, so this can also create.
+    pub fn compare_and_swap(
+        &self,
+        id: &str,
+        expected_version: u64,
+        mut doc: DocumentStored,
+    ) -> Result<(), DBError> {
+        validate_id(id)?;
+        doc.fields
+            .keys()
+            .try_for_each(|name| validate_field_name(name))?;
+        doc.id = id.to_string();
+        doc.version = expected_version + 1;
+
+        let replaced = match self.documents.entry(id.to_string()) {
+            Entry::Occupied(mut entry) => {
+                let found = entry.get().version;
+                if found != expected_version {
+                    return Err(DBError::Conflict(id.to_string(), expected_version, found));
+                }
+                doc.touch(Some(entry.get()));
+                let doc_arc = Arc::new(doc);
+                let old = entry.insert(Arc::clone(&doc_arc));
+                self.cleanup_indexes(id, &old);
+                self.index_document(id, &doc_arc);
+                true
+            }
+            Entry::Vacant(entry) => {
+                if expected_version != 0 {
+                    return Err(DBError::Conflict(id.to_string(), expected_version, 0));
+                }
+                doc.touch(None);
+                let stored = entry.insert(Arc::new(doc));
+                self.index_document(id, &stored);
+                false
+            }
+        };
+        self.notify_write(id.to_string(), replaced);
+        Ok(())
+    }
+
+    /// Set one field of `id`, creating the document if it does not exist.
+    /// Returns the document as stored together with the version it
+    /// replaced, or `None` if the document is new.
+    ///
+    /// The merge happens under the document's entry lock, so concurrent
+    /// writes to different fields of the same document all survive.
+    pub fn upsert_field(
+        &self,
+        id: &str,
+        field: &str,
+        data: FieldMaterialized,
+    ) -> Result<(Arc<DocumentStored>, Option<Arc<DocumentStored>>), DBError> {
+        self.upsert_fields(id, HashMap::from([(field.to_string(), data)]))
+    }
+
+    /// Like `upsert_field`, but set several fields in one write
+    pub fn upsert_fields(
+        &self,
+        id: &str,
+        fields: HashMap<String, FieldMaterialized>,
+    ) -> Result<(Arc<DocumentStored>, Option<Arc<DocumentStored>>), DBError> {
+        validate_id(id)?;
+        fields
+            .keys()
+            .try_for_each(|name| validate_field_name(name))?;
+        let (doc_arc, old) = match self.documents.entry(id.to_string()) {
+            Entry::Occupied(mut entry) => {
+                let mut doc = (**entry.get()).clone();
+                doc.fields.extend(fields);
+                doc.version += 1;
+                doc.touch(Some(entry.get()));
+                let doc_arc = Arc::new(doc);
+
+                let old = entry.insert(Arc::clone(&doc_arc));
+                self.cleanup_indexes(id, &old);
+                self.index_document(id, &doc_arc);
+                (doc_arc, Some(old))
+            }
+            Entry::Vacant(entry) => {
+                let mut doc = DocumentStored {
+                    id: id.to_string(),
+                    fields,
+                    version: 1,
+                    wrapped_key: None,
+                    created_at: None,
+                    updated_at: None,
+                    metadata: HashMap::new(),
+                    deleted_at: None,
+                };
+                doc.touch(None);
+                let doc_arc = Arc::new(doc);
+
+                let stored = entry.insert(Arc::clone(&doc_arc));
+                self.index_document(id, &stored);
+                (doc_arc, None)
+            }
+        };
+        self.notify_write(id.to_string(), old.is_some());
+        Ok((doc_arc, old))
+    }
+
+    /// Replace the value of an existing field, reindexing the document so
+    /// tokens of the previous value no longer match.
+    ///
+    /// Writes go through `compare_and_swap` and are retried if another
+    /// writer updates the document in between. Returns the document as it
+    /// was before the update.
+    pub fn update_field(
+        &self,
+        id: &str,
+        field: &str,
+        data: FieldMaterialized,
+    ) -> Result<Arc<DocumentStored>, DBError> {
+        loop {
+            let existing = self.get(id)?;
+            if !existing.fields.contains_key(field) {
+                return Err(DBError::FieldNotFound(id.to_string(), field.to_string()));
+            }
+
+            let mut doc = (*existing).clone();
+            doc.fields.insert(field.to_string(), data.clone());
+
+            match self.compare_and_swap(id, existing.version, doc) {
+                Ok(()) => return Ok(existing),
+                Err(DBError::Conflict(..)) => continue,
+                Err(e) => return Err(e),
+            }
         }
+    }
 
-        for (field_name, field_data) in &doc_arc.fields {
-            self.token_index
-                .entry(field_data.token.clone())
-                .or_insert_with(HashSet::new)
-                .insert(doc_id.clone());
+    /// Add `value` to the existing `field` of `id` alongside the values it
+    /// already holds, indexing its token so queries for any of the values
+    /// match. Returns how many values the field now holds. Retried like
+    /// `update_field` if another writer updates the document in between.
+    pub fn append_field_value(
+        &self,
+        id: &str,
+        field: &str,
+        value: FieldValue,
+    ) -> Result<usize, DBError> {
+        loop {
+            let existing = self.get(id)?;
+            let mut doc = (*existing).clone();
+            let field_data = doc
+                .fields
+                .get_mut(field)
+                .ok_or_else(|| DBError::FieldNotFound(id.to_string(), field.to_string()))?;
+            field_data.extra_values.push(value.clone());
+            let count = field_data.value_count();
 
-            self.field_index
-                .entry(field_name.clone())
-                .or_insert_with(HashSet::new)
-                .insert(doc_id.clone());
+            match self.compare_and_swap(id, existing.version, doc) {
+                Ok(()) => return Ok(count),
+                Err(DBError::Conflict(..)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Move field `old` of `id` to the name `new`, keeping its value and
+    /// tokens, so field lookups find it under the new name only. Fails if
+    /// the document has no `old` field or already has a `new` one. Retried
+    /// like `update_field` if another writer updates the document in
+    /// between.
+    pub fn rename_field(&self, id: &str, old: &str, new: &str) -> Result<(), DBError> {
+        loop {
+            let existing = self.get(id)?;
+            if existing.fields.contains_key(new) {
+                return Err(DBError::FieldExists(id.to_string(), new.to_string()));
+            }
+            let mut doc = (*existing).clone();
+            let field_data = doc
+                .fields
+                .remove(old)
+                .ok_or_else(|| DBError::FieldNotFound(id.to_string(), old.to_string()))?;
+            doc.fields.insert(new.to_string(), field_data);
+
+            match self.compare_and_swap(id, existing.version, doc) {
+                Ok(()) => return Ok(()),
+                Err(DBError::Conflict(..)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Set metadata `key` of an existing document to `value`, returning the
+    /// value it replaced. Retried like `update_field` if another writer
+    /// updates the document in between.
+    pub fn set_metadata(
+        &self,
+        id: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<Option<String>, DBError> {
+        loop {
+            let existing = self.get(id)?;
+            let mut doc = (*existing).clone();
+            let previous = doc.metadata.insert(key.to_string(), value.to_string());
+
+            match self.compare_and_swap(id, existing.version, doc) {
+                Ok(()) => return Ok(previous),
+                Err(DBError::Conflict(..)) => continue,
+                Err(e) => return Err(e),
+            }
         }
+    }
 
-        let old_doc = self.documents.insert(doc_id, doc_arc);
-        Ok(old_doc)
+    /// Return every document whose metadata holds all of `filters`, ordered
+    /// by document id.
+    ///
+    /// Metadata is not indexed, so this scans every document.
+    pub fn query_by_metadata(&self, filters: &[(String, String)]) -> Vec<Arc<DocumentStored>> {
+        let mut results: Vec<Arc<DocumentStored>> = self
+            .documents
+            .iter()
+            .filter(|entry| {
+                filters
+                    .iter()
+                    .all(|(key, value)| entry.metadata.get(key) == Some(value))
+            })
+            .map(|entry| Arc::clone(entry.value()))
+            .collect();
+        results.sort_by(|a, b| a.id.cmp(&b.id));
+        results
+    }
+
+    /// Whether a document with `id` is stored, without cloning it
+    pub fn contains(&self, id: &str) -> bool {
+        self.documents.contains_key(id)
     }
 
     pub fn get(&self, id: &str) -> Result<Arc<DocumentStored>, DBError> {
@@ -70,27 +463,687 @@ impl InMemoryDB {
             .ok_or_else(|| DBError::NotFound(id.to_string()))
     }
 
+    /// Return every document carrying `token`, ordered by document id.
+    ///
+    /// Sorting the matched ids costs O(m log m) for m matches, which keeps
+    /// output stable across runs regardless of hash iteration order.
+    /// Documents only match through fields that have not expired.
     pub fn query_by_token(&self, token: &str) -> Vec<Arc<DocumentStored>> {
+        if !self.may_contain_token(token) {
+            return Vec::new();
+        }
         let doc_ids = match self.token_index.get(token) {
-            Some(ids) => ids.clone(),
+            Some(ids) => ids.iter().cloned().collect(),
             None => return Vec::new(),
         };
 
-        let mut results = Vec::with_capacity(doc_ids.len());
-        for id in doc_ids {
-            if let Some(doc) = self.documents.get(&id) {
-                results.push(Arc::clone(&doc));
+        let now = unix_now();
+        let mut results = self.resolve_sorted(doc_ids);
+        results.retain(|doc| carries_live_token(doc, token, now));
+        results
+    }
+
+    /// Like `query_by_token`, but stop once `max` documents are collected.
+    ///
+    /// Matches are taken in index order and not sorted, so which documents
+    /// are returned when there are more than `max` is unspecified and may
+    /// differ between runs. Only the matched ids are copied in full; no more
+    /// than `max` documents are resolved beyond those that turn out expired.
+    pub fn query_by_token_limited(&self, token: &str, max: usize) -> Vec<Arc<DocumentStored>> {
+        if max == 0 {
+            return Vec::new();
+        }
+        self.query_by_token_iter(token).take(max).collect()
+    }
+
+    /// Iterate over the documents carrying an unexpired `token`.
+    ///
+    /// The matching ids are copied when this is called, so writes made
+    /// while iterating never invalidate it, but each document is only
+    /// looked up when the iterator reaches it: stopping early (e.g. with
+    /// `take`) skips the rest, and documents removed in the meantime are
+    /// left out. Order is unspecified.
+    pub fn query_by_token_iter<'a>(
+        &'a self,
+        token: &'a str,
+    ) -> impl Iterator<Item = Arc<DocumentStored>> + 'a {
+        let doc_ids: Vec<String> = if self.may_contain_token(token) {
+            self.token_index
+                .get(token)
+                .map(|ids| ids.iter().cloned().collect())
+                .unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        let now = unix_now();
+        doc_ids
+            .into_iter()
+            .filter_map(move |id| self.documents.get(&id).map(|doc| Arc::clone(&doc)))
+            .filter(move |doc| carries_live_token(doc, token, now))
+    }
+
+    /// Like `query_by_token`, but resolve and filter the matched documents
+    /// on several threads when there are at least `PARALLEL_MIN_MATCHES`.
+    ///
+    /// Each thread handles a contiguous run of the sorted ids and the runs
+    /// are concatenated in order, so results are identical to the serial
+    /// version.
+    #[cfg(feature = "parallel")]
+    pub fn query_by_token_par(&self, token: &str) -> Vec<Arc<DocumentStored>> {
+        if !self.may_contain_token(token) {
+            return Vec::new();
+        }
+        let mut doc_ids: Vec<String> = match self.token_index.get(token) {
+            Some(ids) => ids.iter().cloned().collect(),
+            None => return Vec::new(),
+        };
+
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        if doc_ids.len() < PARALLEL_MIN_MATCHES || threads < 2 {
+            let now = unix_now();
+            let mut results = self.resolve_sorted(doc_ids);
+            results.retain(|doc| carries_live_token(doc, token, now));
+            return results;
+        }
+
+        doc_ids.sort();
+        let now = unix_now();
+        let chunk_len = doc_ids.len().div_ceil(threads);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = doc_ids
+                .chunks(chunk_len)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .filter_map(|id| self.documents.get(id).map(|doc| Arc::clone(&doc)))
+                            .filter(|doc| carries_live_token(doc, token, now))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("query worker panicked"))
+                .collect()
+        })
+    }
+
+    /// Like `query_by_token`, but only match through `field`: documents
+    /// carrying `token` in other fields only are left out.
+    pub fn query_by_field_token(&self, field: &str, token: &str) -> Vec<Arc<DocumentStored>> {
+        let now = unix_now();
+        let mut results = self.query_by_token(token);
+        results.retain(|doc| {
+            doc.fields
+                .get(field)
+                .is_some_and(|f| !f.is_expired(now) && f.tokens().any(|t| tokens_equal(t, token)))
+        });
+        results
+    }
+
+    /// Like `query_by_token`, but only keep documents where `verify` accepts
+    /// one of the unexpired fields carrying `token`.
+    ///
+    /// Tokens are truncated to 8 bytes, so distinct values can collide;
+    /// `verify` lets the caller rule out such impostors, typically by
+    /// decrypting the field and comparing it with the searched value.
+    pub fn query_by_token_verified<F>(&self, token: &str, verify: F) -> Vec<Arc<DocumentStored>>
+    where
+        F: Fn(&DocumentStored, &FieldMaterialized) -> bool,
+    {
+        let now = unix_now();
+        let mut results = self.query_by_token(token);
+        results.retain(|doc| {
+            doc.fields.values().any(|f| {
+                !f.is_expired(now) && f.tokens().any(|t| tokens_equal(t, token)) && verify(doc, f)
+            })
+        });
+        results
+    }
+
+    /// Return every document containing an unexpired `field`, ordered by
+    /// document id.
+    pub fn query_by_field(&self, field: &str) -> Vec<Arc<DocumentStored>> {
+        let doc_ids = match self.field_index.get(field) {
+            Some(ids) => ids.iter().cloned().collect(),
+            None => return Vec::new(),
+        };
+
+        let now = unix_now();
+        let mut results = self.resolve_sorted(doc_ids);
+        results.retain(|doc| doc.fields.get(field).is_some_and(|f| !f.is_expired(now)));
+        results
+    }
+
+    /// Return every document whose unexpired `field` has a recorded value
+    /// length of `len` bytes, ordered by document id.
+    ///
+    /// Scans the documents carrying `field`; fields written before lengths
+    /// were recorded never match.
+    pub fn query_by_length(&self, field: &str, len: usize) -> Vec<Arc<DocumentStored>> {
+        let mut results = self.query_by_field(field);
+        results.retain(|doc| {
+            doc.fields
+                .get(field)
+                .is_some_and(|f| f.value_len == Some(len))
+        });
+        results
+    }
+
+    /// Return every document with a prefix-indexed field starting with
+    /// `prefix`, ordered by document id.
+    ///
+    /// Prefixes shorter than `PREFIX_MIN_LEN` characters are never indexed
+    /// and always return no results.
+    pub fn query_by_prefix(
+        &self,
+        algo: TokenAlgo,
+        encoding: TokenEncoding,
+        key: &[u8; 32],
+        prefix: &str,
+    ) -> Vec<Arc<DocumentStored>> {
+        if prefix.chars().count() < PREFIX_MIN_LEN {
+            return Vec::new();
+        }
+        self.query_by_token(&tokenize_prefix(algo, encoding, key, prefix))
+    }
+
+    /// Return every document whose numeric `field` lies in `[min, max]`,
+    /// ordered by document id.
+    ///
+    /// The range index only narrows candidates to power-of-two buckets, so
+    /// each candidate field is decrypted with `key` and compared exactly.
+    pub fn query_range(
+        &self,
+        algo: TokenAlgo,
+        encoding: TokenEncoding,
+        key: &[u8; 32],
+        field: &str,
+        min: f64,
+        max: f64,
+    ) -> Vec<Arc<DocumentStored>> {
+        let mut doc_ids = HashSet::new();
+        for range_token in tokenize_range_query(algo, encoding, key, min, max) {
+            if let Some(ids) = self.range_index.get(&(field.to_string(), range_token)) {
+                doc_ids.extend(ids.iter().cloned());
             }
         }
+
+        let now = unix_now();
+        let mut results = self.resolve_sorted(doc_ids.into_iter().collect());
+        results.retain(|doc| {
+            let Ok(doc_key) = doc.field_key(key) else {
+                return false;
+            };
+            doc.fields
+                .get(field)
+                .filter(|field_data| !field_data.is_expired(now))
+                .and_then(|field_data| {
+                    decrypt_padded(
+                        &field_data.cipher,
+                        &field_data.nonce,
+                        &doc_key,
+                        field_data.pad_block,
+                    )
+                    .ok()
+                })
+                .and_then(|plaintext| String::from_utf8(plaintext).ok())
+                .and_then(|text| text.parse::<f64>().ok())
+                .is_some_and(|value| min <= value && value <= max)
+        });
         results
     }
 
+    /// Decrypt every unexpired field, or only `field` when given, and
+    /// return the documents with a value `pattern` matches together with
+    /// the names of the matching fields, ordered by document id.
+    ///
+    /// This scans the whole database and holds plaintext in memory while
+    /// it does; fields that fail to decrypt or are not UTF-8 never match.
+    pub fn scan_decrypted(
+        &self,
+        key: &[u8; 32],
+        field: Option<&str>,
+        pattern: &Pattern,
+    ) -> Vec<(Arc<DocumentStored>, Vec<String>)> {
+        let now = unix_now();
+        let mut docs: Vec<Arc<DocumentStored>> = self
+            .documents
+            .iter()
+            .map(|entry| Arc::clone(entry.value()))
+            .collect();
+        docs.sort_by(|a, b| a.id.cmp(&b.id));
+
+        docs.into_iter()
+            .filter_map(|doc| {
+                let doc_key = doc.field_key(key).ok()?;
+                let mut matched: Vec<String> = doc
+                    .fields
+                    .iter()
+                    .filter(|(name, field_data)| {
+                        field.is_none_or(|field| field == name.as_str())
+                            && !field_data.is_expired(now)
+                    })
+                    .filter(|(_, field_data)| {
+                        field_data.values().any(|(_, cipher, nonce)| {
+                            decrypt_padded(cipher, nonce, &doc_key, field_data.pad_block)
+                                .ok()
+                                .and_then(|plaintext| String::from_utf8(plaintext).ok())
+                                .is_some_and(|text| pattern.is_match(&text))
+                        })
+                    })
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                matched.sort();
+                (!matched.is_empty()).then_some((doc, matched))
+            })
+            .collect()
+    }
+
+    /// Count documents carrying `token` without materializing them.
+    ///
+    /// Counts come straight from the index, so they include fields that
+    /// have expired but not yet been swept.
+    pub fn count_by_token(&self, token: &str) -> usize {
+        if !self.may_contain_token(token) {
+            return 0;
+        }
+        self.token_index.get(token).map_or(0, |ids| ids.len())
+    }
+
+    /// Return the ids of documents carrying `token`, sorted, without
+    /// looking up or cloning any document. The cheapest query, for callers
+    /// that only need ids, e.g. to join against other data.
+    ///
+    /// Like `count_by_token`, ids come straight from the index, so they
+    /// include documents whose matching field has expired but not yet been
+    /// swept; otherwise they are the ids `query_by_token` returns.
+    pub fn query_ids_by_token(&self, token: &str) -> Vec<String> {
+        if !self.may_contain_token(token) {
+            return Vec::new();
+        }
+        let mut ids: Vec<String> = match self.token_index.get(token) {
+            Some(ids) => ids.iter().cloned().collect(),
+            None => return Vec::new(),
+        };
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Documents holding any of `tokens`, each paired with how many of the
+    /// distinct tokens it holds, most matches first and ties in id order.
+    /// Each token is matched like `query_by_token`, so expired fields do
+    /// not count.
+    pub fn query_ranked(&self, tokens: &[String]) -> Vec<(Arc<DocumentStored>, usize)> {
+        let distinct: HashSet<&String> = tokens.iter().collect();
+        let mut scores: HashMap<String, (Arc<DocumentStored>, usize)> = HashMap::new();
+        for token in distinct {
+            for doc in self.query_by_token_iter(token) {
+                scores.entry(doc.id.clone()).or_insert((doc, 0)).1 += 1;
+            }
+        }
+        rank(scores.into_values().collect())
+    }
+
+    /// Documents whose trigram-indexed `field` shares at least
+    /// `min_overlap` (and at least one) trigram tokens with `value`, each
+    /// paired with how many it shares, most first and ties in id order.
+    ///
+    /// Only fields stored with trigram tokens can match, and only by their
+    /// first value; expired fields never do.
+    pub fn query_fuzzy(
+        &self,
+        algo: TokenAlgo,
+        encoding: TokenEncoding,
+        key: &[u8; 32],
+        field: &str,
+        value: &str,
+        min_overlap: usize,
+    ) -> Vec<(Arc<DocumentStored>, usize)> {
+        let now = unix_now();
+        let mut scores: HashMap<String, (Arc<DocumentStored>, usize)> = HashMap::new();
+        for token in tokenize_trigrams(algo, encoding, key, value) {
+            for doc in self.query_by_token_iter(&token) {
+                let shares = doc.fields.get(field).is_some_and(|field_data| {
+                    !field_data.is_expired(now)
+                        && field_data
+                            .trigram_tokens
+                            .iter()
+                            .any(|trigram| tokens_equal(trigram, &token))
+                });
+                if shares {
+                    scores.entry(doc.id.clone()).or_insert((doc, 0)).1 += 1;
+                }
+            }
+        }
+        scores.retain(|_, (_, score)| *score >= min_overlap.max(1));
+        rank(scores.into_values().collect())
+    }
+
+    /// Count documents containing `field` without materializing them.
+    pub fn count_by_field(&self, field: &str) -> usize {
+        self.field_index.get(field).map_or(0, |ids| ids.len())
+    }
+
+    /// Remove every document that `query_by_token` would return for
+    /// `token`, returning the removed ids in order.
+    ///
+    /// The matching ids are collected before anything is removed, so the
+    /// index is never mutated while it is being read.
+    pub fn remove_by_token(&self, token: &str) -> Vec<String> {
+        let ids: Vec<String> = self
+            .query_by_token(token)
+            .into_iter()
+            .map(|doc| doc.id.clone())
+            .collect();
+
+        ids.into_iter()
+            .filter(|id| self.remove(id).is_ok())
+            .collect()
+    }
+
+    /// Every field name used by at least one document, sorted
+    pub fn field_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .field_index
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Remove `id` and its index entries, returning the removed document,
+    /// or `DBError::NotFound` if no document has that id.
+    ///
+    /// Index entries go before the document does, so an id found in an
+    /// index always resolves to a document.
     pub fn remove(&self, id: &str) -> Result<Arc<DocumentStored>, DBError> {
-        if let Some((_key, doc)) = self.documents.remove(id) {
-            self.cleanup_indexes(id, &doc);
-            Ok(doc)
-        } else {
-            Err(DBError::NotFound(id.to_string()))
+        let removed = match self.documents.entry(id.to_string()) {
+            Entry::Occupied(entry) => {
+                self.cleanup_indexes(id, entry.get());
+                entry.remove()
+            }
+            Entry::Vacant(_) => return Err(DBError::NotFound(id.to_string())),
+        };
+        self.notify(&ChangeEvent::Removed(id.to_string()));
+        Ok(removed)
+    }
+
+    /// Like `remove`, but report whether a document was removed instead of
+    /// treating a missing id as an error
+    pub fn remove_if_exists(&self, id: &str) -> bool {
+        self.remove(id).is_ok()
+    }
+
+    /// Store a copy of `src_id` under `dst_id`, indexed under the new id.
+    ///
+    /// Fields are copied as they are stored: nothing is re-encrypted, since
+    /// the key is the same. The copy starts at version 1 with fresh
+    /// timestamps and is independent of the source from then on. Fails if
+    /// `dst_id` already exists.
+    pub fn copy(&self, src_id: &str, dst_id: &str) -> Result<(), DBError> {
+        validate_id(dst_id)?;
+        let mut doc = (*self.get(src_id)?).clone();
+        doc.id = dst_id.to_string();
+        doc.version = 1;
+        doc.touch(None);
+
+        match self.documents.entry(dst_id.to_string()) {
+            Entry::Occupied(_) => return Err(DBError::Duplicate(dst_id.to_string())),
+            Entry::Vacant(entry) => {
+                let stored = entry.insert(Arc::new(doc));
+                self.index_document(dst_id, &stored);
+            }
+        }
+        self.notify_write(dst_id.to_string(), false);
+        Ok(())
+    }
+
+    /// Move a document to a new id, rewriting its index entries.
+    pub fn rename(&self, old_id: &str, new_id: &str) -> Result<(), DBError> {
+        validate_id(new_id)?;
+        if self.documents.contains_key(new_id) {
+            return Err(DBError::Duplicate(new_id.to_string()));
+        }
+
+        let old_doc = self.remove(old_id)?;
+
+        let mut doc = (*old_doc).clone();
+        doc.id = new_id.to_string();
+
+        match self.documents.entry(new_id.to_string()) {
+            Entry::Occupied(_) => {
+                // Lost a race with a concurrent insert of `new_id`; put the
+                // original back rather than clobbering it.
+                self.upsert_unvalidated((*old_doc).clone())?;
+                Err(DBError::Duplicate(new_id.to_string()))
+            }
+            Entry::Vacant(entry) => {
+                let stored = entry.insert(Arc::new(doc));
+                self.index_document(new_id, &stored);
+                drop(stored);
+                self.notify(&ChangeEvent::Inserted(new_id.to_string()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove every field that expired at or before `now` (unix seconds),
+    /// dropping documents left with no fields. Returns the number of fields
+    /// removed.
+    pub fn sweep_expired(&self, now: i64) -> usize {
+        let candidates: Vec<String> = self
+            .documents
+            .iter()
+            .filter(|entry| entry.fields.values().any(|f| f.is_expired(now)))
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        let mut removed = 0;
+        for id in candidates {
+            let Entry::Occupied(mut entry) = self.documents.entry(id.clone()) else {
+                continue;
+            };
+
+            let mut doc = (**entry.get()).clone();
+            let before = doc.fields.len();
+            doc.fields.retain(|_, f| !f.is_expired(now));
+            if doc.fields.len() == before {
+                continue;
+            }
+            removed += before - doc.fields.len();
+
+            let event = if doc.fields.is_empty() {
+                self.cleanup_indexes(&id, entry.get());
+                entry.remove();
+                ChangeEvent::Removed(id)
+            } else {
+                doc.version += 1;
+                doc.touch(Some(entry.get()));
+                let doc_arc = Arc::new(doc);
+                let old = entry.insert(Arc::clone(&doc_arc));
+                self.cleanup_indexes(&id, &old);
+                self.index_document(&id, &doc_arc);
+                drop(entry);
+                ChangeEvent::Updated(id)
+            };
+            self.notify(&event);
+        }
+        removed
+    }
+
+    /// Discard every index and repopulate it from the stored documents, so
+    /// indexes that drifted from the documents match them again.
+    ///
+    /// Lookups running at the same time may miss documents until the
+    /// rebuild finishes.
+    pub fn rebuild_indexes(&self) {
+        self.token_index.clear();
+        self.field_index.clear();
+        self.range_index.clear();
+        if let Some(filter) = &self.token_filter {
+            filter.clear();
+        }
+
+        for entry in self.documents.iter() {
+            self.index_document(entry.key(), entry.value());
+        }
+    }
+
+    /// Compare the indexes with the entries `rebuild_indexes` would make
+    /// from the stored documents. Missing entries make queries skip
+    /// documents they should find; stale ones (left behind by removals and
+    /// replaced versions) are only filtered out at query time.
+    pub fn check_indexes(&self) -> IndexCheck {
+        let mut tokens: HashSet<(String, String)> = HashSet::new();
+        let mut fields: HashSet<(String, String)> = HashSet::new();
+        let mut ranges: HashSet<((String, String), String)> = HashSet::new();
+        for entry in self.documents.iter() {
+            let (id, doc) = (entry.key(), entry.value());
+            for (field_name, field_data) in &doc.fields {
+                for token in field_data.tokens() {
+                    tokens.insert((token.clone(), id.clone()));
+                }
+                fields.insert((field_name.clone(), id.clone()));
+                if let Some(range_token) = &field_data.range_token {
+                    ranges.insert(((field_name.clone(), range_token.clone()), id.clone()));
+                }
+            }
+        }
+
+        let (missing_tokens, stale_tokens) = compare_index(&self.token_index, &tokens);
+        let (missing_fields, stale_fields) = compare_index(&self.field_index, &fields);
+        let (missing_ranges, stale_ranges) = compare_index(&self.range_index, &ranges);
+        IndexCheck {
+            missing_tokens,
+            missing_fields,
+            missing_ranges,
+            stale: stale_tokens + stale_fields + stale_ranges,
+        }
+    }
+
+    /// Remove index entries whose document no longer exists, returning how
+    /// many were dropped from each index. Lookups already skip such ids;
+    /// this keeps them from accumulating.
+    ///
+    /// Each id is only dropped while its document entry is locked and
+    /// empty, so an id whose document is being written concurrently stays.
+    pub fn compact_indexes(&self) -> IndexCompaction {
+        let tokens = self.prune_dangling(&self.token_index);
+        if let Some(filter) = &self.token_filter {
+            for token in &tokens {
+                filter.remove(token);
+            }
+        }
+
+        IndexCompaction {
+            token_ids: tokens.len(),
+            field_ids: self.prune_dangling(&self.field_index).len(),
+            range_ids: self.prune_dangling(&self.range_index).len(),
+        }
+    }
+
+    /// Drop the ids in `index` that do not resolve to a document, returning
+    /// the key of each one dropped.
+    ///
+    /// Ids are copied out of the index before `documents` is consulted, as
+    /// in `resolve_sorted`, so no index guard is held while a document
+    /// entry is locked.
+    fn prune_dangling<K>(&self, index: &DashMap<K, HashSet<String>>) -> Vec<K>
+    where
+        K: Clone + Eq + std::hash::Hash,
+    {
+        let keys: Vec<K> = index.iter().map(|entry| entry.key().clone()).collect();
+
+        let mut pruned = Vec::new();
+        for key in keys {
+            let ids: Vec<String> = match index.get(&key) {
+                Some(ids) => ids.iter().cloned().collect(),
+                None => continue,
+            };
+            for id in ids {
+                if self.documents.contains_key(&id) {
+                    continue;
+                }
+                let Entry::Vacant(_slot) = self.documents.entry(id.clone()) else {
+                    continue;
+                };
+                if let Some(mut key_ids) = index.get_mut(&key) {
+                    if key_ids.remove(&id) {
+                        pruned.push(key.clone());
+                    }
+                    if key_ids.is_empty() {
+                        drop(key_ids);
+                        index.remove(&key);
+                    }
+                }
+            }
+        }
+        pruned
+    }
+
+    /// Stop maintaining indexes on writes, for bulk loads that index once at
+    /// the end with `build_indexes`. The indexes are emptied, so until then
+    /// token, field and range lookups find nothing; callers that query
+    /// should check `is_indexing_deferred` first.
+    pub fn defer_indexing(&self) {
+        self.indexing_deferred.store(true, Ordering::SeqCst);
+        self.rebuild_indexes();
+    }
+
+    /// Leave deferred mode and index every stored document. Writes made
+    /// while this runs may be missed, so call it once the load is done.
+    pub fn build_indexes(&self) {
+        self.indexing_deferred.store(false, Ordering::SeqCst);
+        self.rebuild_indexes();
+    }
+
+    /// Whether writes are currently leaving the indexes alone
+    pub fn is_indexing_deferred(&self) -> bool {
+        self.indexing_deferred.load(Ordering::SeqCst)
+    }
+
+    /// How the database would shrink if the documents `ids` were removed,
+    /// without removing anything. Ids that are not stored are ignored.
+    pub fn removal_impact(&self, ids: &[String]) -> RemovalImpact {
+        let docs: Vec<Arc<DocumentStored>> =
+            ids.iter().filter_map(|id| self.get(id).ok()).collect();
+
+        let mut tokens: HashMap<&String, HashSet<&str>> = HashMap::new();
+        let mut fields: HashMap<&String, HashSet<&str>> = HashMap::new();
+        let mut field_count = 0;
+        for doc in &docs {
+            field_count += doc.fields.len();
+            for (field_name, field_data) in &doc.fields {
+                for token in field_data.tokens() {
+                    tokens.entry(token).or_default().insert(&doc.id);
+                }
+                fields.entry(field_name).or_default().insert(&doc.id);
+            }
+        }
+
+        // An index entry goes away once every id it lists is removed
+        let freed = |index: &DashMap<String, HashSet<String>>,
+                     removing: HashMap<&String, HashSet<&str>>| {
+            removing
+                .into_iter()
+                .filter(|(key, removed)| {
+                    index
+                        .get(*key)
+                        .is_some_and(|ids| ids.iter().all(|id| removed.contains(id.as_str())))
+                })
+                .count()
+        };
+
+        RemovalImpact {
+            documents: docs.len(),
+            fields: field_count,
+            tokens: freed(&self.token_index, tokens),
+            field_names: freed(&self.field_index, fields),
         }
     }
 
@@ -98,6 +1151,77 @@ impl InMemoryDB {
         self.documents.clear();
         self.token_index.clear();
         self.field_index.clear();
+        self.range_index.clear();
+        if let Some(filter) = &self.token_filter {
+            filter.clear();
+        }
+        self.notify(&ChangeEvent::Cleared);
+    }
+
+    /// Run `listener` after every write from now on: each insert, update
+    /// and removal of a document and each `clear`.
+    ///
+    /// Listeners run on the writing thread once the write is visible and
+    /// its locks are released, so they may read from this database. A
+    /// transaction reports each document it writes separately.
+    pub fn on_change<F>(&self, listener: F)
+    where
+        F: Fn(&ChangeEvent) + Send + Sync + 'static,
+    {
+        self.listeners
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Arc::new(listener));
+    }
+
+    fn notify(&self, event: &ChangeEvent) {
+        // Cloned out so a listener may register another without deadlocking
+        let listeners = self
+            .listeners
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        for listener in listeners {
+            listener(event);
+        }
+    }
+
+    fn notify_write(&self, id: String, replaced: bool) {
+        self.notify(&if replaced {
+            ChangeEvent::Updated(id)
+        } else {
+            ChangeEvent::Inserted(id)
+        });
+    }
+
+    /// Copy every document into a serializable point-in-time snapshot.
+    ///
+    /// Writes may continue while the snapshot is taken. DashMap iteration
+    /// locks one shard at a time, so the snapshot is only weakly consistent:
+    /// each document is captured whole, but writes that land during the
+    /// scan may or may not be included. Indexes are not captured; `restore`
+    /// rebuilds them from the documents so the two always agree.
+    pub fn snapshot(&self) -> DbSnapshot {
+        let mut documents: Vec<DocumentStored> = self
+            .documents
+            .iter()
+            .map(|entry| (**entry.value()).clone())
+            .collect();
+        documents.sort_by(|a, b| a.id.cmp(&b.id));
+
+        DbSnapshot { documents }
+    }
+
+    /// Build a database from a snapshot, reindexing every document
+    pub fn restore(snapshot: DbSnapshot) -> Self {
+        let db = Self::new();
+        for doc in snapshot.documents {
+            let doc_id = doc.id.clone();
+            let doc_arc = Arc::new(doc);
+            db.documents.insert(doc_id.clone(), Arc::clone(&doc_arc));
+            db.index_document(&doc_id, &doc_arc);
+        }
+        db
     }
 
     pub fn all_ids(&self) -> Vec<String> {
@@ -107,21 +1231,142 @@ impl InMemoryDB {
             .collect()
     }
 
+    /// Up to `limit` ids in sorted order, starting `offset` ids in; empty
+    /// once `offset` is past the end
+    pub fn ids_page(&self, offset: usize, limit: usize) -> Vec<String> {
+        let mut ids = self.all_ids();
+        ids.sort_unstable();
+        ids.into_iter().skip(offset).take(limit).collect()
+    }
+
+    /// Visit every stored document in a single pass over the map.
+    ///
+    /// Shard read locks are held while `f` runs, so the callback must not
+    /// call back into this database (e.g. `upsert` or `remove`) or it may
+    /// deadlock.
+    pub fn for_each_document<F: FnMut(&DocumentStored)>(&self, mut f: F) {
+        for entry in self.documents.iter() {
+            f(entry.value());
+        }
+    }
+
+    /// Number of stored documents
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Collect database statistics.
+    ///
+    /// The byte and field totals are summed over every stored field, so
+    /// this is O(total fields) rather than O(1).
     pub fn stats(&self) -> DBStats {
+        let mut total_cipher_bytes = 0;
+        let mut total_nonce_bytes = 0;
+        let mut total_field_count = 0;
+        self.for_each_document(|doc| {
+            for field_data in doc.fields.values() {
+                for (_, cipher, nonce) in field_data.values() {
+                    total_cipher_bytes += cipher.len();
+                    total_nonce_bytes += nonce.len();
+                }
+            }
+            total_field_count += doc.fields.len();
+        });
+
         DBStats {
             document_count: self.documents.len(),
             token_index_size: self.token_index.len(),
             field_index_size: self.field_index.len(),
+            field_distribution: self
+                .field_index
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().len()))
+                .collect(),
+            total_cipher_bytes,
+            total_nonce_bytes,
+            total_field_count,
+            token_filter_fp_rate: self
+                .token_filter
+                .as_ref()
+                .map(|filter| filter.false_positive_rate()),
         }
     }
 
+    /// `false` if no document carries `token`; always `true` without a
+    /// token filter
+    fn may_contain_token(&self, token: &str) -> bool {
+        self.token_filter
+            .as_ref()
+            .is_none_or(|filter| filter.may_contain(token))
+    }
+
+    fn index_document(&self, doc_id: &str, doc: &DocumentStored) {
+        if self.is_indexing_deferred() {
+            return;
+        }
+        for (field_name, field_data) in &doc.fields {
+            for token in field_data.tokens() {
+                // Added to the filter before the index so a concurrent
+                // lookup never sees an indexed token the filter rejects
+                if let Some(filter) = &self.token_filter {
+                    filter.insert(token);
+                }
+                self.token_index
+                    .entry(token.clone())
+                    .or_default()
+                    .insert(doc_id.to_string());
+            }
+
+            self.field_index
+                .entry(field_name.clone())
+                .or_default()
+                .insert(doc_id.to_string());
+
+            if let Some(range_token) = &field_data.range_token {
+                self.range_index
+                    .entry((field_name.clone(), range_token.clone()))
+                    .or_default()
+                    .insert(doc_id.to_string());
+            }
+        }
+    }
+
+    /// Resolve index ids to documents in id order.
+    ///
+    /// Takes the ids by value so callers release their index guard first;
+    /// `upsert` locks `documents` before the indexes, so resolving while an
+    /// index shard is held could deadlock.
+    fn resolve_sorted(&self, mut doc_ids: Vec<String>) -> Vec<Arc<DocumentStored>> {
+        doc_ids.sort();
+
+        let mut results = Vec::with_capacity(doc_ids.len());
+        for id in doc_ids {
+            if let Some(doc) = self.documents.get(&id) {
+                results.push(Arc::clone(&doc));
+            }
+        }
+        results
+    }
+
     fn cleanup_indexes(&self, doc_id: &str, doc: &DocumentStored) {
+        if self.is_indexing_deferred() {
+            return;
+        }
         for (field_name, field_data) in &doc.fields {
-            if let Some(mut token_ids) = self.token_index.get_mut(&field_data.token) {
-                token_ids.remove(doc_id);
-                if token_ids.is_empty() {
-                    drop(token_ids);
-                    self.token_index.remove(&field_data.token);
+            for token in field_data.tokens() {
+                if let Some(mut token_ids) = self.token_index.get_mut(token) {
+                    token_ids.remove(doc_id);
+                    if token_ids.is_empty() {
+                        drop(token_ids);
+                        self.token_index.remove(token);
+                    }
+                }
+                if let Some(filter) = &self.token_filter {
+                    filter.remove(token);
                 }
             }
 
@@ -132,13 +1377,172 @@ impl InMemoryDB {
                     self.field_index.remove(field_name);
                 }
             }
+
+            if let Some(range_token) = &field_data.range_token {
+                let key = (field_name.clone(), range_token.clone());
+                if let Some(mut range_ids) = self.range_index.get_mut(&key) {
+                    range_ids.remove(doc_id);
+                    if range_ids.is_empty() {
+                        drop(range_ids);
+                        self.range_index.remove(&key);
+                    }
+                }
+            }
         }
     }
 }
 
+/// Smallest match count `query_by_token_par` spreads across threads
+#[cfg(feature = "parallel")]
+pub const PARALLEL_MIN_MATCHES: usize = 1024;
+
+/// Longest document id or field name, in bytes, that writes accept
+pub const MAX_NAME_BYTES: usize = 1024;
+
+/// Reject document ids that are empty, only whitespace or longer than
+/// `MAX_NAME_BYTES`
+pub fn validate_id(id: &str) -> Result<(), DBError> {
+    validate_name("document id", id)
+}
+
+/// Reject field names that are empty, only whitespace or longer than
+/// `MAX_NAME_BYTES`
+pub fn validate_field_name(name: &str) -> Result<(), DBError> {
+    validate_name("field name", name)
+}
+
+fn validate_name(what: &str, name: &str) -> Result<(), DBError> {
+    if name.trim().is_empty() {
+        return Err(DBError::InvalidInput(format!(
+            "{} cannot be empty or only whitespace",
+            what
+        )));
+    }
+    if name.len() > MAX_NAME_BYTES {
+        return Err(DBError::InvalidInput(format!(
+            "{} is {} bytes; at most {} are allowed",
+            what,
+            name.len(),
+            MAX_NAME_BYTES
+        )));
+    }
+    Ok(())
+}
+
+/// Whether an unexpired field of `doc` carries `token`
+fn carries_live_token(doc: &DocumentStored, token: &str, now: i64) -> bool {
+    doc.fields
+        .values()
+        .any(|f| !f.is_expired(now) && f.tokens().any(|t| tokens_equal(t, token)))
+}
+
+/// How many of the `expected` `(key, id)` entries `index` lacks, and how
+/// many ids it lists beyond them
+fn compare_index<K>(
+    index: &DashMap<K, HashSet<String>>,
+    expected: &HashSet<(K, String)>,
+) -> (usize, usize)
+where
+    K: Clone + Eq + std::hash::Hash,
+{
+    let missing = expected
+        .iter()
+        .filter(|(key, id)| !index.get(key).is_some_and(|ids| ids.contains(id)))
+        .count();
+    let mut stale = 0;
+    for entry in index.iter() {
+        stale += entry
+            .value()
+            .iter()
+            .filter(|id| !expected.contains(&(entry.key().clone(), (*id).clone())))
+            .count();
+    }
+    (missing, stale)
+}
+
+/// Sort scored documents by score, highest first, then by id
+fn rank(mut scored: Vec<(Arc<DocumentStored>, usize)>) -> Vec<(Arc<DocumentStored>, usize)> {
+    scored.sort_unstable_by(|(a, a_score), (b, b_score)| {
+        b_score.cmp(a_score).then_with(|| a.id.cmp(&b.id))
+    });
+    scored
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Serializable copy of an `InMemoryDB`'s documents, ordered by id
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DbSnapshot {
+    pub documents: Vec<DocumentStored>,
+}
+
+/// Result of `InMemoryDB::removal_impact`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RemovalImpact {
+    pub documents: usize,
+    pub fields: usize,
+    /// Token index entries that would be dropped
+    pub tokens: usize,
+    /// Field index entries that would be dropped
+    pub field_names: usize,
+}
+
+/// Result of `InMemoryDB::check_indexes`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexCheck {
+    /// Document tokens the token index does not list the document under
+    pub missing_tokens: usize,
+    /// Document fields the field index does not list the document under
+    pub missing_fields: usize,
+    /// Numeric fields the range index does not list the document under
+    pub missing_ranges: usize,
+    /// Index entries naming a document that no longer holds the token,
+    /// field or range, or no longer exists
+    pub stale: usize,
+}
+
+impl IndexCheck {
+    /// Whether every document can be found through every index it belongs
+    /// in; stale entries do not count, since queries filter them out
+    pub fn is_consistent(&self) -> bool {
+        self.missing() == 0
+    }
+
+    /// Entries missing across all indexes
+    pub fn missing(&self) -> usize {
+        self.missing_tokens + self.missing_fields + self.missing_ranges
+    }
+}
+
+/// Result of `InMemoryDB::compact_indexes`: ids dropped from each index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexCompaction {
+    pub token_ids: usize,
+    pub field_ids: usize,
+    pub range_ids: usize,
+}
+
+impl IndexCompaction {
+    /// Ids dropped across all indexes
+    pub fn total(&self) -> usize {
+        self.token_ids + self.field_ids + self.range_ids
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DBStats {
     pub document_count: usize,
     pub token_index_size: usize,
     pub field_index_size: usize,
+    /// Number of documents carrying each field name
+    pub field_distribution: HashMap<String, usize>,
+    pub total_cipher_bytes: usize,
+    pub total_nonce_bytes: usize,
+    pub total_field_count: usize,
+    /// Estimated false-positive rate of the token filter, if enabled
+    pub token_filter_fp_rate: Option<f64>,
 }
@@ -1,15 +1,330 @@
-use serde::{Serialize, Deserialize};
+use crate::encoding::{base64_array, base64_bytes};
+use magentadb_crypto::{decrypt_padded, unwrap_key, wrap_key, CryptoError, Nonce};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FieldMaterialized {
+    #[serde(with = "base64_bytes")]
     pub cipher: Vec<u8>,
-    pub nonce: Vec<u8>,
+    #[serde(with = "base64_array")]
+    pub nonce: Nonce,
     pub token: String,
     pub masked: String,
+    /// Prefix tokens, present only for fields indexed for prefix search
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prefix_tokens: Vec<String>,
+    /// Trigram tokens of the first value, present only for fields indexed
+    /// for fuzzy search
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trigram_tokens: Vec<String>,
+    /// Magnitude-bucket token, present only for numeric fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range_token: Option<String>,
+    /// Unix time (seconds) after which the field is treated as absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// Encrypted with `encrypt_deterministic`, so equal values share ciphertext
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deterministic: bool,
+    /// Block size the plaintext was padded to with `pad` before encryption
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pad_block: Option<usize>,
+    /// Plaintext length in bytes, recorded for `query_by_length`. Without
+    /// padding this is already implied by the ciphertext size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_len: Option<usize>,
+    /// Values added after the first with `InMemoryDB::append_field_value`,
+    /// encrypted with the same options and each matched by its own token
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_values: Vec<FieldValue>,
+    /// Whether the field has tokens at all. Fields stored without them are
+    /// left out of the token index, never match a value query and can only
+    /// be read by document id.
+    #[serde(default = "default_searchable", skip_serializing_if = "is_searchable")]
+    pub searchable: bool,
+    /// What the plaintext encodes, so it can be read back as that type.
+    /// Fields written before types were recorded are strings.
+    #[serde(default, skip_serializing_if = "ValueType::is_string")]
+    pub value_type: ValueType,
+}
+
+fn default_searchable() -> bool {
+    true
+}
+
+fn is_searchable(searchable: &bool) -> bool {
+    *searchable
+}
+
+/// One further value of a multi-value field
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FieldValue {
+    #[serde(with = "base64_bytes")]
+    pub cipher: Vec<u8>,
+    #[serde(with = "base64_array")]
+    pub nonce: Nonce,
+    pub token: String,
+}
+
+impl FieldMaterialized {
+    /// All tokens this field contributes to the token index
+    pub fn tokens(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.token)
+            .chain(self.prefix_tokens.iter())
+            .chain(self.trigram_tokens.iter())
+            .chain(self.extra_values.iter().map(|value| &value.token))
+            .filter(|_| self.searchable)
+    }
+
+    /// `(token, cipher, nonce)` of the first value followed by those of any
+    /// appended values
+    pub fn values(&self) -> impl Iterator<Item = (&str, &[u8], &Nonce)> {
+        std::iter::once((self.token.as_str(), &self.cipher[..], &self.nonce)).chain(
+            self.extra_values
+                .iter()
+                .map(|value| (value.token.as_str(), &value.cipher[..], &value.nonce)),
+        )
+    }
+
+    /// Number of values the field holds
+    pub fn value_count(&self) -> usize {
+        1 + self.extra_values.len()
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// What a field's plaintext encodes. Every type is stored as text:
+/// numbers as written by `f64`'s `Display`, booleans as `true` or `false`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueType {
+    #[default]
+    String,
+    Number,
+    Bool,
+}
+
+impl ValueType {
+    pub fn is_string(&self) -> bool {
+        *self == ValueType::String
+    }
+
+    /// Check that `value` is a valid encoding of this type
+    pub fn check(self, value: &str) -> Result<(), String> {
+        let valid = match self {
+            ValueType::String => true,
+            ValueType::Number => value.parse::<f64>().is_ok_and(f64::is_finite),
+            ValueType::Bool => matches!(value, "true" | "false"),
+        };
+        match (valid, self) {
+            (true, _) => Ok(()),
+            (false, ValueType::Number) => Err(format!("Value '{}' is not a finite number", value)),
+            (false, _) => Err(format!("Value '{}' is not a valid {}", value, self)),
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueType::String => f.write_str("string"),
+            ValueType::Number => f.write_str("number"),
+            ValueType::Bool => f.write_str("bool"),
+        }
+    }
+}
+
+/// How much of a value its display form reveals
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskPolicy {
+    /// A fixed redaction only
+    None,
+    /// The first `n` characters, then a redaction
+    FirstN(usize),
+    /// A fixed redaction plus a coarse length bucket
+    #[default]
+    LengthHint,
+}
+
+impl fmt::Display for MaskPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaskPolicy::None => f.write_str("none"),
+            MaskPolicy::FirstN(n) => write!(f, "first:{}", n),
+            MaskPolicy::LengthHint => f.write_str("length-hint"),
+        }
+    }
+}
+
+impl FromStr for MaskPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(MaskPolicy::None),
+            "length-hint" => Ok(MaskPolicy::LengthHint),
+            _ => s
+                .strip_prefix("first:")
+                .and_then(|n| n.parse().ok())
+                .map(MaskPolicy::FirstN)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown mask policy '{}' (expected none, length-hint or first:N)",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+/// Display form of a field value under `policy`. Only `FirstN` reveals
+/// plaintext characters; no policy reveals token bytes.
+pub fn mask(value: &str, policy: MaskPolicy) -> String {
+    match policy {
+        MaskPolicy::None => "•••".to_string(),
+        MaskPolicy::FirstN(n) => format!("{}•••", value.chars().take(n).collect::<String>()),
+        MaskPolicy::LengthHint => {
+            let hint = match value.chars().count() {
+                0 => "empty",
+                1..=7 => "short",
+                8..=31 => "medium",
+                _ => "long",
+            };
+            format!("••• ({})", hint)
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DocumentStored {
     pub id: String,
     pub fields: std::collections::HashMap<String, FieldMaterialized>,
+    /// Incremented on every write, used by `compare_and_swap`
+    #[serde(default)]
+    pub version: u64,
+    /// Per-document data key that this document's fields are encrypted
+    /// under; documents without one use the master key directly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapped_key: Option<WrappedKey>,
+    /// Unix time (milliseconds) of the first write; absent for documents
+    /// stored before timestamps were tracked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>,
+    /// Unix time (milliseconds) of the latest write
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<i64>,
+    /// Labels stored in plaintext: never encrypted or tokenized, only
+    /// matched by scanning
+    #[serde(default, skip_serializing_if = "std::collections::HashMap::is_empty")]
+    pub metadata: std::collections::HashMap<String, String>,
+    /// Unix time (milliseconds) the document was soft-deleted. A deleted
+    /// document stays in the file, so it can be restored, but is never
+    /// loaded for queries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+}
+
+impl DocumentStored {
+    /// Stamp a write made now: `created_at` is carried over from
+    /// `previous`, or set to now when there is no previous version
+    pub fn touch(&mut self, previous: Option<&DocumentStored>) {
+        let now = unix_millis();
+        self.created_at = match previous {
+            Some(previous) => previous.created_at,
+            None => Some(now),
+        };
+        self.updated_at = Some(now);
+    }
+
+    /// Whether the document was soft-deleted and awaits restoring or purging
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// The key this document's fields are encrypted under
+    pub fn field_key(&self, master: &[u8; 32]) -> Result<[u8; 32], CryptoError> {
+        match &self.wrapped_key {
+            Some(wrapped) => wrapped.unwrap(master),
+            None => Ok(*master),
+        }
+    }
+}
+
+/// Current unix time in milliseconds
+pub fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+/// A data key encrypted under the master key. Tokens are always derived
+/// from the master key, so search still works across documents.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WrappedKey {
+    #[serde(with = "base64_array")]
+    pub nonce: Nonce,
+    #[serde(with = "base64_bytes")]
+    pub cipher: Vec<u8>,
+}
+
+impl WrappedKey {
+    pub fn new(key: &[u8; 32], master: &[u8; 32]) -> Result<Self, CryptoError> {
+        let (nonce, cipher) = wrap_key(key, master)?;
+        Ok(Self { nonce, cipher })
+    }
+
+    pub fn unwrap(&self, master: &[u8; 32]) -> Result<[u8; 32], CryptoError> {
+        unwrap_key(&self.nonce, &self.cipher, master)
+    }
+}
+
+/// A field whose ciphertext failed to authenticate or unpad
+#[derive(Debug, Clone)]
+pub struct VerifyFailure {
+    pub id: String,
+    pub field: String,
+    pub error: String,
+}
+
+/// Decrypt every field of `documents` with `key`, returning the fields that
+/// fail ordered by document id and field name
+pub fn verify_documents<'a>(
+    documents: impl IntoIterator<Item = &'a DocumentStored>,
+    key: &[u8; 32],
+) -> Vec<VerifyFailure> {
+    let mut failures = Vec::new();
+    for doc in documents {
+        let doc_key = match doc.field_key(key) {
+            Ok(doc_key) => doc_key,
+            Err(e) => {
+                failures.extend(doc.fields.keys().map(|field| VerifyFailure {
+                    id: doc.id.clone(),
+                    field: field.clone(),
+                    error: e.to_string(),
+                }));
+                continue;
+            }
+        };
+        for (field, data) in &doc.fields {
+            let failed = data.values().find_map(|(_, cipher, nonce)| {
+                decrypt_padded(cipher, nonce, &doc_key, data.pad_block).err()
+            });
+            if let Some(e) = failed {
+                failures.push(VerifyFailure {
+                    id: doc.id.clone(),
+                    field: field.clone(),
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+    failures.sort_by(|a, b| (&a.id, &a.field).cmp(&(&b.id, &b.field)));
+    failures
 }
@@ -1,6 +1,15 @@
+pub mod bloom;
 pub mod db;
 pub mod document;
+pub mod encoding;
+pub mod pattern;
+pub mod transaction;
+pub mod wal;
 
+pub use bloom::*;
 pub use db::*;
 pub use document::*;
+pub use pattern::*;
+pub use transaction::*;
+pub use wal::*;
 // pub use storage::*;
@@ -0,0 +1,84 @@
+use std::fmt;
+
+/// A literal pattern for scanning decrypted values.
+///
+/// The pattern matches any value containing it as a substring. A leading
+/// `^` anchors it to the start of the value and a trailing `$` to the end;
+/// a backslash makes the next punctuation character literal, so `\^`, `\$`
+/// and `\\` match those characters and `\.` is the same as `.`. Every other
+/// character, including regex metacharacters, stands for itself.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    literal: String,
+    anchored_start: bool,
+    anchored_end: bool,
+}
+
+#[derive(Debug)]
+pub struct PatternError {
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid pattern at position {}: {}",
+            self.position, self.message
+        )
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, PatternError> {
+        let (anchored_start, body) = match pattern.strip_prefix('^') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let offset = pattern.len() - body.len();
+
+        let mut literal = String::with_capacity(body.len());
+        let mut anchored_end = false;
+        let mut chars = body.char_indices().peekable();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, escaped)) if escaped.is_ascii_punctuation() => literal.push(escaped),
+                    Some(_) => {
+                        return Err(PatternError {
+                            position: offset + index,
+                            message: "only punctuation can be escaped",
+                        })
+                    }
+                    None => {
+                        return Err(PatternError {
+                            position: offset + index,
+                            message: "trailing '\\'",
+                        })
+                    }
+                },
+                '$' if chars.peek().is_none() => anchored_end = true,
+                c => literal.push(c),
+            }
+        }
+
+        Ok(Self {
+            literal,
+            anchored_start,
+            anchored_end,
+        })
+    }
+
+    /// Whether the pattern matches anywhere in `text`.
+    pub fn is_match(&self, text: &str) -> bool {
+        match (self.anchored_start, self.anchored_end) {
+            (true, true) => text == self.literal,
+            (true, false) => text.starts_with(&self.literal),
+            (false, true) => text.ends_with(&self.literal),
+            (false, false) => text.contains(&self.literal),
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use crate::db::{DBError, InMemoryDB};
+use crate::document::{DocumentStored, FieldMaterialized};
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+enum TxOp {
+    Upsert(DocumentStored),
+    UpsertField(String, String, FieldMaterialized),
+    Remove(String),
+}
+
+impl TxOp {
+    fn id(&self) -> &str {
+        match self {
+            TxOp::Upsert(doc) => &doc.id,
+            TxOp::UpsertField(id, _, _) | TxOp::Remove(id) => id,
+        }
+    }
+}
+
+/// A batch of writes that is applied to an `InMemoryDB` all at once.
+///
+/// Nothing touches the database until `commit`, so the batch's documents
+/// and index entries only become visible then. If any operation fails
+/// while committing, the ones already applied are undone and the database
+/// is left as it was. Dropping a transaction without committing discards
+/// it.
+///
+/// Commit does not lock out other writers: a concurrent write to a
+/// document in the batch can be overwritten by the undo of a failed commit.
+#[must_use = "a transaction does nothing unless committed"]
+pub struct Transaction<'a> {
+    db: &'a InMemoryDB,
+    ops: Vec<TxOp>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(db: &'a InMemoryDB) -> Self {
+        Self {
+            db,
+            ops: Vec::new(),
+        }
+    }
+
+    /// Insert or replace a whole document
+    pub fn upsert(&mut self, doc: DocumentStored) {
+        self.ops.push(TxOp::Upsert(doc));
+    }
+
+    /// Set one field, as `InMemoryDB::upsert_field`
+    pub fn upsert_field(&mut self, id: &str, field: &str, data: FieldMaterialized) {
+        self.ops
+            .push(TxOp::UpsertField(id.to_string(), field.to_string(), data));
+    }
+
+    /// Remove a document; committing fails if it does not exist by then
+    pub fn remove(&mut self, id: &str) {
+        self.ops.push(TxOp::Remove(id.to_string()));
+    }
+
+    /// Number of buffered operations
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Apply every buffered operation in order, or none of them.
+    ///
+    /// Returns the ids the batch touched, in first-touched order.
+    pub fn commit(self) -> Result<Vec<String>, DBError> {
+        let mut undo: Vec<(String, Option<Arc<DocumentStored>>)> = Vec::new();
+        let mut touched: Vec<String> = Vec::new();
+
+        for op in self.ops {
+            let id = op.id().to_string();
+            let previous = self.db.get(&id).ok();
+
+            let applied = match op {
+                TxOp::Upsert(doc) => self.db.upsert(doc).map(|_| ()),
+                TxOp::UpsertField(id, field, data) => {
+                    self.db.upsert_field(&id, &field, data).map(|_| ())
+                }
+                TxOp::Remove(id) => self.db.remove(&id).map(|_| ()),
+            };
+
+            if let Err(e) = applied {
+                Self::undo(self.db, undo);
+                return Err(e);
+            }
+
+            if !touched.contains(&id) {
+                touched.push(id.clone());
+            }
+            undo.push((id, previous));
+        }
+
+        Ok(touched)
+    }
+
+    /// Discard every buffered operation
+    pub fn rollback(self) {}
+
+    fn undo(db: &InMemoryDB, undo: Vec<(String, Option<Arc<DocumentStored>>)>) {
+        for (id, previous) in undo.into_iter().rev() {
+            match previous {
+                Some(doc) => {
+                    let _ = db.upsert_unvalidated((*doc).clone());
+                }
+                None => {
+                    let _ = db.remove(&id);
+                }
+            }
+        }
+    }
+}
+
+impl InMemoryDB {
+    /// Start a batch of writes to apply together with `Transaction::commit`
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+}
@@ -0,0 +1,230 @@
+use crate::db::{DBError, InMemoryDB};
+use crate::document::DocumentStored;
+use magentadb_crypto::{tokens_equal, wal_entry_tag};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// A single logged mutation.
+///
+/// Every operation carries its full effect (whole documents rather than
+/// deltas), so replaying a log over a snapshot that already contains some
+/// of its entries converges to the same state.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WalOp {
+    Upsert(DocumentStored),
+    Remove(String),
+    Clear,
+}
+
+impl WalOp {
+    /// Apply this operation to an in-memory database
+    pub fn apply(&self, db: &InMemoryDB) -> Result<(), DBError> {
+        match self {
+            WalOp::Upsert(doc) => {
+                db.upsert_unvalidated(doc.clone())?;
+            }
+            WalOp::Remove(id) => match db.remove(id) {
+                Ok(_) | Err(DBError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            },
+            WalOp::Clear => db.clear(),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WalConfig {
+    /// Number of logged operations after which the log should be folded
+    /// into a snapshot
+    pub compaction_threshold: usize,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: 1000,
+        }
+    }
+}
+
+/// What `Wal::open` read back from a log
+#[derive(Debug)]
+pub enum WalReplay {
+    /// Operations of a log that chains from the snapshot it was opened
+    /// against, every entry authenticated
+    Follows(Vec<WalOp>),
+    /// Operations of a log whose first entry does not chain from the
+    /// snapshot. An interrupted compaction leaves such a log behind, its
+    /// operations already folded into the snapshot; anything else is
+    /// tampering. The operations are not authenticated.
+    Stale(Vec<WalOp>),
+}
+
+/// Append-only operation log, one entry per line: its tag, a space and the
+/// JSON-encoded `WalOp`. Tags chain every entry to the one before it and the
+/// first to the integrity tag of the snapshot, so entries cannot be forged,
+/// edited, reordered or removed from the middle. Nothing records how long
+/// the log is, so losing its last entries or the whole file goes unnoticed.
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    config: WalConfig,
+    pending: usize,
+    /// Tag the next entry chains from
+    head: String,
+}
+
+impl Wal {
+    /// Open (or lazily create) the log at `path` that follows the snapshot
+    /// tagged `root`, returning it together with the operations it already
+    /// holds, in order. Tags are checked under `key`.
+    ///
+    /// A final line that fails to parse is treated as a torn write from an
+    /// interrupted append and dropped; an entry anywhere else that fails to
+    /// parse or authenticate is an error.
+    pub fn open(
+        path: impl AsRef<Path>,
+        config: WalConfig,
+        key: &[u8; 32],
+        root: &str,
+    ) -> Result<(Self, WalReplay), DBError> {
+        let path = path.as_ref().to_path_buf();
+        let mut ops = Vec::new();
+        let mut head = root.to_string();
+        let mut stale = false;
+
+        match File::open(&path) {
+            Ok(file) => {
+                let lines: Vec<String> = BufReader::new(file)
+                    .lines()
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| DBError::StorageError(format!("Failed to read WAL: {}", e)))?;
+
+                let last = lines.len().saturating_sub(1);
+                for (i, line) in lines.iter().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let (tag, entry, op) = match parse_entry(line) {
+                        Ok(parsed) => parsed,
+                        Err(_) if i == last => break,
+                        Err(e) => {
+                            return Err(DBError::StorageError(format!(
+                                "Corrupt WAL entry on line {}: {}",
+                                i + 1,
+                                e
+                            )))
+                        }
+                    };
+                    let expected = wal_entry_tag(key, &head, entry.as_bytes());
+                    if !tokens_equal(&expected, tag) {
+                        if !ops.is_empty() {
+                            return Err(DBError::StorageError(format!(
+                                "WAL entry on line {} failed authentication",
+                                i + 1
+                            )));
+                        }
+                        stale = true;
+                    }
+                    head = tag.to_string();
+                    ops.push(op);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(DBError::StorageError(format!("Failed to open WAL: {}", e)));
+            }
+        }
+
+        let wal = Self {
+            path,
+            config,
+            pending: ops.len(),
+            head,
+        };
+        let replay = if stale {
+            WalReplay::Stale(ops)
+        } else {
+            WalReplay::Follows(ops)
+        };
+        Ok((wal, replay))
+    }
+
+    /// Durably append an operation to the log, tagged under `key`
+    pub fn append(&mut self, op: &WalOp, key: &[u8; 32]) -> Result<(), DBError> {
+        let entry = serde_json::to_string(op)
+            .map_err(|e| DBError::StorageError(format!("Failed to encode WAL entry: {}", e)))?;
+        let tag = wal_entry_tag(key, &self.head, entry.as_bytes());
+        let line = format!("{} {}\n", tag, entry);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| DBError::StorageError(format!("Failed to open WAL: {}", e)))?;
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|e| DBError::StorageError(format!("Failed to append to WAL: {}", e)))?;
+
+        self.head = tag;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Number of operations logged since the last compaction
+    pub fn len(&self) -> usize {
+        self.pending
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending == 0
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.pending >= self.config.compaction_threshold
+    }
+
+    /// Fold the log into a snapshot: `write_snapshot` must durably persist
+    /// the full current state, after which the log is emptied.
+    ///
+    /// If the process dies between the two steps the log is left behind,
+    /// no longer chaining from the snapshot; `open` reports it as
+    /// `WalReplay::Stale`, and replaying it is harmless (see `WalOp`).
+    /// `root` is the integrity tag of the new snapshot, which the next
+    /// entry chains from.
+    pub fn compact<F>(&mut self, root: &str, write_snapshot: F) -> Result<(), DBError>
+    where
+        F: FnOnce() -> Result<(), DBError>,
+    {
+        write_snapshot()?;
+
+        match fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(DBError::StorageError(format!(
+                    "Failed to truncate WAL: {}",
+                    e
+                )));
+            }
+        }
+
+        self.pending = 0;
+        self.head = root.to_string();
+        Ok(())
+    }
+}
+
+/// Split a log line into its tag, its entry as written and the operation
+/// the entry encodes
+fn parse_entry(line: &str) -> Result<(&str, &str, WalOp), String> {
+    let (tag, entry) = line
+        .split_once(' ')
+        .filter(|(tag, _)| tag.len() == 64 && tag.bytes().all(|b| b.is_ascii_hexdigit()))
+        .ok_or("entry is not tagged")?;
+    let op = serde_json::from_str(entry).map_err(|e| e.to_string())?;
+    Ok((tag, entry, op))
+}
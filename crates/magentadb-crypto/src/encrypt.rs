@@ -1,3 +1,4 @@
+use crate::key::CryptoError;
 use anyhow::Result;
 use chacha20poly1305::{
     aead::{Aead, KeyInit},
@@ -5,25 +6,161 @@ use chacha20poly1305::{
 };
 use rand::{rngs::OsRng, RngCore};
 
-pub fn encrypt(plaintext: &[u8], key_bytes: &[u8; 32]) -> (Vec<u8>, Vec<u8>) {
+/// Length of an XChaCha20-Poly1305 nonce
+pub const NONCE_LEN: usize = 24;
+
+/// Nonce a value is encrypted under; fixed-size, so a nonce of any other
+/// length cannot be stored
+pub type Nonce = [u8; NONCE_LEN];
+
+/// Length of the random per-database prefix in counter-based nonces
+pub const NONCE_PREFIX_LEN: usize = 16;
+
+/// Largest block size `pad` supports; the pad length must fit in one byte,
+/// with 0 standing for a full 256-byte block
+pub const MAX_PAD_BLOCK: usize = 256;
+
+/// Encrypt under a random nonce, returning `(nonce, ciphertext)`
+pub fn encrypt(plaintext: &[u8], key_bytes: &[u8; 32]) -> Result<(Nonce, Vec<u8>), CryptoError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    encrypt_with_nonce(plaintext, key_bytes, nonce)
+}
+
+/// Encrypt using the nonce `prefix || counter` (counter big-endian), so
+/// nonces never repeat as long as each counter value is used once per prefix
+pub fn encrypt_counter(
+    plaintext: &[u8],
+    key_bytes: &[u8; 32],
+    prefix: &[u8; NONCE_PREFIX_LEN],
+    counter: u64,
+) -> Result<(Nonce, Vec<u8>), CryptoError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..].copy_from_slice(&counter.to_be_bytes());
+    encrypt_with_nonce(plaintext, key_bytes, nonce)
+}
+
+/// Recover the counter from a nonce produced by `encrypt_counter` with
+/// `prefix`, or `None` if the nonce was not
+pub fn nonce_counter(prefix: &[u8; NONCE_PREFIX_LEN], nonce: &Nonce) -> Option<u64> {
+    if nonce[..NONCE_PREFIX_LEN] != prefix[..] {
+        return None;
+    }
+    let counter: [u8; 8] = nonce[NONCE_PREFIX_LEN..].try_into().ok()?;
+    Some(u64::from_be_bytes(counter))
+}
+
+/// Domain tag for deterministic nonces, keeping them unrelated to tokens
+const DETERMINISTIC_NONCE_DOMAIN: &[u8] = b"\0nonce\0";
+
+/// Encrypt so that equal plaintexts under the same key always produce the
+/// same `(nonce, ciphertext)`, using a nonce derived as an HMAC of the
+/// plaintext.
+///
+/// This deliberately leaks equality: anyone holding the data can tell which
+/// fields share a value, even without tokens. Nonce reuse is only ever with
+/// an identical plaintext, so confidentiality of distinct values holds.
+pub fn encrypt_deterministic(
+    plaintext: &[u8],
+    key_bytes: &[u8; 32],
+) -> Result<(Nonce, Vec<u8>), CryptoError> {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key_bytes).expect("HMAC can take key of any size");
+    mac.update(DETERMINISTIC_NONCE_DOMAIN);
+    mac.update(plaintext);
+    let digest = mac.finalize().into_bytes();
+
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(&digest[..NONCE_LEN]);
+    encrypt_with_nonce(plaintext, key_bytes, nonce)
+}
+
+/// Fails only when the cipher refuses the plaintext, e.g. one too long for it
+fn encrypt_with_nonce(
+    plaintext: &[u8],
+    key_bytes: &[u8; 32],
+    nonce: Nonce,
+) -> Result<(Nonce, Vec<u8>), CryptoError> {
     let key = Key::from(*key_bytes);
     let cipher = XChaCha20Poly1305::new(&key);
-    let mut nonce = [0u8; 24];
-    OsRng.fill_bytes(&mut nonce);
     let ct = cipher
         .encrypt(&XNonce::from(nonce), plaintext)
-        .expect("encryption failed");
-    (nonce.to_vec(), ct)
+        .map_err(|e| CryptoError::Encryption(e.to_string()))?;
+    Ok((nonce, ct))
 }
 
-pub fn decrypt(ciphertext: &[u8], nonce: &[u8], key_bytes: &[u8; 32]) -> Result<Vec<u8>> {
+pub fn decrypt(ciphertext: &[u8], nonce: &Nonce, key_bytes: &[u8; 32]) -> Result<Vec<u8>> {
     let key = Key::from(*key_bytes);
     let cipher = XChaCha20Poly1305::new(&key);
-    let nonce_array: [u8; 24] = nonce
-        .try_into()
-        .map_err(|_| anyhow::anyhow!("Invalid nonce length"))?;
 
     cipher
-        .decrypt(&XNonce::from(nonce_array), ciphertext)
+        .decrypt(&XNonce::from(*nonce), ciphertext)
         .map_err(|e| anyhow::anyhow!("Decryption failed: {}", e))
 }
+
+/// Decrypt each `(ciphertext, nonce)` pair under the same key, setting the
+/// cipher up once for all of them. Results are in the order of `items`; one
+/// that fails does not stop the rest.
+pub fn decrypt_many(items: &[(&[u8], &Nonce)], key_bytes: &[u8; 32]) -> Vec<Result<Vec<u8>>> {
+    let key = Key::from(*key_bytes);
+    let cipher = XChaCha20Poly1305::new(&key);
+
+    items
+        .iter()
+        .map(|(ciphertext, nonce)| {
+            cipher
+                .decrypt(&XNonce::from(**nonce), *ciphertext)
+                .map_err(|e| anyhow::anyhow!("Decryption failed: {}", e))
+        })
+        .collect()
+}
+
+/// Decrypt a value and strip the padding it was encrypted with, if any
+pub fn decrypt_padded(
+    ciphertext: &[u8],
+    nonce: &Nonce,
+    key_bytes: &[u8; 32],
+    pad_block: Option<usize>,
+) -> Result<Vec<u8>> {
+    let plaintext = decrypt(ciphertext, nonce, key_bytes)?;
+    match pad_block {
+        Some(block) => unpad(&plaintext, block),
+        None => Ok(plaintext),
+    }
+}
+
+/// PKCS#7-style padding to the next multiple of `block` bytes (1 to
+/// `MAX_PAD_BLOCK`). At least one byte is always added, so a value that
+/// already fills its last block gains a whole block of padding.
+pub fn pad(plaintext: &[u8], block: usize) -> Vec<u8> {
+    assert!(
+        (1..=MAX_PAD_BLOCK).contains(&block),
+        "invalid pad block size"
+    );
+    let pad_len = block - plaintext.len() % block;
+
+    let mut padded = Vec::with_capacity(plaintext.len() + pad_len);
+    padded.extend_from_slice(plaintext);
+    padded.resize(plaintext.len() + pad_len, pad_len as u8);
+    padded
+}
+
+/// Remove padding added by `pad` with the same `block`
+pub fn unpad(padded: &[u8], block: usize) -> Result<Vec<u8>> {
+    let invalid = || anyhow::anyhow!("Invalid padding");
+
+    let last = *padded.last().ok_or_else(invalid)?;
+    let pad_len = if last == 0 { 256 } else { last as usize };
+    if pad_len > block || pad_len > padded.len() || !padded.len().is_multiple_of(block) {
+        return Err(invalid());
+    }
+
+    let (data, padding) = padded.split_at(padded.len() - pad_len);
+    if padding.iter().any(|&b| b != last) {
+        return Err(invalid());
+    }
+    Ok(data.to_vec())
+}
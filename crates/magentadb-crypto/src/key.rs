@@ -0,0 +1,165 @@
+use crate::encrypt::{decrypt, encrypt, Nonce};
+use crate::token::tokens_equal;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rand::{rngs::OsRng, RngCore};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CryptoError {
+    WeakKey(&'static str),
+    KeyUnwrap(String),
+    InvalidKey(String),
+    KeyMismatch,
+    IntegrityCheckFailed,
+    Encryption(String),
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::WeakKey(reason) => write!(f, "Weak key: {}", reason),
+            CryptoError::KeyUnwrap(msg) => write!(f, "Failed to unwrap key: {}", msg),
+            CryptoError::InvalidKey(msg) => write!(f, "Invalid key: {}", msg),
+            CryptoError::KeyMismatch => {
+                write!(f, "Key mismatch — data was encrypted with a different key")
+            }
+            CryptoError::IntegrityCheckFailed => write!(
+                f,
+                "Database integrity check failed — documents were added, removed or modified outside MagentaDB"
+            ),
+            CryptoError::Encryption(msg) => write!(f, "Encryption failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// Reject keys that must never be used for encryption or tokenization.
+///
+/// Randomly generated keys always pass; this guards keys that come from
+/// outside, such as a hand-edited database file.
+pub fn validate_key(key: &[u8; 32]) -> Result<(), CryptoError> {
+    if key.iter().all(|&b| b == 0) {
+        return Err(CryptoError::WeakKey("all bytes are zero"));
+    }
+    Ok(())
+}
+
+/// Constant the key fingerprint is a keyed hash of
+const FINGERPRINT_DOMAIN: &[u8] = b"magentadb key fingerprint";
+
+/// Identify `key` without revealing it: the hex HMAC-SHA256 of a fixed
+/// constant under the key
+pub fn key_fingerprint(key: &[u8; 32]) -> String {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(FINGERPRINT_DOMAIN);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Check that `key` is the key `fingerprint` was computed from
+pub fn verify_key_fingerprint(key: &[u8; 32], fingerprint: &str) -> Result<(), CryptoError> {
+    if tokens_equal(&key_fingerprint(key), fingerprint) {
+        Ok(())
+    } else {
+        Err(CryptoError::KeyMismatch)
+    }
+}
+
+/// Constant the key integrity tags are computed under is derived from
+const INTEGRITY_DOMAIN: &[u8] = b"magentadb integrity key";
+
+/// Tag `data` with a hex HMAC-SHA256 under a key derived from `key`, so
+/// the tag is never computed under the key tokens are
+pub fn integrity_tag(key: &[u8; 32], data: &[u8]) -> String {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(INTEGRITY_DOMAIN);
+    let integrity_key = mac.finalize().into_bytes();
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(&integrity_key).expect("HMAC can take key of any size");
+    mac.update(data);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Check that `tag` is the integrity tag of `data` under `key`
+pub fn verify_integrity_tag(key: &[u8; 32], data: &[u8], tag: &str) -> Result<(), CryptoError> {
+    if tokens_equal(&integrity_tag(key, data), tag) {
+        Ok(())
+    } else {
+        Err(CryptoError::IntegrityCheckFailed)
+    }
+}
+
+/// Domain tag keeping log entry tags apart from snapshot integrity tags
+const WAL_DOMAIN: &[u8] = b"\0wal\0";
+
+/// Tag a write-ahead log entry: the integrity tag of `entry` chained to
+/// `prev`, the tag of the entry before it or, for the first entry, the
+/// integrity tag of the snapshot the log follows. Entries can then not be
+/// edited, inserted, reordered or moved to another log unnoticed.
+pub fn wal_entry_tag(key: &[u8; 32], prev: &str, entry: &[u8]) -> String {
+    integrity_tag(key, &[WAL_DOMAIN, prev.as_bytes(), b"\0", entry].concat())
+}
+
+/// Parse a key written as 64 hex digits, rejecting any other length and
+/// keys that fail `validate_key`
+pub fn parse_key_hex(s: &str) -> Result<[u8; 32], CryptoError> {
+    let bytes = hex::decode(s.trim()).map_err(|e| CryptoError::InvalidKey(e.to_string()))?;
+    let len = bytes.len();
+    let key: [u8; 32] = bytes.try_into().map_err(|_| {
+        CryptoError::InvalidKey(format!(
+            "expected 32 bytes (64 hex digits), found {} bytes",
+            len
+        ))
+    })?;
+    validate_key(&key)?;
+    Ok(key)
+}
+
+/// Parse a key written as standard base64, rejecting anything that does
+/// not decode to exactly 32 bytes and keys that fail `validate_key`
+pub fn parse_key_base64(s: &str) -> Result<[u8; 32], CryptoError> {
+    let bytes = STANDARD
+        .decode(s.trim())
+        .map_err(|e| CryptoError::InvalidKey(e.to_string()))?;
+    let len = bytes.len();
+    let key: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| CryptoError::InvalidKey(format!("expected 32 bytes, found {} bytes", len)))?;
+    validate_key(&key)?;
+    Ok(key)
+}
+
+/// `key` as standard base64, the form `parse_key_base64` reads
+pub fn key_to_base64(key: &[u8; 32]) -> String {
+    STANDARD.encode(key)
+}
+
+/// A fresh random 256-bit key
+pub fn generate_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// Encrypt `key` under `master`, returning `(nonce, ciphertext)`
+pub fn wrap_key(key: &[u8; 32], master: &[u8; 32]) -> Result<(Nonce, Vec<u8>), CryptoError> {
+    encrypt(key, master)
+}
+
+/// Recover a key wrapped with `wrap_key`
+pub fn unwrap_key(
+    nonce: &Nonce,
+    cipher: &[u8],
+    master: &[u8; 32],
+) -> Result<[u8; 32], CryptoError> {
+    let key = decrypt(cipher, nonce, master).map_err(|e| CryptoError::KeyUnwrap(e.to_string()))?;
+    key.try_into()
+        .map_err(|_| CryptoError::KeyUnwrap("wrapped key has the wrong length".to_string()))
+}
@@ -1,5 +1,7 @@
 pub mod encrypt;
+pub mod key;
 pub mod token;
 
 pub use encrypt::*;
-pub use token::*;
\ No newline at end of file
+pub use key::*;
+pub use token::*;
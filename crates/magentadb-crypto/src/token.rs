@@ -10,19 +10,298 @@
 //     let result = mac.finalize().into_bytes();
 //     URL_SAFE_NO_PAD.encode(&result[..20])
 // }
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+
+/// Keyed hash used to derive tokens. A database picks one when it is
+/// created and every token in it must come from that algorithm.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenAlgo {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl TokenAlgo {
+    pub fn name(self) -> &'static str {
+        match self {
+            TokenAlgo::Sha256 => "sha256",
+            TokenAlgo::Sha512 => "sha512",
+        }
+    }
+}
+
+impl fmt::Display for TokenAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TokenAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(TokenAlgo::Sha256),
+            "sha512" => Ok(TokenAlgo::Sha512),
+            _ => Err(format!(
+                "unknown token algorithm '{}' (expected sha256 or sha512)",
+                s
+            )),
+        }
+    }
+}
+
+/// Text encoding of token bytes. A database picks one when it is created,
+/// like its `TokenAlgo`, so tokens in it are always compared in one form.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenEncoding {
+    #[default]
+    Hex,
+    Base64url,
+}
+
+impl TokenEncoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            TokenEncoding::Hex => "hex",
+            TokenEncoding::Base64url => "base64url",
+        }
+    }
+
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            TokenEncoding::Hex => hex::encode(bytes),
+            TokenEncoding::Base64url => URL_SAFE_NO_PAD.encode(bytes),
+        }
+    }
+
+    /// Whether `token` is written exactly as this encoding writes tokens,
+    /// such as one printed by another process; says nothing about which
+    /// key or algorithm produced it
+    pub fn is_token(self, token: &str) -> bool {
+        let decoded = match self {
+            TokenEncoding::Hex => hex::decode(token).ok(),
+            TokenEncoding::Base64url => URL_SAFE_NO_PAD.decode(token).ok(),
+        };
+        decoded.is_some_and(|bytes| bytes.len() == TOKEN_BYTES && self.encode(&bytes) == token)
+    }
+}
+
+impl fmt::Display for TokenEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TokenEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hex" => Ok(TokenEncoding::Hex),
+            "base64url" => Ok(TokenEncoding::Base64url),
+            _ => Err(format!(
+                "unknown token encoding '{}' (expected hex or base64url)",
+                s
+            )),
+        }
+    }
+}
+
+/// Bytes of the keyed hash kept in every token
+const TOKEN_BYTES: usize = 8;
+
+/// Shortest prefix, in characters, that receives a prefix token
+pub const PREFIX_MIN_LEN: usize = 3;
+
+/// Domain tag mixed into prefix tokens. The tag is not length-framed, so a
+/// prefix token equals the whole-value token of a plaintext consisting of
+/// the tag bytes followed by the prefix; values that do not start with a
+/// NUL byte never collide with one.
+const PREFIX_DOMAIN: &[u8] = b"\0prefix\0";
 
 /// Generate a searchable token from plaintext using HMAC
-pub fn tokenize(key: &[u8; 32], value: &str) -> String {
+pub fn tokenize(algo: TokenAlgo, encoding: TokenEncoding, key: &[u8; 32], value: &str) -> String {
+    hmac_token(algo, encoding, key, &[value.as_bytes()])
+}
+
+/// Compare two tokens in time that depends only on their lengths, not on
+/// where they first differ
+pub fn tokens_equal(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Generate the token used to look up values starting with `prefix`
+pub fn tokenize_prefix(
+    algo: TokenAlgo,
+    encoding: TokenEncoding,
+    key: &[u8; 32],
+    prefix: &str,
+) -> String {
+    hmac_token(algo, encoding, key, &[PREFIX_DOMAIN, prefix.as_bytes()])
+}
+
+/// Generate prefix tokens for every prefix of `value` from
+/// `PREFIX_MIN_LEN` characters up to the full value
+pub fn tokenize_prefixes(
+    algo: TokenAlgo,
+    encoding: TokenEncoding,
+    key: &[u8; 32],
+    value: &str,
+) -> Vec<String> {
+    value
+        .char_indices()
+        .map(|(i, c)| &value[..i + c.len_utf8()])
+        .skip(PREFIX_MIN_LEN - 1)
+        .map(|prefix| tokenize_prefix(algo, encoding, key, prefix))
+        .collect()
+}
+
+/// Domain tag mixed into trigram tokens so they never equal a prefix or
+/// range token. As with `PREFIX_DOMAIN`, a whole-value token only collides
+/// with one if its plaintext starts with the tag bytes.
+const TRIGRAM_DOMAIN: &[u8] = b"\0trigram\0";
+
+/// Distinct three-character sequences of `value`, lowercased and padded
+/// with two spaces in front and one behind, so the start and end of a
+/// value weigh in and even one or two characters give some trigrams
+fn trigrams(value: &str) -> BTreeSet<String> {
+    let padded: Vec<char> = "  "
+        .chars()
+        .chain(value.to_lowercase().chars())
+        .chain(" ".chars())
+        .collect();
+    padded
+        .windows(3)
+        .map(|window| window.iter().collect())
+        .collect()
+}
+
+/// Generate a token for every distinct trigram of `value`, for fuzzy
+/// search. Values that differ by a typo still share most of them. The
+/// tokens leak roughly which values resemble each other.
+pub fn tokenize_trigrams(
+    algo: TokenAlgo,
+    encoding: TokenEncoding,
+    key: &[u8; 32],
+    value: &str,
+) -> Vec<String> {
+    trigrams(value)
+        .iter()
+        .map(|trigram| hmac_token(algo, encoding, key, &[TRIGRAM_DOMAIN, trigram.as_bytes()]))
+        .collect()
+}
+
+/// Domain tag mixed into range tokens so they never equal a prefix or
+/// trigram token. As with `PREFIX_DOMAIN`, a whole-value token only
+/// collides with one if its plaintext starts with the tag bytes.
+const RANGE_DOMAIN: &[u8] = b"\0range\0";
+
+/// Magnitude bucket of a finite number: zero, or its sign and binary
+/// exponent. Buckets are monotonic in the value, so the buckets between
+/// those of `min` and `max` cover every value in `[min, max]`.
+fn range_bucket(value: f64) -> String {
+    if value == 0.0 {
+        return "0".to_string();
+    }
+    if value > 0.0 {
+        format!("+{}", exponent(value))
+    } else {
+        format!("-{}", exponent(value))
+    }
+}
+
+/// Unbiased IEEE 754 exponent of `|value|`; all subnormals share -1023
+fn exponent(value: f64) -> i32 {
+    ((value.abs().to_bits() >> 52) & 0x7ff) as i32 - 1023
+}
+
+/// Generate the range token for a numeric value, or `None` if it is not
+/// finite. Range tokens leak the value's approximate magnitude (its
+/// power-of-two bucket) to anyone comparing them.
+pub fn tokenize_range(
+    algo: TokenAlgo,
+    encoding: TokenEncoding,
+    key: &[u8; 32],
+    value: f64,
+) -> Option<String> {
+    if !value.is_finite() {
+        return None;
+    }
+    Some(hmac_token(
+        algo,
+        encoding,
+        key,
+        &[RANGE_DOMAIN, range_bucket(value).as_bytes()],
+    ))
+}
+
+/// Generate the range tokens of every bucket overlapping `[min, max]`.
+///
+/// Matches are candidates only: a bucket spans a power of two, so values
+/// must still be compared exactly once decrypted.
+pub fn tokenize_range_query(
+    algo: TokenAlgo,
+    encoding: TokenEncoding,
+    key: &[u8; 32],
+    min: f64,
+    max: f64,
+) -> Vec<String> {
+    if min.is_nan() || max.is_nan() || min > max {
+        return Vec::new();
+    }
+    let min = min.max(f64::MIN);
+    let max = max.min(f64::MAX);
+    // Closest-to-zero value on each side; halving MIN_POSITIVE lands in the
+    // shared subnormal bucket
+    let tiny = f64::MIN_POSITIVE / 2.0;
+
+    let mut buckets = Vec::new();
+    if min < 0.0 {
+        let smallest = if max < 0.0 { max } else { -tiny };
+        for e in exponent(smallest)..=exponent(min) {
+            buckets.push(format!("-{}", e));
+        }
+    }
+    if min <= 0.0 && max >= 0.0 {
+        buckets.push("0".to_string());
+    }
+    if max > 0.0 {
+        let smallest = if min > 0.0 { min } else { tiny };
+        for e in exponent(smallest)..=exponent(max) {
+            buckets.push(format!("+{}", e));
+        }
+    }
+
+    buckets
+        .iter()
+        .map(|bucket| hmac_token(algo, encoding, key, &[RANGE_DOMAIN, bucket.as_bytes()]))
+        .collect()
+}
+
+fn hmac_token(algo: TokenAlgo, encoding: TokenEncoding, key: &[u8; 32], parts: &[&[u8]]) -> String {
     use hmac::{Hmac, Mac};
-    use sha2::Sha256;
+    use sha2::{Sha256, Sha512};
 
-    type HmacSha256 = Hmac<Sha256>;
+    fn digest<M: Mac + hmac::digest::KeyInit>(key: &[u8; 32], parts: &[&[u8]]) -> Vec<u8> {
+        let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC can take key of any size");
+        for part in parts {
+            mac.update(part);
+        }
 
-    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
-    mac.update(value.as_bytes());
+        mac.finalize().into_bytes()[..TOKEN_BYTES].to_vec()
+    }
 
-    let result = mac.finalize();
-    hex::encode(&result.into_bytes()[0..8])
+    let bytes = match algo {
+        TokenAlgo::Sha256 => digest::<Hmac<Sha256>>(key, parts),
+        TokenAlgo::Sha512 => digest::<Hmac<Sha512>>(key, parts),
+    };
+    encoding.encode(&bytes)
 }